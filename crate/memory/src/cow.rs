@@ -37,18 +37,27 @@ impl<T: PageTable> CowExt<T> {
         }
     }
     pub fn map_to_shared(&mut self, addr: VirtAddr, target: PhysAddr, writable: bool) {
-        let entry = self.page_table.map(addr, target);
-        entry.set_writable(false);
-        entry.set_shared(writable);
-        entry.update();
-        let frame = target / PAGE_SIZE;
+        self.page_table.map(addr, target);
+        self.share(addr, writable);
+    }
+    /// Turn an existing mapping to shared state, e.g. the parent's pages when forking.
+    pub fn share(&mut self, addr: VirtAddr, writable: bool) {
+        let frame = {
+            let entry = self.page_table.get_entry(addr);
+            entry.set_writable(false);
+            entry.set_shared(writable);
+            entry.update();
+            entry.target() / PAGE_SIZE
+        };
         match writable {
             true => self.rc_map.write_increase(&frame),
             false => self.rc_map.read_increase(&frame),
         }
     }
-    pub fn unmap_shared(&mut self, addr: VirtAddr) {
-        {
+    /// Unmap a shared page.
+    /// Return the frame if this is the last reference to it, then it should be deallocated.
+    pub fn unmap_shared(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        let target = {
             let entry = self.page_table.get_entry(addr);
             let frame = entry.target() / PAGE_SIZE;
             if entry.readonly_shared() {
@@ -56,15 +65,25 @@ impl<T: PageTable> CowExt<T> {
             } else if entry.writable_shared() {
                 self.rc_map.write_decrease(&frame);
             }
-        }
+            entry.target()
+        };
         self.page_table.unmap(addr);
+        match self.rc_map.is_shared(&(target / PAGE_SIZE)) {
+            true => None,
+            false => Some(target),
+        }
+    }
+    fn is_shared(&mut self, addr: VirtAddr) -> bool {
+        let entry = self.page_table.get_entry(addr);
+        entry.readonly_shared() || entry.writable_shared()
     }
     /// This function must be called whenever PageFault happens.
     /// Return whether copy-on-write happens.
     pub fn page_fault_handler(&mut self, addr: VirtAddr, alloc_frame: impl FnOnce() -> PhysAddr) -> bool {
-        {
+        let (user, execute) = {
             let entry = self.page_table.get_entry(addr);
-            if !entry.readonly_shared() && !entry.writable_shared() {
+            // 只读共享页上的写操作是真正的错误
+            if !entry.writable_shared() {
                 return false;
             }
             let frame = entry.target() / PAGE_SIZE;
@@ -75,19 +94,60 @@ impl<T: PageTable> CowExt<T> {
                 self.rc_map.write_decrease(&frame);
                 return true;
             }
-        }
+            (entry.user(), entry.execute())
+        };
         use core::mem::uninitialized;
         let mut temp_data: [u8; PAGE_SIZE] = unsafe { uninitialized() };
         temp_data[..].copy_from_slice(self.get_page_slice_mut(addr));
 
         self.unmap_shared(addr);
-        self.map(addr, alloc_frame());
+        {
+            let entry = self.page_table.map_frame(addr, alloc_frame());
+            entry.set_user(user);
+            entry.set_execute(execute);
+            entry.update();
+        }
 
         self.get_page_slice_mut(addr).copy_from_slice(&temp_data[..]);
         true
     }
 }
 
+impl<T: PageTable> PageTable for CowExt<T> {
+    type Entry = T::Entry;
+
+    fn map(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut T::Entry {
+        self.page_table.map(addr, target)
+    }
+    fn unmap(&mut self, addr: VirtAddr) {
+        match self.is_shared(addr) {
+            true => { self.unmap_shared(addr); }
+            false => self.page_table.unmap(addr),
+        }
+    }
+    fn get_entry(&mut self, addr: VirtAddr) -> &mut T::Entry {
+        self.page_table.get_entry(addr)
+    }
+    fn map_frame(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut T::Entry {
+        self.page_table.map_frame(addr, target)
+    }
+    fn unmap_frame(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        match self.is_shared(addr) {
+            true => self.unmap_shared(addr),
+            false => self.page_table.unmap_frame(addr),
+        }
+    }
+    fn get_page_slice_mut<'a, 'b>(&'a mut self, addr: VirtAddr) -> &'b mut [u8] {
+        self.page_table.get_page_slice_mut(addr)
+    }
+    fn read(&mut self, addr: VirtAddr) -> u8 {
+        self.page_table.read(addr)
+    }
+    fn write(&mut self, addr: VirtAddr, data: u8) {
+        self.page_table.write(addr, data)
+    }
+}
+
 impl<T: PageTable> Deref for CowExt<T> {
     type Target = T;
 
@@ -123,6 +183,7 @@ impl FrameRcMap {
     }
     fn read_decrease(&mut self, frame: &Frame) {
         self.map().get_mut(frame).unwrap().0 -= 1;
+        self.remove_unused(frame);
     }
     fn write_increase(&mut self, frame: &Frame) {
         let (r, w) = self.map().get(&frame).unwrap_or(&(0, 0)).clone();
@@ -130,6 +191,15 @@ impl FrameRcMap {
     }
     fn write_decrease(&mut self, frame: &Frame) {
         self.map().get_mut(frame).unwrap().1 -= 1;
+        self.remove_unused(frame);
+    }
+    fn is_shared(&mut self, frame: &Frame) -> bool {
+        self.map().contains_key(frame)
+    }
+    fn remove_unused(&mut self, frame: &Frame) {
+        if self.map().get(frame) == Some(&(0, 0)) {
+            self.map().remove(frame);
+        }
     }
     fn map(&mut self) -> &mut BTreeMap<Frame, (u16, u16)> {
         if self.0.is_none() {
@@ -203,5 +273,15 @@ pub mod test {
                    "The last write reference should not allocate new frame.");
         assert_eq!(pt.read(0x1000), 2);
         assert_eq!(pt.read(0x2000), 3);
+        pt.unmap(0x1000);
+        pt.unmap(0x2000);
+
+        pt.map_to_shared(0x1000, target, true);
+        pt.map_to_shared(0x2000, target, false);
+        assert_eq!(pt.unmap_frame(0x1000), None,
+                   "The frame should not be released while it is still shared.");
+        assert_eq!(pt.unmap_frame(0x2000), Some(target));
+        assert_eq!(pt.rc_map.read_count(&frame), 0);
+        assert_eq!(pt.rc_map.write_count(&frame), 0);
     }
 }
\ No newline at end of file
@@ -1,6 +1,7 @@
 use alloc::vec::Vec;
 use core::fmt::{Debug, Error, Formatter};
 use super::*;
+use cow::CowExt;
 use paging::*;
 
 pub trait InactivePageTable {
@@ -27,23 +28,38 @@ pub struct MemoryArea {
     phys_start_addr: Option<PhysAddr>,
     flags: MemoryAttr,
     name: &'static str,
+    /// Allocate frames on page fault, rather than when pushed
+    lazy: bool,
+    /// Map frames owned by others (e.g. shared memory objects), see `MemorySet::push_shared`
+    shared: bool,
 }
 
 impl MemoryArea {
     pub fn new(start_addr: VirtAddr, end_addr: VirtAddr, flags: MemoryAttr, name: &'static str) -> Self {
         assert!(start_addr <= end_addr, "invalid memory area");
-        MemoryArea { start_addr, end_addr, phys_start_addr: None, flags, name }
+        MemoryArea { start_addr, end_addr, phys_start_addr: None, flags, name, lazy: false, shared: false }
+    }
+    /// Pages of it are mapped by `MemorySet::page_fault_handler` on the first access
+    pub fn new_lazy(start_addr: VirtAddr, end_addr: VirtAddr, flags: MemoryAttr, name: &'static str) -> Self {
+        assert!(start_addr <= end_addr, "invalid memory area");
+        MemoryArea { start_addr, end_addr, phys_start_addr: None, flags, name, lazy: true, shared: false }
+    }
+    /// Pages of it are mapped to the frames given to `MemorySet::push_shared`,
+    /// which are not deallocated when unmapped
+    pub fn new_shared(start_addr: VirtAddr, end_addr: VirtAddr, flags: MemoryAttr, name: &'static str) -> Self {
+        assert!(start_addr <= end_addr, "invalid memory area");
+        MemoryArea { start_addr, end_addr, phys_start_addr: None, flags, name, lazy: false, shared: true }
     }
     pub fn new_identity(start_addr: VirtAddr, end_addr: VirtAddr, flags: MemoryAttr, name: &'static str) -> Self {
         assert!(start_addr <= end_addr, "invalid memory area");
-        MemoryArea { start_addr, end_addr, phys_start_addr: Some(start_addr), flags, name }
+        MemoryArea { start_addr, end_addr, phys_start_addr: Some(start_addr), flags, name, lazy: false, shared: false }
     }
     pub fn new_physical(phys_start_addr: PhysAddr, phys_end_addr: PhysAddr, offset: usize, flags: MemoryAttr, name: &'static str) -> Self {
         let start_addr = phys_start_addr + offset;
         let end_addr = phys_end_addr + offset;
         assert!(start_addr <= end_addr, "invalid memory area");
         let phys_start_addr = Some(phys_start_addr);
-        MemoryArea { start_addr, end_addr, phys_start_addr, flags, name }
+        MemoryArea { start_addr, end_addr, phys_start_addr, flags, name, lazy: false, shared: false }
     }
     pub unsafe fn as_slice(&self) -> &[u8] {
         use core::slice;
@@ -53,9 +69,29 @@ impl MemoryArea {
         use core::slice;
         slice::from_raw_parts_mut(self.start_addr as *mut u8, self.end_addr - self.start_addr)
     }
+    pub fn start_addr(&self) -> VirtAddr {
+        self.start_addr
+    }
+    pub fn end_addr(&self) -> VirtAddr {
+        self.end_addr
+    }
     pub fn contains(&self, addr: VirtAddr) -> bool {
         addr >= self.start_addr && addr < self.end_addr
     }
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+    /// The part of it in `[start_addr, end_addr)`
+    fn sub_area(&self, start_addr: VirtAddr, end_addr: VirtAddr) -> MemoryArea {
+        let start_addr = start_addr.max(self.start_addr);
+        let end_addr = end_addr.min(self.end_addr);
+        MemoryArea {
+            start_addr,
+            end_addr,
+            phys_start_addr: self.phys_start_addr.map(|phys| phys + (start_addr - self.start_addr)),
+            ..*self
+        }
+    }
     fn is_overlap_with(&self, other: &MemoryArea) -> bool {
         let p0 = Page::of_addr(self.start_addr);
         let p1 = Page::of_addr(self.end_addr - 1) + 1;
@@ -63,7 +99,15 @@ impl MemoryArea {
         let p3 = Page::of_addr(other.end_addr - 1) + 1;
         !(p1 <= p2 || p0 >= p3)
     }
-    fn map<T: InactivePageTable>(&self, pt: &mut T::Active) {
+    /// The number of frames taken by `map`
+    fn frame_count(&self) -> usize {
+        match self.phys_start_addr {
+            None if !self.lazy && !self.shared => Page::range_of(self.start_addr, self.end_addr).count(),
+            _ => 0,
+        }
+    }
+    /// Map the pages of it, the ones of a normal area to `frames`, see `alloc_frames`
+    fn map<T: InactivePageTable>(&self, pt: &mut T::Active, frames: &mut impl Iterator<Item=PhysAddr>) {
         match self.phys_start_addr {
             Some(phys_start) => {
                 for page in Page::range_of(self.start_addr, self.end_addr) {
@@ -72,11 +116,20 @@ impl MemoryArea {
                     self.flags.apply(pt.map(addr, target));
                 }
             }
+            None if self.lazy || self.shared => {
+                // Only create the page table entries,
+                // so that `get_entry` is always valid in the area.
+                for page in Page::range_of(self.start_addr, self.end_addr) {
+                    let addr = page.start_address();
+                    pt.map(addr, 0);
+                    pt.unmap(addr);
+                }
+            }
             None => {
                 for page in Page::range_of(self.start_addr, self.end_addr) {
                     let addr = page.start_address();
-                    let target = T::alloc_frame().expect("failed to allocate frame");
-                    self.flags.apply(pt.map(addr, target));
+                    let target = frames.next().expect("not enough frames");
+                    self.flags.apply(pt.map_frame(addr, target));
                 }
             }
         }
@@ -84,15 +137,33 @@ impl MemoryArea {
     fn unmap<T: InactivePageTable>(&self, pt: &mut T::Active) {
         for page in Page::range_of(self.start_addr, self.end_addr) {
             let addr = page.start_address();
-            if self.phys_start_addr.is_none() {
-                let target = pt.get_entry(addr).target();
-                T::dealloc_frame(target);
+            if (self.lazy || self.shared) && !is_mapped(pt.get_entry(addr)) {
+                continue;
+            }
+            match self.phys_start_addr {
+                Some(_) => pt.unmap(addr),
+                None if self.shared => pt.unmap(addr),
+                None => if let Some(target) = pt.unmap_frame(addr) {
+                    T::dealloc_frame(target);
+                },
             }
-            pt.unmap(addr);
         }
     }
 }
 
+/// Allocate `count` frames before editing a page table.
+///
+/// `T::alloc_frame` may swap out a page of the current address space to get a frame,
+/// which is impossible in `InactivePageTable::edit`, where the active table is taken.
+fn alloc_frames<T: InactivePageTable>(count: usize) -> Vec<PhysAddr> {
+    (0..count).map(|_| T::alloc_frame().expect("failed to allocate frame")).collect()
+}
+
+/// Whether the entry refers to a page, in memory or swapped out
+fn is_mapped(entry: &impl Entry) -> bool {
+    entry.present() || entry.swapped()
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
 pub struct MemoryAttr {
     user: bool,
@@ -160,9 +231,134 @@ impl<T: InactivePageTable> MemorySet<T> {
         assert!(self.areas.iter()
                     .find(|other| area.is_overlap_with(other))
                     .is_none(), "memory area overlap");
-        self.page_table.edit(|pt| area.map::<T>(pt));
+        let mut frames = alloc_frames::<T>(area.frame_count()).into_iter();
+        self.page_table.edit(|pt| area.map::<T>(pt, &mut frames));
         self.areas.push(area);
     }
+    /// Whether the pages of `[addr, addr + len)` are all in user areas, and writable if `write`
+    pub fn check_user_range(&self, addr: VirtAddr, len: usize, write: bool) -> bool {
+        let end_addr = match addr.checked_add(len) {
+            Some(end_addr) => end_addr,
+            None => return false,
+        };
+        if len == 0 {
+            return true;
+        }
+        Page::range_of(addr, end_addr).all(|page| {
+            let (first, last) = (page.start_address(), page.start_address() + (PAGE_SIZE - 1));
+            self.areas.iter().any(|area| {
+                area.start_addr <= last && first < area.end_addr &&
+                    area.flags.user && !area.flags.hide && !(write && area.flags.readonly)
+            })
+        })
+    }
+    /// Change the flags of the area starting at `start_addr`, and apply them to its pages in memory.
+    ///
+    /// Only adding restrictions is supported, e.g. to protect an area after its data is written.
+    pub fn set_flags(&mut self, start_addr: VirtAddr, flags: MemoryAttr) {
+        let area = {
+            let area = self.areas.iter_mut().find(|area| area.start_addr == start_addr).expect("no such area");
+            area.flags = flags;
+            *area
+        };
+        self.page_table.edit(|pt| {
+            for page in Page::range_of(area.start_addr, area.end_addr) {
+                let entry = pt.get_entry(page.start_address());
+                if entry.present() {
+                    flags.apply(entry);
+                }
+            }
+        });
+    }
+    /// Push an area made by `MemoryArea::new_shared`, map its pages to `frames` in order
+    pub fn push_shared(&mut self, area: MemoryArea, frames: &[PhysAddr]) {
+        assert!(area.shared, "not a shared area");
+        assert!(self.areas.iter()
+                    .find(|other| area.is_overlap_with(other))
+                    .is_none(), "memory area overlap");
+        self.page_table.edit(|pt| {
+            for (page, &target) in Page::range_of(area.start_addr, area.end_addr).zip(frames.iter()) {
+                area.flags.apply(pt.map(page.start_address(), target));
+            }
+        });
+        self.areas.push(area);
+    }
+    /// Unmap `[start_addr, end_addr)`, which may cover several areas or a part of one.
+    /// The rest of the areas are kept.
+    pub fn remove(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) {
+        if start_addr >= end_addr {
+            return;
+        }
+        let start_addr = Page::of_addr(start_addr).start_address();
+        let end_addr = Page::of_addr(end_addr - 1).start_address() + PAGE_SIZE;
+        let range = MemoryArea::new(start_addr, end_addr, MemoryAttr::default(), "");
+        let Self { ref mut page_table, ref mut areas, .. } = self;
+        let mut rest = Vec::new();
+        page_table.edit(|pt| {
+            for area in areas.drain(..) {
+                if !area.is_overlap_with(&range) {
+                    rest.push(area);
+                    continue;
+                }
+                area.sub_area(start_addr, end_addr).unmap::<T>(pt);
+                if area.start_addr < start_addr {
+                    rest.push(area.sub_area(area.start_addr, start_addr));
+                }
+                if end_addr < area.end_addr {
+                    rest.push(area.sub_area(end_addr, area.end_addr));
+                }
+            }
+        });
+        *areas = rest;
+    }
+    /// Find a free range of `len` bytes, starting from `addr` or above.
+    /// Return `None` if it would pass the end of the address space.
+    pub fn find_free_area(&self, addr: VirtAddr, len: usize) -> Option<VirtAddr> {
+        let mut addr = Page::of_addr(addr).start_address();
+        loop {
+            let range = MemoryArea::new(addr, addr.checked_add(len)?, MemoryAttr::default(), "");
+            match self.areas.iter().find(|area| area.is_overlap_with(&range)) {
+                Some(area) => addr = Page::of_addr(area.end_addr - 1).start_address().checked_add(PAGE_SIZE)?,
+                None => return Some(addr),
+            }
+        }
+    }
+    /// Map the page of `addr` if it is in a lazy area and has not been mapped.
+    /// The page is zeroed, then `fill` is called with the page address and data.
+    /// Return whether the page is mapped, never for a hidden area.
+    ///
+    /// `self` must be the current memory set, since the page is accessed by its address.
+    pub fn page_fault_handler(&mut self, addr: VirtAddr, fill: impl FnOnce(VirtAddr, &mut [u8])) -> bool {
+        let area = match self.find_area(addr) {
+            Some(area) if area.lazy && !area.flags.hide => area.clone(),
+            _ => return false,
+        };
+        let addr = Page::of_addr(addr).start_address();
+        let target = match T::alloc_frame() {
+            Some(target) => target,
+            None => return false,
+        };
+        let mut mapped = false;
+        self.page_table.edit(|pt| {
+            if is_mapped(pt.get_entry(addr)) {
+                return;
+            }
+            pt.map_frame(addr, target);
+            {
+                let data = pt.get_page_slice_mut(addr);
+                for byte in data.iter_mut() {
+                    *byte = 0;
+                }
+                fill(addr, data);
+            }
+            area.flags.apply(pt.get_entry(addr));
+            mapped = true;
+        });
+        if !mapped {
+            T::dealloc_frame(target);
+        }
+        mapped
+    }
     pub fn iter(&self) -> impl Iterator<Item=&MemoryArea> {
         self.areas.iter()
     }
@@ -178,6 +374,13 @@ impl<T: InactivePageTable> MemorySet<T> {
     pub fn kstack_top(&self) -> usize {
         self.kstack.top
     }
+    pub fn kstack(&self) -> &Stack {
+        &self.kstack
+    }
+    /// Exchange the kernel stacks with `other`, to keep running on the current one in a new memory set
+    pub fn swap_kstack(&mut self, other: &mut Self) {
+        ::core::mem::swap(&mut self.kstack, &mut other.kstack);
+    }
     pub fn clear(&mut self) {
         let Self { ref mut page_table, ref mut areas, .. } = self;
         page_table.edit(|pt| {
@@ -192,9 +395,78 @@ impl<T: InactivePageTable> MemorySet<T> {
 impl<T: InactivePageTable> Clone for MemorySet<T> {
     fn clone(&self) -> Self {
         let mut page_table = T::new();
+        let count = self.areas.iter().map(|area| area.frame_count()).sum();
+        let mut frames = alloc_frames::<T>(count).into_iter();
         page_table.edit(|pt| {
             for area in self.areas.iter() {
-                area.map::<T>(pt);
+                area.map::<T>(pt, &mut frames);
+            }
+        });
+        MemorySet {
+            areas: self.areas.clone(),
+            page_table,
+            kstack: T::alloc_stack(),
+        }
+    }
+}
+
+impl<T, A> MemorySet<T> where T: InactivePageTable<Active=CowExt<A>>, A: PageTable {
+    /// 写时复制：与新的内存空间共享所有已分配的物理页
+    ///
+    /// Used for `fork`. The pages of both sides become readonly until they are written.
+    ///
+    /// `self` must be the current memory set. Swapped out pages are brought back by `swap_in`,
+    /// which may swap out others, but never a shared one, i.e. the ones already done.
+    pub fn clone_cow(&mut self, mut swap_in: impl FnMut(&mut A, VirtAddr)) -> Self {
+        let mut shared = Vec::new();
+        let mut shared_areas = Vec::new();
+        {
+            let Self { ref mut page_table, ref areas, .. } = self;
+            page_table.edit(|pt| {
+                // Frames of shared areas are not copied on write
+                for area in areas.iter().filter(|area| area.shared) {
+                    for page in Page::range_of(area.start_addr, area.end_addr) {
+                        let addr = page.start_address();
+                        shared_areas.push((addr, pt.get_entry(addr).target(), area.flags));
+                    }
+                }
+                for area in areas.iter().filter(|area| area.phys_start_addr.is_none() && !area.shared) {
+                    for page in Page::range_of(area.start_addr, area.end_addr) {
+                        let addr = page.start_address();
+                        if pt.get_entry(addr).swapped() {
+                            swap_in(&mut **pt, addr);
+                        }
+                        let (writable, is_shared) = {
+                            let entry = pt.get_entry(addr);
+                            // Not accessed page of a lazy area
+                            if !entry.present() {
+                                continue;
+                            }
+                            match entry.readonly_shared() || entry.writable_shared() {
+                                true => (entry.writable_shared(), true),
+                                false => (entry.writable(), false),
+                            }
+                        };
+                        if !is_shared {
+                            pt.share(addr, writable);
+                        }
+                        shared.push((addr, pt.get_entry(addr).target(), writable, area.flags));
+                    }
+                }
+            });
+        }
+        let mut page_table = T::new();
+        page_table.edit(|pt| {
+            // No frame is taken by them
+            for area in self.areas.iter().filter(|area| area.phys_start_addr.is_some() || area.lazy) {
+                area.map::<T>(pt, &mut None.into_iter());
+            }
+            for &(addr, target, writable, flags) in shared.iter() {
+                pt.map_to_shared(addr, target, writable);
+                flags.apply(pt.get_entry(addr));
+            }
+            for &(addr, target, flags) in shared_areas.iter() {
+                flags.apply(pt.map(addr, target));
             }
         });
         MemorySet {
@@ -223,4 +495,93 @@ impl<T: InactivePageTable> Debug for MemorySet<T> {
 pub struct Stack {
     pub top: usize,
     pub bottom: usize,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use paging::MockPageTable;
+
+    struct MockInactivePageTable(MockPageTable);
+
+    impl InactivePageTable for MockInactivePageTable {
+        type Active = MockPageTable;
+
+        fn new() -> Self { MockInactivePageTable(MockPageTable::new()) }
+        fn new_bare() -> Self { Self::new() }
+        fn edit(&mut self, f: impl FnOnce(&mut Self::Active)) { f(&mut self.0) }
+        unsafe fn activate(&self) {}
+        unsafe fn with(&self, f: impl FnOnce()) { f() }
+        fn token(&self) -> usize { 0 }
+        // Frames are not accessed
+        fn alloc_frame() -> Option<PhysAddr> { Some(0) }
+        fn dealloc_frame(_target: PhysAddr) {}
+        fn alloc_stack() -> Stack { Stack { top: 0, bottom: 0 } }
+    }
+
+    type MockMemorySet = MemorySet<MockInactivePageTable>;
+
+    fn ranges(ms: &MockMemorySet) -> Vec<(VirtAddr, VirtAddr)> {
+        ms.iter().map(|area| (area.start_addr(), area.end_addr())).collect()
+    }
+
+    fn present(ms: &mut MockMemorySet, addr: VirtAddr) -> bool {
+        ms.page_table.0.get_entry(addr).present()
+    }
+
+    #[test]
+    fn remove() {
+        let attr = MemoryAttr::default().user();
+        let mut ms = MockMemorySet::new();
+        ms.push(MemoryArea::new(0x1000, 0x4000, attr, "a"));
+        ms.push(MemoryArea::new(0x5000, 0x7000, attr, "b"));
+        ms.push(MemoryArea::new_lazy(0x7000, 0x9000, attr, "c"));
+
+        // The tail of `a`, all of `b` and the head of `c`, by whole pages
+        ms.remove(0x3800, 0x7001);
+        assert_eq!(ranges(&ms), [(0x1000, 0x3000), (0x8000, 0x9000)]);
+        assert!(present(&mut ms, 0x2000));
+        for &addr in [0x3000, 0x5000, 0x6000, 0x7000].iter() {
+            assert!(!present(&mut ms, addr));
+        }
+
+        // Nothing mapped
+        ms.remove(0x4000, 0x8000);
+        assert_eq!(ranges(&ms), [(0x1000, 0x3000), (0x8000, 0x9000)]);
+    }
+
+    #[test]
+    fn split() {
+        let attr = MemoryAttr::default().user();
+        let mut ms = MockMemorySet::new();
+        ms.push(MemoryArea::new(0x1000, 0x5000, attr, "normal"));
+        ms.push(MemoryArea::new_identity(0x8000, 0xc000, attr, "identity"));
+
+        ms.remove(0x2000, 0x3000);
+        ms.remove(0x9000, 0xb000);
+        assert_eq!(ranges(&ms), [(0x1000, 0x2000), (0x3000, 0x5000), (0x8000, 0x9000), (0xb000, 0xc000)]);
+        assert!(present(&mut ms, 0x1000));
+        assert!(!present(&mut ms, 0x2000));
+        assert!(present(&mut ms, 0x3000));
+        // The rest of an identity area keeps its targets
+        assert_eq!(ms.page_table.0.get_entry(0xb000).target(), 0xb000);
+        assert_eq!(ms.find_area(0xb000).unwrap().phys_start_addr, Some(0xb000));
+
+        // The split areas are freed as others
+        ms.clear();
+        assert!(ranges(&ms).is_empty());
+        assert!(!present(&mut ms, 0x3000));
+        assert!(!present(&mut ms, 0xb000));
+    }
+
+    #[test]
+    fn find_free_area() {
+        let attr = MemoryAttr::default().user();
+        let mut ms = MockMemorySet::new();
+        ms.push(MemoryArea::new_lazy(0x1000, 0x3000, attr, "a"));
+        ms.push(MemoryArea::new_lazy(0x4000, 0x5000, attr, "b"));
+        assert_eq!(ms.find_free_area(0x0, 0x1000), Some(0x0));
+        assert_eq!(ms.find_free_area(0x1800, 0x1000), Some(0x3000));
+        assert_eq!(ms.find_free_area(0x1000, 0x2000), Some(0x5000));
+        assert_eq!(ms.find_free_area(!0 - 0xfff, 0x2000), None);
+    }
+}
@@ -20,6 +20,8 @@ pub struct MockEntry {
     writable_shared: bool,
     readonly_shared: bool,
     swapped: bool,
+    user: bool,
+    execute: bool,
 }
 
 impl Entry for MockEntry {
@@ -46,10 +48,10 @@ impl Entry for MockEntry {
     }
     fn swapped(&self) -> bool { self.swapped }
     fn set_swapped(&mut self, value: bool) { self.swapped = value; }
-    fn user(&self) -> bool { unimplemented!() }
-    fn set_user(&mut self, value: bool) { unimplemented!() }
-    fn execute(&self) -> bool { unimplemented!() }
-    fn set_execute(&mut self, value: bool) { unimplemented!() }
+    fn user(&self) -> bool { self.user }
+    fn set_user(&mut self, value: bool) { self.user = value; }
+    fn execute(&self) -> bool { self.execute }
+    fn set_execute(&mut self, value: bool) { self.execute = value; }
 }
 
 type PageFaultHandler = Box<FnMut(&mut MockPageTable, VirtAddr)>;
@@ -60,9 +62,12 @@ impl PageTable for MockPageTable {
     fn map(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut Self::Entry {
         let entry = &mut self.entries[addr / PAGE_SIZE];
         assert!(!entry.present);
-        entry.present = true;
-        entry.writable = true;
-        entry.target = target & !(PAGE_SIZE - 1);
+        *entry = MockEntry {
+            target: target & !(PAGE_SIZE - 1),
+            present: true,
+            writable: true,
+            ..MockEntry::default()
+        };
         entry
     }
     fn unmap(&mut self, addr: VirtAddr) {
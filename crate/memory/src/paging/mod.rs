@@ -14,6 +14,21 @@ pub trait PageTable {
     fn map(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut Self::Entry;
     fn unmap(&mut self, addr: VirtAddr);
     fn get_entry(&mut self, addr: VirtAddr) -> &mut Self::Entry;
+    /// Map a page to a frame allocated for it.
+    ///
+    /// Extensions managing allocated frames (e.g. `SwapExt`) should override this.
+    fn map_frame(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut Self::Entry {
+        self.map(addr, target)
+    }
+    /// Unmap a page whose frame was allocated for it.
+    /// Return the frame if no one else uses it, then it should be deallocated.
+    ///
+    /// Extensions sharing or swapping frames (e.g. `CowExt`, `SwapExt`) should override this.
+    fn unmap_frame(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        let target = self.get_entry(addr).target();
+        self.unmap(addr);
+        Some(target)
+    }
     // For testing with mock
     fn get_page_slice_mut<'a,'b>(&'a mut self, addr: VirtAddr) -> &'b mut [u8];
     fn read(&mut self, addr: VirtAddr) -> u8;
@@ -14,14 +14,18 @@ impl SwapManager for EnhancedClockSwapManager {
     }
 
     fn push(&mut self, addr: usize) {
+        if self.deque.contains(&addr) {
+            return;
+        }
         let pos = if self.clock_ptr == 0 {self.deque.len()} else {self.clock_ptr};
         self.deque.insert(pos, addr);
     }
 
     fn remove(&mut self, addr: usize) {
-        let id = self.deque.iter()
-            .position(|&x| x == addr)
-            .expect("address not found");
+        let id = match self.deque.iter().position(|&x| x == addr) {
+            Some(id) => id,
+            None => return,
+        };
         if id < self.clock_ptr {
             self.clock_ptr -= 1;
         }
@@ -31,6 +35,9 @@ impl SwapManager for EnhancedClockSwapManager {
     fn pop<T, S>(&mut self, page_table: &mut T, _swapper: &mut S) -> Option<VirtAddr>
         where T: PageTable, S: Swapper
     {
+        if self.deque.is_empty() {
+            return None;
+        }
         loop {
             let addr = self.deque[self.clock_ptr];
             // FIXME: Once define `slice`, all modifies of `entry` below will fail.
@@ -14,9 +14,10 @@ impl SwapManager for FifoSwapManager {
     }
 
     fn remove(&mut self, addr: usize) {
-        let id = self.deque.iter()
-            .position(|&x| x == addr)
-            .expect("address not found");
+        let id = match self.deque.iter().position(|&x| x == addr) {
+            Some(id) => id,
+            None => return,
+        };
         self.deque.remove(id);
     }
 
@@ -34,6 +34,9 @@ impl Swapper for MockSwapper {
         }
         Ok(())
     }
+    fn swap_drop(&mut self, token: usize) -> Result<(), ()> {
+        self.map.remove(&token).map(|_| ()).ok_or(())
+    }
 }
 
 impl MockSwapper {
@@ -74,6 +77,15 @@ mod test {
         assert_data_eq(&data, &data2);
     }
 
+    #[test]
+    fn swap_drop() {
+        let mut swapper = MockSwapper::default();
+        let mut data: [u8; 4096] = unsafe{ uninitialized() };
+        let token = swapper.swap_out(&data).unwrap();
+        swapper.swap_drop(token).unwrap();
+        assert_eq!(swapper.swap_in(token, &mut data), Err(()));
+    }
+
     #[test]
     fn invalid_token() {
         let mut swapper = MockSwapper::default();
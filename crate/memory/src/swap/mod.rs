@@ -16,7 +16,7 @@ pub trait SwapManager {
     fn tick(&mut self);
     /// Called when map a swappable page into the memory
     fn push(&mut self, addr: VirtAddr);
-    /// Called to delete the addr entry from the swap manager
+    /// Called to delete the addr entry from the swap manager, if exists
     fn remove(&mut self, addr: VirtAddr);
     /// Try to swap out a page, return then victim
     /// (The params is only used by `EnhancedClockSwapManager`)
@@ -33,10 +33,15 @@ pub trait Swapper {
     fn swap_update(&mut self, token: usize, data: &[u8]) -> Result<(), ()>;
     /// Recover data from device and deallocate the space.
     fn swap_in(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()>;
+    /// Deallocate the space without reading it, e.g. when the page is unmapped.
+    fn swap_drop(&mut self, token: usize) -> Result<(), ()>;
 }
 
 /// Wrapper for page table, supporting swap functions
-struct SwapExt<T: PageTable, M: SwapManager, S: Swapper> {
+///
+/// Only pages mapped by `map_frame` or `map_to_swappable` are managed.
+/// A victim must be present and not shared, so it is safe to be mixed with `CowExt`.
+pub struct SwapExt<T: PageTable, M: SwapManager, S: Swapper> {
     page_table: T,
     swap_manager: M,
     swapper: S,
@@ -52,19 +57,28 @@ impl<T: PageTable, M: SwapManager, S: Swapper> SwapExt<T, M, S> {
     }
     pub fn map_to_swappable(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut T::Entry {
         self.swap_manager.push(addr);
-        self.map(addr, target)
+        self.page_table.map(addr, target)
     }
     /// Swap out any one of the swapped pages, return the released PhysAddr.
     pub fn swap_out_any(&mut self) -> Result<PhysAddr, SwapError> {
-        let victim = {
-            let Self {ref mut page_table, ref mut swap_manager, ref mut swapper} = self;
-            swap_manager.pop(page_table, swapper)
-        };
-        match victim {
-            None => Err(SwapError::NoSwapped),
-            Some(addr) => self.swap_out(addr),
+        loop {
+            let victim = {
+                let Self {ref mut page_table, ref mut swap_manager, ref mut swapper} = self;
+                swap_manager.pop(page_table, swapper)
+            };
+            match victim {
+                None => return Err(SwapError::NoSwapped),
+                // The page may have been shared or unmapped since pushed, skip it
+                Some(addr) => if self.swappable(addr) {
+                    return self.swap_out(addr);
+                },
+            }
         }
     }
+    fn swappable(&mut self, addr: VirtAddr) -> bool {
+        let entry = self.page_table.get_entry(addr);
+        entry.present() && !entry.swapped() && !entry.readonly_shared() && !entry.writable_shared()
+    }
     /// Swap out page of `addr`, return the origin map target.
     fn swap_out(&mut self, addr: VirtAddr) -> Result<PhysAddr, SwapError> {
         let data = self.page_table.get_page_slice_mut(addr);
@@ -104,12 +118,66 @@ impl<T: PageTable, M: SwapManager, S: Swapper> SwapExt<T, M, S> {
             return false;
         }
         // Allocate a frame, if failed, swap out a page
-        let frame = alloc_frame().unwrap_or_else(|| self.swap_out_any().ok().unwrap());
-        self.swap_in(addr, frame).ok().unwrap();
+        let frame = alloc_frame().unwrap_or_else(|| self.swap_out_any().ok().expect("no page to swap out"));
+        self.swap_in(addr, frame).ok().expect("failed to swap in");
         true
     }
+    /// Release the space on device of a swapped page, then unmap it.
+    fn drop_swapped(&mut self, addr: VirtAddr) {
+        let token = {
+            let entry = self.page_table.get_entry(addr);
+            let token = entry.target() / PAGE_SIZE;
+            // Make it look like a normal page, so that the origin page table can unmap it
+            entry.set_swapped(false);
+            entry.set_present(true);
+            token
+        };
+        self.swapper.swap_drop(token).ok().expect("invalid swap token");
+        self.page_table.unmap(addr);
+    }
 }
 
+impl<T: PageTable, M: SwapManager, S: Swapper> PageTable for SwapExt<T, M, S> {
+    type Entry = T::Entry;
+
+    fn map(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut T::Entry {
+        self.page_table.map(addr, target)
+    }
+    fn unmap(&mut self, addr: VirtAddr) {
+        self.swap_manager.remove(addr);
+        match self.page_table.get_entry(addr).swapped() {
+            true => self.drop_swapped(addr),
+            false => self.page_table.unmap(addr),
+        }
+    }
+    fn get_entry(&mut self, addr: VirtAddr) -> &mut T::Entry {
+        self.page_table.get_entry(addr)
+    }
+    fn map_frame(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut T::Entry {
+        self.map_to_swappable(addr, target)
+    }
+    fn unmap_frame(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.swap_manager.remove(addr);
+        match self.page_table.get_entry(addr).swapped() {
+            true => {
+                self.drop_swapped(addr);
+                None
+            }
+            false => self.page_table.unmap_frame(addr),
+        }
+    }
+    fn get_page_slice_mut<'a, 'b>(&'a mut self, addr: VirtAddr) -> &'b mut [u8] {
+        self.page_table.get_page_slice_mut(addr)
+    }
+    fn read(&mut self, addr: VirtAddr) -> u8 {
+        self.page_table.read(addr)
+    }
+    fn write(&mut self, addr: VirtAddr, data: u8) {
+        self.page_table.write(addr, data)
+    }
+}
+
+#[derive(Debug)]
 pub enum SwapError {
     AlreadySwapped,
     NotSwapped,
@@ -193,4 +261,37 @@ mod test {
             assert_eq!(*(*page_fault_count).borrow(), count);
         }
     }
+
+    #[test]
+    fn swapped_page() {
+        use super::fifo::FifoSwapManager;
+        let mut pt = SwapExt::new(MockPageTable::new(), FifoSwapManager::default(), MockSwapper::default());
+        let mut data = [0u8; PAGE_SIZE];
+        pt.map_frame(0x0, PAGE_SIZE);
+        pt.write(0x0, 42);
+        assert_eq!(pt.swap_out_any().unwrap(), PAGE_SIZE);
+        assert!(pt.get_entry(0x0).swapped());
+        assert!(!pt.get_entry(0x0).present());
+
+        // No free frame, take the one of another page
+        pt.map_frame(0x1000, 2 * PAGE_SIZE);
+        assert!(pt.page_fault_handler(0x0, || None));
+        assert_eq!(pt.get_entry(0x0).target(), 2 * PAGE_SIZE);
+        assert_eq!(pt.read(0x0), 42);
+        assert!(pt.get_entry(0x1000).swapped());
+
+        // Unmap a swapped page, its space on device is dropped
+        let token = pt.get_entry(0x1000).target() / PAGE_SIZE;
+        assert_eq!(pt.unmap_frame(0x1000), None);
+        assert!(!pt.get_entry(0x1000).present());
+        assert!(!pt.get_entry(0x1000).swapped());
+        assert_eq!(pt.swapper.swap_in(token, &mut data), Err(()));
+
+        assert_eq!(pt.swap_out_any().unwrap(), 2 * PAGE_SIZE);
+        let token = pt.get_entry(0x0).target() / PAGE_SIZE;
+        pt.unmap(0x0);
+        assert!(!pt.get_entry(0x0).swapped());
+        assert_eq!(pt.swapper.swap_in(token, &mut data), Err(()));
+        assert!(pt.swap_out_any().is_err());
+    }
 }
\ No newline at end of file
@@ -1,61 +1,140 @@
-use alloc::collections::BinaryHeap;
-use core::cmp::{Ordering, PartialOrd};
+use alloc::collections::BTreeMap;
 
 type Time = usize;
 
-struct Timer<T> {
-    time: Time,
-    data: T,
-}
-
-impl<T> PartialEq for Timer<T> {
-    fn eq(&self, other: &Self) -> bool {
-        self.time.eq(&other.time)
-    }
-}
-
-impl<T> Eq for Timer<T> {}
-
-impl<T> PartialOrd for Timer<T> {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        other.time.partial_cmp(&self.time)
-    }
-}
-
-impl<T> Ord for Timer<T> {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(&other).unwrap()
-    }
-}
+/// Handle of a timer, to cancel or re-arm it
+pub type TimerId = usize;
 
+/// Timers firing on ticks.
+///
+/// A timer is due once its time comes, and stays due until popped,
+/// so the ones overdue are not lost if ticks are missed.
+/// All operations are O(log n).
 pub struct EventHub<T> {
     tick: Time,
-    timers: BinaryHeap<Timer<T>>,
+    /// Ordered by time, then by id, i.e. the order they are pushed
+    timers: BTreeMap<(Time, TimerId), T>,
+    /// The time of each timer
+    times: BTreeMap<TimerId, Time>,
+    next_id: TimerId,
 }
 
 impl<T> EventHub<T> {
     pub fn new() -> Self {
         EventHub {
             tick: 0,
-            timers: BinaryHeap::new(),
+            timers: BTreeMap::new(),
+            times: BTreeMap::new(),
+            next_id: 0,
         }
     }
     pub fn tick(&mut self) {
         self.tick += 1;
     }
+    /// Go on `ticks` at once, for the ticks missed by a sleeping timer
+    pub fn tick_by(&mut self, ticks: Time) {
+        self.tick += ticks;
+    }
+    /// The time of the earliest timer
+    pub fn next_time(&self) -> Option<Time> {
+        self.timers.keys().next().map(|&(time, _)| time)
+    }
+    /// Take a timer due or overdue
     pub fn pop(&mut self) -> Option<T> {
-        match self.timers.peek() {
-            None => return None,
-            Some(timer) if timer.time != self.tick => return None,
-            _ => {}
+        let key = match self.timers.keys().next() {
+            Some(&key) if key.0 <= self.tick => key,
+            _ => return None,
         };
-        self.timers.pop().map(|t| t.data)
+        self.times.remove(&key.1);
+        self.timers.remove(&key)
+    }
+    /// Add a timer `time_after` ticks later
+    pub fn push(&mut self, time_after: Time, data: T) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        let time = self.tick.saturating_add(time_after);
+        self.timers.insert((time, id), data);
+        self.times.insert(id, time);
+        id
     }
-    pub fn push(&mut self, time_after: Time, data: T) {
-        let time = self.tick + time_after;
-        self.timers.push(Timer { time, data });
+    /// Remove the timer `id`, return its data if it has not been popped
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        let time = self.times.remove(&id)?;
+        self.timers.remove(&(time, id))
+    }
+    /// Re-arm the timer `id` to `time_after` ticks later, return false if it has been popped
+    pub fn reset(&mut self, id: TimerId, time_after: Time) -> bool {
+        let data = match self.cancel(id) {
+            Some(data) => data,
+            None => return false,
+        };
+        let time = self.tick.saturating_add(time_after);
+        self.timers.insert((time, id), data);
+        self.times.insert(id, time);
+        true
     }
     pub fn get_time(&self) -> Time {
         self.tick
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overdue() {
+        let mut hub = EventHub::new();
+        hub.push(1, 'a');
+        hub.push(0, 'b');
+        assert_eq!(hub.pop(), Some('b'));
+        hub.tick();
+        hub.tick();
+        // Missed its tick, but still fires
+        assert_eq!(hub.pop(), Some('a'));
+        assert_eq!(hub.pop(), None);
+    }
+
+    #[test]
+    fn cancel_reset() {
+        let mut hub = EventHub::new();
+        let a = hub.push(1, 'a');
+        let b = hub.push(1, 'b');
+        let c = hub.push(2, 'c');
+        assert_eq!(hub.cancel(a), Some('a'));
+        assert_eq!(hub.cancel(a), None);
+        assert!(hub.reset(b, 3));
+        hub.tick();
+        assert_eq!(hub.pop(), None);
+        hub.tick();
+        assert_eq!(hub.pop(), Some('c'));
+        assert!(!hub.reset(c, 1));
+        hub.tick();
+        assert_eq!(hub.pop(), Some('b'));
+        assert_eq!(hub.get_time(), 3);
+    }
+
+    #[test]
+    fn next_time() {
+        let mut hub = EventHub::new();
+        assert_eq!(hub.next_time(), None);
+        let a = hub.push(5, 'a');
+        hub.push(3, 'b');
+        assert_eq!(hub.next_time(), Some(3));
+        hub.tick_by(4);
+        assert_eq!(hub.pop(), Some('b'));
+        hub.cancel(a);
+        assert_eq!(hub.next_time(), None);
+    }
+
+    #[test]
+    fn far_future() {
+        let mut hub = EventHub::new();
+        hub.tick();
+        let a = hub.push(usize::max_value(), 'a');
+        assert_eq!(hub.next_time(), Some(usize::max_value()));
+        assert!(hub.reset(a, usize::max_value()));
+        hub.tick();
+        assert_eq!(hub.pop(), None);
+    }
+}
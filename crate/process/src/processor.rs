@@ -1,45 +1,143 @@
-use alloc::{boxed::Box, collections::BTreeMap};
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
 use scheduler::*;
-use event_hub::EventHub;
+use event_hub::{EventHub, TimerId};
 use util::GetMut2;
-use core::fmt::Debug;
+use core::any::Any;
+use core::fmt::{self, Debug};
 
 #[derive(Debug)]
 pub struct Process<T> {
     pid: Pid,
+    /// The process waiting for it, the first thread of its group
     parent: Pid,
+    /// Thread group, the pid of its first thread
+    group: Pid,
+    /// Process group for job control, kept by the first thread
+    pgid: Pid,
+    /// Session of the process group, kept by the first thread
+    sid: Pid,
+    /// Stopped or continued since the parent last waited, kept by the first thread
+    job_change: Option<JobChange>,
+    /// Removed once exited and switched out, since no one is going to wait for it
+    detached: bool,
     status: Status,
     context: T,
+    /// The CPU it runs on, or whose run queue it is in
+    cpu: usize,
+    /// Is it in a run queue ?
+    queued: bool,
+    /// Kept to set on a new scheduler
+    priority: u8,
+    /// Thread-local storage
+    locals: Locals,
+    /// The timer to wake it up from sleeping
+    timer: Option<TimerId>,
+    /// Its own real-time class, `None` in the fair class of `Scheduler`
+    rt: Option<(RtPolicy, u8)>,
+    /// Ticks left to run in `RtPolicy::RoundRobin`
+    rt_slice: usize,
+    /// The owner of the lock it waits for, and the lock, to lend its priority to
+    blocked_on: Option<(Pid, usize)>,
+}
+
+/// Values of the thread-local keys of a thread, by the addresses of the keys.
+/// Only the thread itself touches them, so they need not be `Send`.
+#[derive(Default)]
+struct Locals(BTreeMap<usize, Box<Any>>);
+
+unsafe impl Send for Locals {}
+
+impl Debug for Locals {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
 }
 
 pub type Pid = usize;
 pub type ErrorCode = usize;
 
+/// Policies of the real-time class, which runs ahead of the fair class.
+/// The priority of a real-time process is in 1..=`MAX_RT_PRIORITY`, the higher the first.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RtPolicy {
+    /// Run until blocked, or preempted by a higher priority
+    Fifo,
+    /// Like `Fifo`, but give way to the same priority after a time slice
+    RoundRobin,
+}
+
+/// The highest real-time priority
+pub const MAX_RT_PRIORITY: u8 = 99;
+
+/// Time slice of `RtPolicy::RoundRobin`
+const RT_TIME_SLICE: usize = 5;
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Status {
     Ready,
     Running,
     Waiting(Pid),
     Sleeping,
+    /// Stopped by a signal, until continued
+    Stopped,
     Exited(ErrorCode),
 }
 
+/// A change of a process to report by `wait`, besides exiting
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum JobChange {
+    Stopped(ErrorCode),
+    Continued,
+}
+
 pub trait Context: Debug {
     unsafe fn switch(&mut self, target: &mut Self);
     fn new_kernel(entry: extern fn(usize) -> !, arg: usize) -> Self;
 }
 
 pub struct Processor_<T: Context, S: Scheduler> {
-    procs: BTreeMap<Pid, Process<T>>,
-    current_pid: Pid,
+    /// Boxed, so a context stays in place while its CPU switches out of it without the lock
+    procs: BTreeMap<Pid, Box<Process<T>>>,
+    /// Per-CPU states, by CPU id
+    cpus: BTreeMap<usize, Cpu<S>>,
+    /// Get the id of the calling CPU
+    cpu_id: fn() -> usize,
+    /// Interrupt a sleeping CPU by its id, so that it runs `tick_by` and schedules
+    wake_cpu: fn(usize),
+    /// Adopts the orphans, the init process of the first CPU
+    init_pid: Pid,
     event_hub: EventHub<Event>,
+    /// Results of the exited threads by `spawn`, taken by `join`.
+    /// A killed thread has none.
+    results: BTreeMap<Pid, Box<Any + Send>>,
+}
+
+/// States of a CPU
+struct Cpu<S: Scheduler> {
+    current_pid: Pid,
+    /// Run when nothing else is ready. Never in a run queue.
+    idle_pid: Pid,
     /// Choose what on next schedule ?
     next: Option<Pid>,
+    /// The process just switched out.
+    /// Its context is being saved, so it goes back to the run queue after the switch completes.
+    prev: Option<Pid>,
+    /// Run queue of this CPU
     // WARNING: if MAX_PROCESS_NUM is too large, will cause stack overflow
     scheduler: S,
+    /// Run queue of the real-time class, picked before `scheduler`
+    rt: RtQueue,
+    /// Number of processes in the run queues
+    ready: usize,
+    /// Its timer stopped by `idle_ticks`, until `tick_by`
+    sleeping: bool,
 }
 
 impl<T> Process<T> {
+    /// Is it a thread made by `add_thread`, rather than the first of its group ?
+    fn is_thread(&self) -> bool {
+        self.group != self.pid
+    }
     fn exit_code(&self) -> Option<ErrorCode> {
         match self.status {
             Status::Exited(code) => Some(code),
@@ -50,67 +148,391 @@ impl<T> Process<T> {
 
 // TODO: 除schedule()外的其它函数，应该只设置进程状态，不应调用schedule
 impl<T: Context, S: Scheduler> Processor_<T, S> {
-    pub fn new(init_context: T, scheduler: S) -> Self {
-        let init_proc = Process {
-            pid: 0,
-            parent: 0,
+    /// Make an empty processor. Each CPU must join by `add_cpu` before using it.
+    pub fn new(cpu_id: fn() -> usize, wake_cpu: fn(usize)) -> Self {
+        Processor_ {
+            procs: BTreeMap::new(),
+            cpus: BTreeMap::new(),
+            cpu_id,
+            wake_cpu,
+            init_pid: 0,
+            event_hub: EventHub::new(),
+            results: BTreeMap::new(),
+        }
+    }
+
+    /// Let the calling CPU join scheduling, with its own run queue.
+    ///
+    /// `init_context` is the one running now.
+    /// `idle_context` runs when nothing else is ready, `None` to use `init_context` as idle.
+    pub fn add_cpu(&mut self, init_context: T, idle_context: Option<T>, scheduler: S) -> Pid {
+        let cpu = self.cpu();
+        assert!(!self.cpus.contains_key(&cpu), "CPU {} has been added", cpu);
+        let init_pid = self.alloc_pid();
+        if self.cpus.is_empty() {
+            self.init_pid = init_pid;
+        }
+        self.procs.insert(init_pid, Box::new(Process {
+            pid: init_pid,
+            parent: init_pid,
+            group: init_pid,
+            pgid: init_pid,
+            sid: init_pid,
+            job_change: None,
+            detached: false,
             status: Status::Running,
             context: init_context,
+            cpu,
+            queued: false,
+            priority: 0,
+            locals: Locals::default(),
+            timer: None,
+            rt: None,
+            rt_slice: 0,
+            blocked_on: None,
+        }));
+        let idle_pid = match idle_context {
+            Some(context) => {
+                let pid = self.alloc_pid();
+                self.procs.insert(pid, Box::new(Process {
+                    pid,
+                    parent: init_pid,
+                    group: pid,
+                    pgid: pid,
+                    sid: pid,
+                    job_change: None,
+                    detached: false,
+                    status: Status::Ready,
+                    context,
+                    cpu,
+                    queued: false,
+                    priority: 0,
+                    locals: Locals::default(),
+                    timer: None,
+                    rt: None,
+                    rt_slice: 0,
+                    blocked_on: None,
+                }));
+                pid
+            }
+            None => init_pid,
         };
-        Processor_ {
-            procs: {
-                let mut map = BTreeMap::<Pid, Process<T>>::new();
-                map.insert(0, init_proc);
-                map
-            },
-            current_pid: 0,
-            event_hub: EventHub::new(),
+        self.cpus.insert(cpu, Cpu {
+            current_pid: init_pid,
+            idle_pid,
             next: None,
+            prev: None,
             scheduler,
-        }
+            rt: RtQueue::default(),
+            ready: 0,
+            sleeping: false,
+        });
+        info!("CPU {} added, init {} idle {}", cpu, init_pid, idle_pid);
+        init_pid
+    }
+
+    fn cpu(&self) -> usize {
+        (self.cpu_id)()
+    }
+
+    fn current_cpu(&mut self) -> &mut Cpu<S> {
+        let cpu = self.cpu();
+        self.cpus.get_mut(&cpu).expect("CPU has not been added")
     }
 
     pub fn set_priority(&mut self, priority: u8) {
-        self.scheduler.set_priority(self.current_pid, priority);
+        let pid = self.current_pid();
+        self.get_mut(pid).priority = priority;
+        // Set on all run queues, since the process may migrate
+        for cpu in self.cpus.values_mut() {
+            cpu.scheduler.set_priority(pid, priority);
+        }
+    }
+
+    /// Replace the scheduler of every CPU by a new one from `new_scheduler`.
+    /// Processes in the old run queues are moved to the new ones, with their priorities.
+    pub fn set_scheduler(&mut self, mut new_scheduler: impl FnMut() -> S) {
+        for (&id, cpu) in self.cpus.iter_mut() {
+            let mut scheduler = new_scheduler();
+            for process in self.procs.values().filter(|p| p.priority != 0) {
+                scheduler.set_priority(process.pid, process.priority);
+            }
+            for process in self.procs.values().filter(|p| p.queued && p.cpu == id) {
+                if cpu.rt.contains(process.pid) {
+                    continue;
+                }
+                cpu.scheduler.remove(process.pid);
+                scheduler.insert(process.pid);
+            }
+            cpu.scheduler = scheduler;
+        }
+        info!("scheduler replaced");
     }
 
     pub fn set_reschedule(&mut self) {
-        let pid = self.current_pid;
-        self.set_status(pid, Status::Ready);
+        let pid = self.current_pid();
+        if self.get(pid).status == Status::Running {
+            self.set_status(pid, Status::Ready);
+        }
     }
 
+    /// The smallest pid not used by a process, a process group or a session
     fn alloc_pid(&self) -> Pid {
-        let mut next: Pid = 0;
-        for &i in self.procs.keys() {
-            if i != next {
-                return next;
-            } else {
-                next = i + 1;
+        (0..).find(|pid| !self.procs.contains_key(pid)
+            && !self.procs.values().any(|p| !p.is_thread() && (p.pgid == *pid || p.sid == *pid))).unwrap()
+    }
+
+    /// Is `pid` running or being switched out on any CPU, or an idle process ?
+    /// Such a process must not be put into a run queue.
+    fn is_active(&self, pid: Pid) -> bool {
+        self.cpus.values().any(|cpu|
+            cpu.current_pid == pid || cpu.prev == Some(pid) || cpu.idle_pid == pid)
+    }
+
+    /// Is `pid` active, or any thread of its group if it is the first one ?
+    fn is_group_active(&self, pid: Pid) -> bool {
+        match self.get(pid).is_thread() {
+            true => self.is_active(pid),
+            false => self.procs.values().any(|p| p.group == pid && self.is_active(p.pid)),
+        }
+    }
+
+    /// Put `pid` into the run queue of its class.
+    /// Preempt the current process if it has a higher priority on this CPU.
+    /// Wake up its CPU if sleeping, and the first CPU to keep time while it runs.
+    fn queue_insert(&mut self, pid: Pid) {
+        let priority = self.rt_priority(pid);
+        let cpu_id = {
+            let process = self.get_mut(pid);
+            assert!(!process.queued);
+            process.queued = true;
+            if process.rt_slice == 0 {
+                process.rt_slice = RT_TIME_SLICE;
             }
+            process.cpu
+        };
+        {
+            let cpu = self.cpus.get_mut(&cpu_id).unwrap();
+            match priority {
+                0 => cpu.scheduler.insert(pid),
+                _ => cpu.rt.insert(pid, priority),
+            }
+            cpu.ready += 1;
+        }
+        if cpu_id == self.cpu() && priority > self.rt_priority(self.current_pid()) {
+            self.set_reschedule();
+        }
+        let first = *self.cpus.keys().next().unwrap();
+        self.wake_if_sleeping(cpu_id);
+        self.wake_if_sleeping(first);
+    }
+
+    /// Interrupt `cpu_id` if it is sleeping, unless it is this CPU
+    fn wake_if_sleeping(&mut self, cpu_id: usize) {
+        if cpu_id == self.cpu() {
+            return;
+        }
+        let cpu = self.cpus.get_mut(&cpu_id).unwrap();
+        if cpu.sleeping {
+            cpu.sleeping = false;
+            (self.wake_cpu)(cpu_id);
+        }
+    }
+
+    fn queue_remove(&mut self, pid: Pid) {
+        let cpu = {
+            let process = self.get_mut(pid);
+            assert!(process.queued);
+            process.queued = false;
+            process.cpu
+        };
+        let cpu = self.cpus.get_mut(&cpu).unwrap();
+        if !cpu.rt.remove(pid) {
+            cpu.scheduler.remove(pid);
+        }
+        cpu.ready -= 1;
+    }
+
+    /// The real-time priority `pid` runs at, the highest of its own and
+    /// the ones lent by the processes waiting for its locks. 0 in the fair class.
+    fn rt_priority(&self, pid: Pid) -> u8 {
+        // Lenders in a deadlock lend to each other, so don't go around forever
+        self.rt_priority_(pid, self.procs.len())
+    }
+
+    fn rt_priority_(&self, pid: Pid, depth: usize) -> u8 {
+        let own = self.get(pid).rt.map_or(0, |(_, priority)| priority);
+        if depth == 0 {
+            return own;
+        }
+        self.procs.values()
+            .filter(|p| p.blocked_on.map(|(owner, _)| owner) == Some(pid))
+            .map(|p| self.rt_priority_(p.pid, depth - 1))
+            .fold(own, |a, b| a.max(b))
+    }
+
+    /// The highest real-time priority in the run queue of this CPU, 0 if none
+    fn rt_top(&self) -> u8 {
+        self.cpus[&self.cpu()].rt.top().map_or(0, |(priority, _)| priority)
+    }
+
+    /// The priority of `pid` has changed, and so have the ones of the owners it lends to.
+    /// Put them in the run queues again by their new priorities.
+    fn priority_changed(&mut self, pid: Pid) {
+        let mut next = Some(pid);
+        for _ in 0..self.procs.len() {
+            let pid = match next {
+                Some(pid) if self.procs.contains_key(&pid) => pid,
+                _ => break,
+            };
+            if self.get(pid).queued {
+                self.queue_remove(pid);
+                self.queue_insert(pid);
+            }
+            next = self.get(pid).blocked_on.map(|(owner, _)| owner);
+        }
+        let current_pid = self.current_pid();
+        if self.rt_top() > self.rt_priority(current_pid) {
+            self.set_reschedule();
+        }
+    }
+
+    /// Move `pid` into the real-time class by `Some((policy, priority))`, or the fair class by `None`
+    pub fn set_rt(&mut self, pid: Pid, rt: Option<(RtPolicy, u8)>) {
+        assert!(rt.map_or(true, |(_, priority)| priority > 0 && priority <= MAX_RT_PRIORITY),
+                "real-time priority out of range");
+        info!("{} real-time class: {:?}", pid, rt);
+        self.get_mut(pid).rt = rt;
+        self.priority_changed(pid);
+    }
+
+    fn set_blocked_on(&mut self, pid: Pid, blocked_on: Option<(Pid, usize)>) {
+        let old = self.get(pid).blocked_on;
+        if old == blocked_on {
+            return;
+        }
+        self.get_mut(pid).blocked_on = blocked_on;
+        for &(owner, _) in old.iter().chain(blocked_on.iter()) {
+            if self.procs.contains_key(&owner) {
+                self.priority_changed(owner);
+            }
+        }
+    }
+
+    /// The current process waits for `lock` held by `owner`, lend its priority to it
+    pub fn lend_priority(&mut self, owner: Pid, lock: usize) {
+        let pid = self.current_pid();
+        if owner != pid {
+            self.set_blocked_on(pid, Some((owner, lock)));
+        }
+    }
+
+    /// The current process stops waiting, e.g. has got the lock
+    pub fn stop_lending(&mut self) {
+        let pid = self.current_pid();
+        self.set_blocked_on(pid, None);
+    }
+
+    /// The current process releases `lock`, the processes waiting for it take back their priorities
+    pub fn release(&mut self, lock: usize) {
+        let owner = self.current_pid();
+        let lenders: Vec<Pid> = self.procs.values()
+            .filter(|p| p.blocked_on == Some((owner, lock)))
+            .map(|p| p.pid).collect();
+        for pid in lenders {
+            self.set_blocked_on(pid, None);
         }
-        return next;
     }
 
+    /// Set the status of `pid`. An exited process keeps its status,
+    /// it may be killed while sleeping or running on another CPU.
     fn set_status(&mut self, pid: Pid, status: Status) {
         let status0 = self.get(pid).status.clone();
-        match (&status0, &status) {
-            (&Status::Ready, &Status::Ready) => return,
-            (&Status::Ready, _) => self.scheduler.remove(pid),
-            (_, &Status::Ready) => self.scheduler.insert(pid),
+        if let Status::Exited(_) = status0 {
+            return;
+        }
+        let queued = self.get(pid).queued;
+        match status == Status::Ready {
+            true if !queued && !self.is_active(pid) => self.queue_insert(pid),
+            false if queued => self.queue_remove(pid),
             _ => {}
         }
         trace!("process {} {:?} -> {:?}", pid, status0, status);
         self.get_mut(pid).status = status;
     }
 
-    /// Called by timer.
+    /// Make `pid` ready, and run it first if it is on this CPU
+    fn wakeup(&mut self, pid: Pid) {
+        self.set_status(pid, Status::Ready);
+        if self.get(pid).cpu == self.cpu() {
+            self.current_cpu().next = Some(pid);
+        }
+    }
+
+    /// Complete the last switch of this CPU, put the process switched out back to the run queue
+    fn finish_switch(&mut self) {
+        let prev = self.current_cpu().prev.take();
+        let pid = match prev {
+            Some(pid) => pid,
+            None => return,
+        };
+        let status = self.get(pid).status.clone();
+        match status {
+            Status::Ready if !self.is_active(pid) => self.queue_insert(pid),
+            // The waiter may missed it when it was still active
+            Status::Exited(_) => {
+                // Waiting for the first thread is waiting for the whole group
+                let group = self.get(pid).group;
+                for &target in [pid, group].iter() {
+                    if !self.procs.contains_key(&target) {
+                        continue;
+                    }
+                    if let Some(waiter) = self.find_waiter(target) {
+                        self.wakeup(waiter);
+                    }
+                }
+                self.remove_detached(pid);
+                if group != pid {
+                    self.remove_detached(group);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Called by timer of every CPU.
     /// Handle events.
     pub fn tick(&mut self) {
-        let current_pid = self.current_pid;
-        if self.scheduler.tick(current_pid) {
+        self.tick_by(1);
+    }
+
+    /// Like `tick`, for a CPU whose timer has slept for `ticks`
+    pub fn tick_by(&mut self, ticks: usize) {
+        self.finish_switch();
+        self.current_cpu().sleeping = false;
+        let current_pid = self.current_pid();
+        let priority = self.rt_priority(current_pid);
+        let expired = match priority {
+            0 => self.current_cpu().scheduler.tick(current_pid),
+            _ => {
+                let process = self.get_mut(current_pid);
+                match process.rt {
+                    Some((RtPolicy::RoundRobin, _)) => {
+                        process.rt_slice = process.rt_slice.saturating_sub(1);
+                        process.rt_slice == 0
+                    }
+                    _ => false,
+                }
+            }
+        };
+        if expired || self.rt_top() > priority || self.is_idle_with_work() {
             self.set_reschedule();
         }
-        self.event_hub.tick();
+        // Time goes on only by the first CPU
+        if self.cpus.keys().next() != Some(&self.cpu()) {
+            return;
+        }
+        self.event_hub.tick_by(ticks);
         while let Some(event) = self.event_hub.pop() {
             debug!("event {:?}", event);
             match event {
@@ -118,66 +540,196 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
                     self.event_hub.push(10, Event::Schedule);
                     self.set_reschedule();
                 },
-                Event::Wakeup(pid) => {
-                    self.set_status(pid, Status::Ready);
+                // It may have exited and been removed
+                Event::Wakeup(pid) if self.procs.contains_key(&pid) => {
+                    self.get_mut(pid).timer = None;
+                    self.wakeup(pid);
                     self.set_reschedule();
-                    self.next = Some(pid);
                 },
+                Event::Wakeup(_) => {}
             }
         }
     }
 
+    /// How many ticks this CPU can stop its timer for, `usize::MAX` if no event is due.
+    /// None if it is not idle, or there is something to do at the next tick.
+    /// The first CPU keeps time, so it sleeps only if all CPUs are idle.
+    /// Otherwise the CPU is marked sleeping, to be woken up by `queue_insert`.
+    pub fn idle_ticks(&mut self) -> Option<usize> {
+        self.finish_switch();
+        let cpu_id = self.cpu();
+        {
+            let cpu = &self.cpus[&cpu_id];
+            if cpu.current_pid != cpu.idle_pid || self.get(cpu.idle_pid).status != Status::Running
+                || self.cpus.values().any(|cpu| cpu.ready > 0) {
+                return None;
+            }
+        }
+        let ticks = match self.cpus.keys().next() == Some(&cpu_id) {
+            true if self.cpus.values().any(|cpu| cpu.current_pid != cpu.idle_pid) => return None,
+            true => self.event_hub.next_time().map_or(usize::max_value(), |time| time.saturating_sub(self.get_time())),
+            false => usize::max_value(),
+        };
+        if ticks == 0 {
+            return None;
+        }
+        self.current_cpu().sleeping = true;
+        Some(ticks)
+    }
+
+    /// Is this CPU idle while another one has processes waiting ?
+    fn is_idle_with_work(&self) -> bool {
+        let cpu = &self.cpus[&self.cpu()];
+        cpu.current_pid == cpu.idle_pid && self.cpus.values().any(|cpu| cpu.ready > 0)
+    }
+
     pub fn get_time(&self) -> usize {
         self.event_hub.get_time()
     }
 
+    /// Add a process to the least loaded CPU
     pub fn add(&mut self, context: T) -> Pid {
         let pid = self.alloc_pid();
+        self.add_to_group(pid, pid, context)
+    }
+
+    /// Add a thread to the group of the current process
+    pub fn add_thread(&mut self, context: T) -> Pid {
+        let pid = self.alloc_pid();
+        let group = self.get(self.current_pid()).group;
+        self.add_to_group(pid, group, context)
+    }
+
+    fn add_to_group(&mut self, pid: Pid, group: Pid, context: T) -> Pid {
+        let cpu = *self.cpus.iter().min_by_key(|(_, cpu)| cpu.ready).unwrap().0;
+        // A new process is in the process group and session of its parent
+        let (parent, pgid, sid) = {
+            let parent = self.get(self.get(self.current_pid()).group);
+            (parent.pid, parent.pgid, parent.sid)
+        };
+        // and the real-time class of its creator
+        let rt = self.get(self.current_pid()).rt;
         let process = Process {
             pid,
-            parent: self.current_pid,
+            parent,
+            group,
+            pgid,
+            sid,
+            job_change: None,
+            detached: false,
             status: Status::Ready,
             context,
+            cpu,
+            queued: false,
+            priority: 0,
+            locals: Locals::default(),
+            timer: None,
+            rt,
+            rt_slice: 0,
+            blocked_on: None,
         };
-        self.scheduler.insert(pid);
-        self.procs.insert(pid, process);
+        self.procs.insert(pid, Box::new(process));
+        // Left by a thread never joined
+        self.results.remove(&pid);
+        self.queue_insert(pid);
         pid
     }
 
     /// Called every interrupt end
     /// Do schedule ONLY IF current status != Running
     pub fn schedule(&mut self) {
-        if self.get(self.current_pid).status == Status::Running {
+        self.finish_switch();
+        let current_pid = self.current_pid();
+        let status = self.get(current_pid).status.clone();
+        if status == Status::Running {
             return;
         }
-        let pid = self.next.take().unwrap_or_else(|| self.scheduler.select().unwrap());
+        // Let the current one compete with others
+        let requeue = status == Status::Ready && current_pid != self.current_cpu().idle_pid;
+        if requeue {
+            self.queue_insert(current_pid);
+        }
+        let pid = self.pick();
+        if requeue && pid != current_pid {
+            // Put back by `finish_switch`
+            self.queue_remove(current_pid);
+        }
         self.switch_to(pid);
     }
 
+    /// Choose the next process of this CPU, the real-time class first.
+    /// Take one from the busiest CPU if the run queues are empty, or fallback to the idle.
+    fn pick(&mut self) -> Pid {
+        let cpu_id = self.cpu();
+        let next = self.current_cpu().next.take();
+        if let Some(pid) = next {
+            if self.get(pid).queued && self.rt_priority(pid) >= self.rt_top() {
+                self.queue_remove(pid);
+                return pid;
+            }
+        }
+        let busiest = match self.cpus[&cpu_id].ready {
+            0 => self.cpus.iter()
+                .filter(|(_, cpu)| cpu.ready > 0)
+                .max_by_key(|(_, cpu)| cpu.ready)
+                .map(|(&id, _)| id),
+            _ => Some(cpu_id),
+        };
+        match busiest {
+            Some(id) => {
+                let pid = {
+                    let cpu = self.cpus.get_mut(&id).unwrap();
+                    match cpu.rt.top() {
+                        Some((_, pid)) => pid,
+                        None => cpu.scheduler.select().unwrap(),
+                    }
+                };
+                self.queue_remove(pid);
+                if id != cpu_id {
+                    debug!("CPU {} steal {} from CPU {}", cpu_id, pid, id);
+                    self.get_mut(pid).cpu = cpu_id;
+                }
+                pid
+            }
+            None => self.cpus[&cpu_id].idle_pid,
+        }
+    }
+
     /// Switch process to `pid`, switch page table if necessary.
     /// Store `rsp` and point it to target kernel stack.
     /// The current status must be set before, and not be `Running`.
     fn switch_to(&mut self, pid: Pid) {
         // for debug print
-        let pid0 = self.current_pid;
+        let pid0 = self.current_pid();
 
-        if pid == self.current_pid {
-            if self.get(self.current_pid).status != Status::Running {
-                self.set_status(pid, Status::Running);
+        if pid == pid0 {
+            if self.get(pid0).status != Status::Running {
+                self.get_mut(pid).status = Status::Running;
             }
             return;
         }
-        self.current_pid = pid;
+        {
+            let cpu = self.current_cpu();
+            cpu.current_pid = pid;
+            cpu.prev = Some(pid0);
+        }
+        let cpu_id = self.cpu();
 
-        let (from, to) = self.procs.get_mut2(pid0, pid);
+        {
+            let (from, to) = self.procs.get_mut2(pid0, pid);
 
-        assert_ne!(from.status, Status::Running);
-        assert_eq!(to.status, Status::Ready);
-        to.status = Status::Running;
-        self.scheduler.remove(pid);
+            assert_ne!(from.status, Status::Running);
+            assert_eq!(to.status, Status::Ready);
+            assert!(!to.queued);
+            to.status = Status::Running;
+            to.cpu = cpu_id;
 
-        info!("switch from {} to {} {:x?}", pid0, pid, to.context);
-        unsafe { from.context.switch(&mut to.context); }
+            info!("CPU {} switch from {} to {} {:x?}", cpu_id, pid0, pid, to.context);
+            unsafe { from.context.switch(&mut to.context); }
+        }
+
+        // Back to `pid0`, maybe on another CPU
+        self.finish_switch();
     }
 
     fn get(&self, pid: Pid) -> &Process<T> {
@@ -187,77 +739,326 @@ impl<T: Context, S: Scheduler> Processor_<T, S> {
         self.procs.get_mut(&pid).unwrap()
     }
     pub fn current_context(&self) -> &T {
-        &self.get(self.current_pid).context
+        &self.get(self.current_pid()).context
+    }
+    pub fn current_context_mut(&mut self) -> &mut T {
+        let pid = self.current_pid();
+        &mut self.get_mut(pid).context
+    }
+    /// Thread-local values of the current thread, by the addresses of their keys
+    pub fn current_locals(&mut self) -> &mut BTreeMap<usize, Box<Any>> {
+        let pid = self.current_pid();
+        &mut self.get_mut(pid).locals.0
+    }
+    /// Keep the result of the current thread for `take_result`, before it exits
+    pub fn set_current_result(&mut self, result: Box<Any + Send>) {
+        let pid = self.current_pid();
+        self.results.insert(pid, result);
+    }
+    /// Take the result of the thread `pid` after waiting for it
+    pub fn take_result(&mut self, pid: Pid) -> Option<Box<Any + Send>> {
+        self.results.remove(&pid)
+    }
+    pub fn context_mut(&mut self, pid: Pid) -> Option<&mut T> {
+        self.procs.get_mut(&pid).map(|p| &mut p.context)
     }
     pub fn current_pid(&self) -> Pid {
-        self.current_pid
+        self.cpus[&self.cpu()].current_pid
+    }
+    /// The parent process of `pid`
+    pub fn parent(&self, pid: Pid) -> Option<Pid> {
+        self.procs.get(&pid)
+            .and_then(|p| self.procs.get(&p.group))
+            .map(|p| p.parent)
+    }
+    /// The init process of the first CPU, which adopts the orphans
+    pub fn init_pid(&self) -> Pid {
+        self.init_pid
+    }
+    /// The thread group of `pid`, the pid of its first thread
+    pub fn group(&self, pid: Pid) -> Option<Pid> {
+        self.procs.get(&pid).map(|p| p.group)
+    }
+    /// All threads in the group of `pid`
+    pub fn group_members(&self, pid: Pid) -> Vec<Pid> {
+        let group = match self.group(pid) {
+            Some(group) => group,
+            None => return Vec::new(),
+        };
+        self.procs.values().filter(|p| p.group == group).map(|p| p.pid).collect()
+    }
+    /// The exit code of `pid` if it has exited
+    pub fn exit_code(&self, pid: Pid) -> Option<ErrorCode> {
+        self.procs.get(&pid).and_then(|p| p.exit_code())
+    }
+    /// The first thread of the group of `pid`, which keeps the states of the process
+    fn leader(&self, pid: Pid) -> Option<&Process<T>> {
+        self.procs.get(&pid).and_then(|p| self.procs.get(&p.group)).map(|p| &**p)
+    }
+    /// The process group of `pid`
+    pub fn getpgid(&self, pid: Pid) -> Option<Pid> {
+        self.leader(pid).map(|p| p.pgid)
+    }
+    /// The session of `pid`
+    pub fn getsid(&self, pid: Pid) -> Option<Pid> {
+        self.leader(pid).map(|p| p.sid)
+    }
+    /// The processes in the process group `pgid`, by their first threads
+    pub fn pgroup_members(&self, pgid: Pid) -> Vec<Pid> {
+        self.procs.values().filter(|p| !p.is_thread() && p.pgid == pgid).map(|p| p.pid).collect()
+    }
+
+    /// Move the process of `pid` to the process group `pgid`, return false if not permitted.
+    ///
+    /// It must be the current process or a child of it, in the same session but not leading it.
+    /// `pgid` is the process itself to make a new group, or a group in the session.
+    pub fn setpgid(&mut self, pid: Pid, pgid: Pid) -> bool {
+        let group = self.get(self.current_pid()).group;
+        let sid = self.get(group).sid;
+        let target = match self.leader(pid) {
+            Some(p) if (p.pid == group || p.parent == group) && p.sid == sid && p.pid != sid => p.pid,
+            _ => return false,
+        };
+        if pgid != target && !self.procs.values().any(|p| !p.is_thread() && p.pgid == pgid && p.sid == sid) {
+            return false;
+        }
+        info!("{} join process group {}", target, pgid);
+        self.get_mut(target).pgid = pgid;
+        true
+    }
+
+    /// Make the current process lead a new session and a new process group in it, return the id.
+    /// Fail if it leads a process group already.
+    pub fn setsid(&mut self) -> Option<Pid> {
+        let group = self.get(self.current_pid()).group;
+        if self.procs.values().any(|p| !p.is_thread() && p.pgid == group) {
+            return None;
+        }
+        info!("{} lead a new session", group);
+        let process = self.get_mut(group);
+        process.pgid = group;
+        process.sid = group;
+        Some(group)
     }
 
     pub fn kill(&mut self, pid: Pid) {
         self.exit(pid, 0x1000); // TODO: error code for killed
     }
 
+    /// Exit `pid` with `error_code`, do nothing if it has exited.
+    ///
+    /// It is a zombie keeping the code until waited by its parent.
+    /// Its children are adopted by init, which does not wait for them,
+    /// so they are removed once exited.
     pub fn exit(&mut self, pid: Pid, error_code: ErrorCode) {
+        if self.get(pid).exit_code().is_some() {
+            return;
+        }
         info!("{} exit, code: {}", pid, error_code);
+        self.cancel_timer(pid);
+        self.set_blocked_on(pid, None);
         self.set_status(pid, Status::Exited(error_code));
         if let Some(waiter) = self.find_waiter(pid) {
             info!("  then wakeup {}", waiter);
-            self.set_status(waiter, Status::Ready);
-            self.next = Some(waiter);
+            self.wakeup(waiter);
+        }
+        if !self.get(pid).is_thread() && pid != self.init_pid {
+            let children: Vec<Pid> = self.procs.values()
+                .filter(|p| p.parent == pid && p.pid != pid && !p.is_thread())
+                .map(|p| p.pid).collect();
+            for child in children {
+                info!("  {} is adopted by init", child);
+                let init_pid = self.init_pid;
+                {
+                    let process = self.get_mut(child);
+                    process.parent = init_pid;
+                    process.detached = true;
+                }
+                self.remove_detached(child);
+            }
+        }
+        self.remove_detached(pid);
+    }
+
+    /// Remove `pid` if it is detached, exited, and no thread of it is active
+    fn remove_detached(&mut self, pid: Pid) {
+        let removable = match self.procs.get(&pid) {
+            Some(p) => p.detached && p.exit_code().is_some(),
+            None => false,
+        };
+        if removable && !self.is_group_active(pid) {
+            info!("remove {}", pid);
+            self.procs.remove(&pid);
         }
     }
 
+    /// Exit all threads in the group of `pid` with `error_code`.
+    ///
+    /// The first thread is kept for its parent to wait, the others are removed once switched out.
+    pub fn exit_group(&mut self, pid: Pid, error_code: ErrorCode) {
+        let group = self.get(pid).group;
+        self.exit_threads(group, error_code);
+        self.exit(group, error_code);
+    }
+
+    /// Exit the threads in the group of `pid` except `pid` itself, e.g. when it calls `exec`.
+    /// They are removed once switched out.
+    pub fn exit_threads(&mut self, pid: Pid, error_code: ErrorCode) {
+        for tid in self.group_members(pid).into_iter().filter(|&tid| tid != pid) {
+            self.get_mut(tid).detached = true;
+            self.exit(tid, error_code);
+            // It has exited before
+            self.remove_detached(tid);
+        }
+    }
+
+    /// Let `pid` sleep for `time` ticks, or until `wakeup_`
     pub fn sleep(&mut self, pid: Pid, time: usize) {
+        self.cancel_timer(pid);
         self.set_status(pid, Status::Sleeping);
-        self.event_hub.push(time, Event::Wakeup(pid));
+        let timer = self.event_hub.push(time, Event::Wakeup(pid));
+        self.get_mut(pid).timer = Some(timer);
+    }
+    fn cancel_timer(&mut self, pid: Pid) {
+        if let Some(timer) = self.get_mut(pid).timer.take() {
+            self.event_hub.cancel(timer);
+        }
+    }
+    /// Stop `pid` by signal `code` until `cont`. Do nothing if it has exited.
+    /// The parent can see it by `current_wait` with `stopped`.
+    pub fn stop(&mut self, pid: Pid, code: ErrorCode) {
+        if self.get(pid).exit_code().is_none() {
+            self.set_status(pid, Status::Stopped);
+            self.report_job_change(pid, JobChange::Stopped(code));
+        }
+    }
+    /// Continue `pid` if it is stopped.
+    /// The parent can see it by `current_wait` with `continued`.
+    pub fn cont(&mut self, pid: Pid) {
+        if self.get(pid).status == Status::Stopped {
+            self.set_status(pid, Status::Ready);
+            self.report_job_change(pid, JobChange::Continued);
+        }
+    }
+    /// Keep the change of the process of `pid` for its parent, and wake the parent up if waiting
+    fn report_job_change(&mut self, pid: Pid, change: JobChange) {
+        let group = self.get(pid).group;
+        self.get_mut(group).job_change = Some(change);
+        if let Some(waiter) = self.find_waiter(group) {
+            self.wakeup(waiter);
+        }
     }
     pub fn sleep_(&mut self, pid: Pid) {
         self.set_status(pid, Status::Sleeping);
     }
+    /// Wake up `pid` if it exists, it may have been killed and removed
     pub fn wakeup_(&mut self, pid: Pid) {
-        self.set_status(pid, Status::Ready);
+        if self.procs.contains_key(&pid) {
+            self.cancel_timer(pid);
+            self.set_status(pid, Status::Ready);
+        }
     }
 
-    /// Let current process wait for another
+    /// Let current process wait for another to exit.
+    ///
+    /// `pid` 0 waits for any child process. The children are shared by the threads of a process.
+    /// A thread can be waited (joined) by any other of its group, but it is not a child process.
     pub fn current_wait_for(&mut self, pid: Pid) -> WaitResult {
-        info!("current {} wait for {:?}", self.current_pid, pid);
-        if self.procs.values().filter(|&p| p.parent == self.current_pid).next().is_none() {
+        self.current_wait(pid, WaitOptions::default())
+    }
+
+    /// Let current process wait for another like `current_wait_for`,
+    /// and report the child processes stopped or continued by `options`.
+    pub fn current_wait(&mut self, pid: Pid, options: WaitOptions) -> WaitResult {
+        let current_pid = self.current_pid();
+        info!("current {} wait for {:?} {:?}", current_pid, pid, options);
+        let group = self.get(current_pid).group;
+        let waitable = match pid {
+            0 => self.procs.values().any(|p| p.parent == group && p.pid != group && !p.is_thread()),
+            _ => match self.procs.get(&pid) {
+                Some(p) if p.is_thread() => p.group == group && pid != current_pid,
+                Some(p) => p.parent == group && pid != group,
+                None => false,
+            },
+        };
+        if !waitable {
             return WaitResult::NotExist;
         }
-        let pid = self.try_wait(pid).unwrap_or_else(|| {
-            let current_pid = self.current_pid;
+        loop {
+            if let Some(result) = self.try_wait(pid, options) {
+                return result;
+            }
+            if options.nohang {
+                return WaitResult::NotReady;
+            }
             self.set_status(current_pid, Status::Waiting(pid));
             self.schedule(); // yield
-            self.try_wait(pid).unwrap()
-        });
-        let exit_code = self.get(pid).exit_code().unwrap();
-        info!("{} wait end and remove {}", self.current_pid, pid);
-        self.procs.remove(&pid);
-        WaitResult::Ok(pid, exit_code)
+        }
     }
 
-    /// Try to find a exited wait target
-    fn try_wait(&mut self, pid: Pid) -> Option<Pid> {
-        match pid {
+    /// Try to find a wait target exited, or changed as `options`.
+    /// Skip the exited ones still active, whose kernel stack may be in use.
+    /// A process is done when all its threads are switched out.
+    fn try_wait(&mut self, pid: Pid, options: WaitOptions) -> Option<WaitResult> {
+        let current_pid = self.current_pid();
+        let group = self.get(current_pid).group;
+        let targets: Vec<Pid> = match pid {
             0 => self.procs.values()
-                .find(|&p| p.parent == self.current_pid && p.exit_code().is_some())
-                .map(|p| p.pid),
-            _ => self.get(pid).exit_code().map(|_| pid),
+                .filter(|p| p.parent == group && p.pid != group && !p.is_thread())
+                .map(|p| p.pid).collect(),
+            _ => [pid].to_vec(),
+        };
+        for target in targets {
+            let exit_code = self.get(target).exit_code();
+            if let Some(exit_code) = exit_code {
+                if self.is_group_active(target) {
+                    continue;
+                }
+                info!("{} wait end and remove {}", current_pid, target);
+                self.procs.remove(&target);
+                return Some(WaitResult::Ok(target, exit_code));
+            }
+            let change = self.get(target).job_change.clone();
+            let result = match change {
+                Some(JobChange::Stopped(code)) if options.stopped => WaitResult::Stopped(target, code),
+                Some(JobChange::Continued) if options.continued => WaitResult::Continued(target),
+                _ => continue,
+            };
+            self.get_mut(target).job_change = None;
+            return Some(result);
         }
+        None
     }
 
     fn find_waiter(&self, pid: Pid) -> Option<Pid> {
         self.procs.values().find(|&p| {
             p.status == Status::Waiting(pid) ||
-                (p.status == Status::Waiting(0) && self.get(pid).parent == p.pid)
+                (p.status == Status::Waiting(0) && self.get(pid).parent == p.group && !self.get(pid).is_thread())
         }).map(|ref p| p.pid)
     }
 }
 
+/// What `current_wait` reports besides the exited processes
+#[derive(Debug, Default, Copy, Clone)]
+pub struct WaitOptions {
+    /// Return `NotReady` at once if nothing to report
+    pub nohang: bool,
+    /// Report the processes stopped
+    pub stopped: bool,
+    /// Report the processes continued
+    pub continued: bool,
+}
+
 #[derive(Debug)]
 pub enum WaitResult {
     /// The target process is exited with `ErrorCode`.
     Ok(Pid, ErrorCode),
+    /// The target process is stopped by signal `ErrorCode`.
+    Stopped(Pid, ErrorCode),
+    /// The target process is continued.
+    Continued(Pid),
+    /// Nothing to report now, only with `nohang`.
+    NotReady,
     /// The target process is not exist.
     NotExist,
 }
@@ -268,7 +1069,7 @@ enum Event {
     Wakeup(Pid),
 }
 
-impl<T: Context> GetMut2<Pid> for BTreeMap<Pid, Process<T>> {
+impl<T: Context> GetMut2<Pid> for BTreeMap<Pid, Box<Process<T>>> {
     type Output = Process<T>;
     fn get_mut(&mut self, id: Pid) -> &mut Process<T> {
         self.get_mut(&id).unwrap()
@@ -1,4 +1,4 @@
-use alloc::{collections::BinaryHeap, vec::Vec};
+use alloc::{boxed::Box, collections::{BinaryHeap, BTreeMap, VecDeque}, vec::Vec};
 
 type Pid = usize;
 
@@ -11,8 +11,29 @@ pub trait Scheduler {
     fn set_priority(&mut self, pid: Pid, priority: u8);
 }
 
+/// Dispatch to a boxed scheduler, so that the policy can be chosen at run time
+impl<S: Scheduler + ?Sized> Scheduler for Box<S> {
+    fn insert(&mut self, pid: Pid) {
+        (**self).insert(pid)
+    }
+    fn remove(&mut self, pid: Pid) {
+        (**self).remove(pid)
+    }
+    fn select(&mut self) -> Option<Pid> {
+        (**self).select()
+    }
+    fn tick(&mut self, current: Pid) -> bool {
+        (**self).tick(current)
+    }
+    fn set_priority(&mut self, pid: Pid, priority: u8) {
+        (**self).set_priority(pid, priority)
+    }
+}
+
 pub use self::rr::RRScheduler;
 pub use self::stride::StrideScheduler;
+pub use self::mlfq::MLFQScheduler;
+pub use self::rt::RtQueue;
 
 mod rr {
     use super::*;
@@ -192,6 +213,7 @@ mod stride {
         }
 
         fn set_priority(&mut self, pid: Pid, priority: u8) {
+            expand(&mut self.infos, pid);
             self.infos[pid].priority = priority;
             trace!("stride {} priority = {}", pid, priority);
         }
@@ -208,8 +230,360 @@ mod stride {
     }
 }
 
+mod mlfq {
+    use super::*;
+
+    /// Multi-level feedback queue
+    ///
+    /// * New processes start at the top level.
+    /// * A process using up its time slice is demoted, and a lower level has a longer slice.
+    /// * A process sleeping before its slice runs out is promoted.
+    /// * All processes are reset to the top level periodically, so hogs can not starve.
+    ///
+    /// `priority` is ignored, levels are decided by behavior only.
+    pub struct MLFQScheduler {
+        /// Time slice of the top level, doubled for each lower level
+        base_slice: usize,
+        /// Reset all processes to the top level every `boost_interval` ticks
+        boost_interval: usize,
+        ticks: usize,
+        /// Run queues from the top level
+        queues: Vec<VecDeque<Pid>>,
+        infos: Vec<MLFQProcInfo>,
+    }
+
+    #[derive(Debug, Default, Copy, Clone)]
+    struct MLFQProcInfo {
+        present: bool,
+        level: usize,
+        rest_slice: usize,
+        /// Selected to run since the last insert
+        selected: bool,
+        /// The last run ended by using up the slice
+        expired: bool,
+        /// The last tick it ran
+        last_tick: usize,
+    }
+
+    impl Scheduler for MLFQScheduler {
+        fn insert(&mut self, pid: Pid) {
+            expand(&mut self.infos, pid);
+            {
+                let info = &mut self.infos[pid];
+                assert!(!info.present);
+                info.present = true;
+                // Not promoted if it is preempted, or yields, in the tick it ran
+                let slept = self.ticks > info.last_tick + 1;
+                if info.selected && !info.expired && slept && info.level > 0 {
+                    info.level -= 1;
+                    info.rest_slice = 0;
+                    trace!("mlfq {} promote to {}", pid, info.level);
+                }
+                info.selected = false;
+                info.expired = false;
+            }
+            if self.infos[pid].rest_slice == 0 {
+                self.infos[pid].rest_slice = self.slice(self.infos[pid].level);
+            }
+            self.queues[self.infos[pid].level].push_back(pid);
+            trace!("mlfq insert {}", pid);
+        }
+
+        fn remove(&mut self, pid: Pid) {
+            let info = &mut self.infos[pid];
+            assert!(info.present);
+            info.present = false;
+            let queue = &mut self.queues[info.level];
+            let i = queue.iter().position(|&p| p == pid).unwrap();
+            queue.remove(i);
+            trace!("mlfq remove {}", pid);
+        }
+
+        fn select(&mut self) -> Option<Pid> {
+            let ret = self.queues.iter().filter_map(|queue| queue.front()).next().cloned();
+            if let Some(pid) = ret {
+                self.infos[pid].selected = true;
+            }
+            trace!("mlfq select {:?}", ret);
+            ret
+        }
+
+        fn tick(&mut self, current: Pid) -> bool {
+            expand(&mut self.infos, current);
+            assert!(!self.infos[current].present);
+
+            self.ticks += 1;
+            if self.ticks % self.boost_interval == 0 {
+                self.boost();
+            }
+
+            let level_num = self.queues.len();
+            let info = &mut self.infos[current];
+            info.last_tick = self.ticks;
+            if info.rest_slice > 0 {
+                info.rest_slice -= 1;
+            } else {
+                warn!("current process rest_slice = 0, need reschedule")
+            }
+            if info.rest_slice > 0 {
+                return false;
+            }
+            info.expired = true;
+            if info.level + 1 < level_num {
+                info.level += 1;
+                trace!("mlfq {} demote to {}", current, info.level);
+            }
+            true
+        }
+
+        fn set_priority(&mut self, _pid: Pid, _priority: u8) {
+        }
+    }
+
+    impl MLFQScheduler {
+        pub fn new(level_num: usize, base_slice: usize, boost_interval: usize) -> Self {
+            assert!(level_num > 0 && base_slice > 0 && boost_interval > 0);
+            MLFQScheduler {
+                base_slice,
+                boost_interval,
+                ticks: 0,
+                queues: (0..level_num).map(|_| VecDeque::new()).collect(),
+                infos: Vec::default(),
+            }
+        }
+        fn slice(&self, level: usize) -> usize {
+            self.base_slice << level
+        }
+        /// Move all processes to the top level, keep their order
+        fn boost(&mut self) {
+            trace!("mlfq boost");
+            for info in self.infos.iter_mut() {
+                info.level = 0;
+                info.rest_slice = self.base_slice;
+                info.expired = false;
+            }
+            let (top, rest) = self.queues.split_first_mut().unwrap();
+            for queue in rest.iter_mut() {
+                top.extend(queue.drain(..));
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn mlfq_demote_hog() {
+            let mut s = MLFQScheduler::new(3, 1, 100);
+            s.insert(0);
+            s.insert(1);
+            assert_eq!(s.select(), Some(0));
+            s.remove(0);
+            assert!(s.tick(0));
+            s.insert(0);
+            // 0 used up its slice, 1 is above it now
+            assert_eq!(s.select(), Some(1));
+            s.remove(1);
+            assert!(s.tick(1));
+            s.insert(1);
+            assert_eq!(s.select(), Some(0));
+            s.remove(0);
+            // A lower level has a longer slice
+            assert!(!s.tick(0));
+            assert!(s.tick(0));
+        }
+
+        #[test]
+        fn mlfq_promote_sleeper() {
+            let mut s = MLFQScheduler::new(3, 2, 100);
+            s.insert(0);
+            s.insert(1);
+            // Both are demoted to level 1
+            for &pid in [0, 1].iter() {
+                assert_eq!(s.select(), Some(pid));
+                s.remove(pid);
+                assert!(!s.tick(pid));
+                assert!(s.tick(pid));
+                s.insert(pid);
+            }
+            // 0 is preempted, not promoted
+            assert_eq!(s.select(), Some(0));
+            s.remove(0);
+            assert!(!s.tick(0));
+            s.insert(0);
+            assert_eq!(s.infos[0].level, 1);
+            assert_eq!(s.select(), Some(1));
+            s.remove(1);
+            while !s.tick(1) {}
+            s.insert(1);
+            // 0 sleeps before its slice runs out, while 1 runs
+            assert_eq!(s.select(), Some(0));
+            s.remove(0);
+            assert!(!s.tick(0));
+            assert_eq!(s.select(), Some(1));
+            s.remove(1);
+            while !s.tick(1) {}
+            s.insert(1);
+            s.insert(0);
+            assert_eq!(s.infos[0].level, 0);
+            assert_eq!(s.select(), Some(0));
+            // Inserted again without running, not promoted twice
+            s.remove(0);
+            s.insert(0);
+            assert_eq!(s.infos[0].level, 0);
+        }
+
+        #[test]
+        fn mlfq_boost() {
+            let mut s = MLFQScheduler::new(3, 1, 5);
+            s.insert(0);
+            s.insert(1);
+            for &pid in [0, 1].iter() {
+                assert_eq!(s.select(), Some(pid));
+                s.remove(pid);
+                assert!(s.tick(pid));
+                s.insert(pid);
+            }
+            assert_eq!(s.select(), Some(0));
+            s.remove(0);
+            assert!(!s.tick(0));
+            assert!(s.tick(0));
+            s.insert(0);
+            assert_eq!(s.infos[0].level, 2);
+            assert_eq!(s.infos[1].level, 1);
+            // The 5th tick resets all to the top level
+            s.insert(2);
+            assert_eq!(s.select(), Some(2));
+            s.remove(2);
+            assert!(s.tick(2));
+            assert_eq!(s.infos[0].level, 0);
+            assert_eq!(s.infos[1].level, 0);
+            assert_eq!(s.select(), Some(1));
+        }
+    }
+}
+
+mod rt {
+    use super::*;
+
+    /// Run queue of the real-time class, ahead of a `Scheduler`.
+    /// The highest priority first, and FIFO in a priority.
+    #[derive(Debug, Default)]
+    pub struct RtQueue {
+        levels: BTreeMap<u8, VecDeque<Pid>>,
+        priorities: BTreeMap<Pid, u8>,
+    }
+
+    impl RtQueue {
+        pub fn insert(&mut self, pid: Pid, priority: u8) {
+            assert!(self.priorities.insert(pid, priority).is_none());
+            self.levels.entry(priority).or_insert_with(VecDeque::new).push_back(pid);
+            trace!("rt insert {} at {}", pid, priority);
+        }
+        /// Return false if it is not in the queue
+        pub fn remove(&mut self, pid: Pid) -> bool {
+            let priority = match self.priorities.remove(&pid) {
+                Some(priority) => priority,
+                None => return false,
+            };
+            let empty = {
+                let level = self.levels.get_mut(&priority).unwrap();
+                level.retain(|&p| p != pid);
+                level.is_empty()
+            };
+            if empty {
+                self.levels.remove(&priority);
+            }
+            trace!("rt remove {}", pid);
+            true
+        }
+        pub fn contains(&self, pid: Pid) -> bool {
+            self.priorities.contains_key(&pid)
+        }
+        /// The first one of the highest priority
+        pub fn top(&self) -> Option<(u8, Pid)> {
+            self.levels.iter().next_back().map(|(&priority, level)| (priority, level[0]))
+        }
+    }
+}
+
 fn expand<T: Default + Clone>(vec: &mut Vec<T>, id: usize) {
     let len = vec.len();
     vec.resize(len.max(id + 1), T::default());
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run an interactive process along with CPU hogs.
+    /// It runs for less than a tick then sleeps for 3 ticks, and preempts the current one on wakeup.
+    /// Return the max ticks it waits after wakeup.
+    fn interactive_latency(mut s: impl Scheduler, hog_num: usize) -> usize {
+        const INTERACTIVE: Pid = 0;
+        for pid in 0..=hog_num {
+            s.insert(pid);
+        }
+        let mut current: Option<Pid> = None;
+        let mut wakeup_time = None;
+        let mut ready_since = Some(0);
+        let mut max_latency = 0;
+        for time in 0..1000 {
+            if wakeup_time == Some(time) {
+                wakeup_time = None;
+                ready_since = Some(time);
+                s.insert(INTERACTIVE);
+                if let Some(pid) = current.take() {
+                    s.insert(pid);
+                }
+            }
+            let pid = match current {
+                Some(pid) => pid,
+                None => loop {
+                    let pid = s.select().unwrap();
+                    s.remove(pid);
+                    if pid != INTERACTIVE {
+                        current = Some(pid);
+                        break pid;
+                    }
+                    max_latency = max_latency.max(time - ready_since.take().unwrap());
+                    wakeup_time = Some(time + 3);
+                },
+            };
+            if s.tick(pid) {
+                s.insert(pid);
+                current = None;
+            }
+        }
+        max_latency
+    }
+
+    #[test]
+    fn rt_queue() {
+        let mut q = RtQueue::default();
+        q.insert(1, 10);
+        q.insert(2, 20);
+        q.insert(3, 20);
+        assert_eq!(q.top(), Some((20, 2)));
+        assert!(q.remove(2));
+        assert!(!q.remove(2));
+        assert_eq!(q.top(), Some((20, 3)));
+        q.remove(3);
+        assert_eq!(q.top(), Some((10, 1)));
+        q.remove(1);
+        assert_eq!(q.top(), None);
+    }
+
+    #[test]
+    fn mlfq_interactive_latency() {
+        let rr = interactive_latency(RRScheduler::new(5), 4);
+        let stride = interactive_latency(StrideScheduler::new(5), 4);
+        let mlfq = interactive_latency(MLFQScheduler::new(4, 1, 50), 4);
+        println!("latency: rr {} stride {} mlfq {}", rr, stride, mlfq);
+        // It only waits for the hogs at the top level after a boost
+        assert!(mlfq <= 2);
+        assert!(mlfq < rr && mlfq < stride);
+    }
+}
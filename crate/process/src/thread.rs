@@ -25,12 +25,15 @@
 //! // But the other struct is not available ...
 //! let t: thread::Thread;   // ERROR!
 //! ```
+//!
+//! A panic in a thread by `spawn` exits the thread only, and is returned by `join` as an `Err`.
+//! The kernel panic handler calls `exit_panicking` for it.
 
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use core::any::Any;
 use core::marker::PhantomData;
-use core::ptr;
+use core::mem;
 use core::time::Duration;
 use core::ops::DerefMut;
 use processor::*;
@@ -66,10 +69,6 @@ impl<S: ThreadSupport> ThreadMod<S> {
         let pid = processor.current_pid();
         processor.sleep(pid, time);
         processor.schedule();
-
-        fn dur_to_ticks(dur: Duration) -> usize {
-            return dur.as_secs() as usize * 100 + dur.subsec_nanos() as usize / 10_000_000;
-        }
     }
 
     /// Spawns a new thread, returning a JoinHandle for it.
@@ -92,17 +91,39 @@ impl<S: ThreadSupport> ThreadMod<S> {
                 F: Send + 'static + FnOnce() -> T,
                 T: Send + 'static,
         {
+            S::processor().current_locals().insert(spawned_key(), Box::new(()));
             let f = unsafe { Box::from_raw(f as *mut F) };
-            let ret = Box::new(f());
-//            unsafe { LocalKey::<usize>::get_map() }.clear();
-            let mut processor = S::processor();
-            let pid = processor.current_pid();
-            processor.exit(pid, Box::into_raw(ret) as usize);
-            processor.schedule();
-            unreachable!()
+            let ret: Box<Any + Send> = Box::new(f());
+            ThreadMod::<S>::exit(Ok(ret))
         }
     }
 
+    /// Exit the current thread by a panic with `payload`, which `join` returns as an `Err`.
+    /// Return if it is not a thread by `spawn`.
+    ///
+    /// The caller must be sure that the processor is not locked on this CPU.
+    /// Nothing on the stack of the thread is dropped, and the locks held by it are never released.
+    pub fn exit_panicking(payload: Box<Any + Send>) {
+        if !S::processor().current_locals().contains_key(&spawned_key()) {
+            return;
+        }
+        error!("thread {} panicked", S::processor().current_pid());
+        Self::exit(Err(payload))
+    }
+
+    /// Exit the current thread by `spawn`, with the result for `join`
+    fn exit(result: ThreadResult) -> ! {
+        // Drop the thread-local values without the processor locked, they may use their keys
+        let locals = mem::replace(S::processor().current_locals(), BTreeMap::new());
+        drop(locals);
+        let mut processor = S::processor();
+        let pid = processor.current_pid();
+        processor.set_current_result(Box::new(result));
+        processor.exit(pid, 0);
+        processor.schedule();
+        unreachable!()
+    }
+
     /// Cooperatively gives up a timeslice to the OS scheduler.
     pub fn yield_now() {
         info!("yield:");
@@ -119,6 +140,40 @@ impl<S: ThreadSupport> ThreadMod<S> {
         processor.sleep_(pid);
         processor.schedule();
     }
+
+    /// Blocks like `park`, but for at most `dur`.
+    /// Whether it is unparked or timed out is up to the caller to find out.
+    pub fn park_timeout(dur: Duration) {
+        let time = dur_to_ticks(dur);
+        info!("park_timeout: {:?} ticks", time);
+        let mut processor = S::processor();
+        let pid = processor.current_pid();
+        processor.sleep(pid, time);
+        processor.schedule();
+    }
+
+    /// Blocks like `park`, or `park_timeout` if `dur` is given,
+    /// but runs `f` after the current thread is marked blocked, e.g. to release a lock.
+    /// So an `unpark` by others once `f` starts is not missed.
+    pub fn park_then(dur: Option<Duration>, f: impl FnOnce()) {
+        info!("park_then: {:?}", dur);
+        {
+            let mut processor = S::processor();
+            let pid = processor.current_pid();
+            match dur {
+                Some(dur) => processor.sleep(pid, dur_to_ticks(dur)),
+                None => processor.sleep_(pid),
+            }
+        }
+        f();
+        S::processor().schedule();
+    }
+}
+
+/// Ticks of 10ms in `dur`, saturated at the max
+fn dur_to_ticks(dur: Duration) -> usize {
+    let ticks = dur.as_secs().saturating_mul(100).saturating_add(dur.subsec_nanos() as u64 / 10_000_000);
+    ticks.min(usize::max_value() as u64) as usize
 }
 
 /// A handle to a thread.
@@ -145,99 +200,71 @@ pub struct JoinHandle<S: ThreadSupport, T> {
     mark: PhantomData<T>,
 }
 
-impl<S: ThreadSupport, T> JoinHandle<S, T> {
+/// The result of a thread by `spawn`, kept by the processor until `join`
+type ThreadResult = Result<Box<Any + Send>, Box<Any + Send>>;
+
+/// Marks the threads by `spawn` in their thread-local storage
+static SPAWNED: u8 = 0;
+
+fn spawned_key() -> usize {
+    &SPAWNED as *const u8 as usize
+}
+
+impl<S: ThreadSupport, T: Send + 'static> JoinHandle<S, T> {
     /// Extracts a handle to the underlying thread.
     pub fn thread(&self) -> &Thread<S> {
         &self.thread
     }
     /// Waits for the associated thread to finish.
-    pub fn join(self) -> Result<T, ()> {
+    /// Return `Err` with the payload if it panicked, or an error if it is killed.
+    pub fn join(self) -> Result<T, Box<Any + Send>> {
         let mut processor = S::processor();
-        match processor.current_wait_for(self.thread.pid) {
-            WaitResult::Ok(_, exit_code) => unsafe {
-                Ok(*Box::from_raw(exit_code as *mut T))
-            }
-            WaitResult::NotExist => Err(()),
+        let result = processor.current_wait_for(self.thread.pid);
+        match result {
+            WaitResult::Ok(pid, _) => match processor.take_result(pid) {
+                Some(result) => {
+                    let result = *result.downcast::<ThreadResult>().ok().unwrap();
+                    result.map(|ret| *ret.downcast::<T>().ok().unwrap())
+                }
+                None => Err(Box::new("the thread is killed")),
+            },
+            WaitResult::NotExist => Err(Box::new("the thread has been waited")),
+            _ => unreachable!(),
         }
     }
 }
 
-//pub struct LocalKey<T: 'static> {
-//    init: fn() -> T,
-//}
-//
-//impl<T: 'static> LocalKey<T> {
-//    pub fn with<F, R>(&'static self, f: F) -> R
-//        where F: FnOnce(&T) -> R
-//    {
-//        let map = unsafe { Self::get_map() };
-//        let key = self as *const _ as usize;
-//        if !map.contains_key(&key) {
-//            map.insert(key, Box::new((self.init)()));
-//        }
-//        let value = map.get(&key).unwrap().downcast_ref::<T>().expect("type error");
-//        f(value)
-//    }
-//    pub const fn new(init: fn() -> T) -> Self {
-//        LocalKey { init }
-//    }
-//    /// Get `BTreeMap<usize, Box<Any>>` at the current kernel stack bottom
-//    /// The stack must be aligned with 0x8000
-//    unsafe fn get_map() -> &'static mut BTreeMap<usize, Box<Any>> {
-//        const STACK_SIZE: usize = 0x8000;
-//        let stack_var = 0usize;
-//        let ptr = (&stack_var as *const _ as usize) / STACK_SIZE * STACK_SIZE;
-//        let map = unsafe { &mut *(ptr as *mut Option<BTreeMap<usize, Box<Any>>>) };
-//        if map.is_none() {
-//            *map = Some(BTreeMap::new());
-//        }
-//        map.as_mut().unwrap()
-//    }
-//}
-//
-//pub mod test {
-//    use thread;
-//    use core::cell::RefCell;
-//    use core::time::Duration;
-//
-//    pub fn unpack() {
-//        let parked_thread = thread::spawn(|| {
-//            println!("Parking thread");
-//            thread::park();
-//            println!("Thread unparked");
-//            5
-//        });
-//
-//        // Let some time pass for the thread to be spawned.
-//        thread::sleep(Duration::from_secs(2));
-//
-//        println!("Unpark the thread");
-//        parked_thread.thread().unpark();
-//
-//        let ret = parked_thread.join().unwrap();
-//        assert_eq!(ret, 5);
-//    }
-//
-//    pub fn local_key() {
-//        static FOO: thread::LocalKey<RefCell<usize>> = thread::LocalKey::new(|| RefCell::new(1));
-//
-//        FOO.with(|f| {
-//            assert_eq!(*f.borrow(), 1);
-//            *f.borrow_mut() = 2;
-//        });
-//
-//        // each thread starts out with the initial value of 1
-//        thread::spawn(move || {
-//            FOO.with(|f| {
-//                assert_eq!(*f.borrow(), 1);
-//                *f.borrow_mut() = 3;
-//            });
-//        }).join();
-//
-//        // we retain our original value of 2 despite the child thread
-//        FOO.with(|f| {
-//            assert_eq!(*f.borrow(), 2);
-//        });
-//        println!("local key success");
-//    }
-//}
+/// A key of thread-local storage, each thread has its own value of it.
+///
+/// The kernel defines it for its `ThreadSupport`, to be used as a `static`.
+pub struct LocalKey<T: 'static, S: ThreadSupport> {
+    init: fn() -> T,
+    mark: PhantomData<S>,
+}
+
+impl<T: 'static, S: ThreadSupport> LocalKey<T, S> {
+    pub const fn new(init: fn() -> T) -> Self {
+        LocalKey { init, mark: PhantomData }
+    }
+    /// Call `f` with the value of the current thread, made by `init` on the first access
+    pub fn with<F, R>(&'static self, f: F) -> R
+        where F: FnOnce(&T) -> R
+    {
+        let key = self as *const _ as usize;
+        let value = match Self::get(key) {
+            Some(value) => value,
+            None => {
+                // Not locked, `init` may use other keys
+                let value: Box<Any> = Box::new((self.init)());
+                S::processor().current_locals().entry(key).or_insert(value);
+                Self::get(key).unwrap()
+            }
+        };
+        // Boxed, it stays in place until the thread exits
+        f(unsafe { &*value })
+    }
+    fn get(key: usize) -> Option<*const T> {
+        S::processor().current_locals().get(&key)
+            .map(|value| value.downcast_ref::<T>().expect("type error") as *const T)
+    }
+}
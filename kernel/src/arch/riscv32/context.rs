@@ -24,9 +24,11 @@ impl TrapFrame {
         tf.sstatus.set_spp(sstatus::SPP::Supervisor);
         tf
     }
-    fn new_user_thread(entry_addr: usize, sp: usize) -> Self {
+    pub fn new_user_thread(entry_addr: usize, sp: usize, is32: bool, argc: usize, argv: usize) -> Self {
         use core::mem::zeroed;
         let mut tf: Self = unsafe { zeroed() };
+        tf.x[10] = argc; // a0
+        tf.x[11] = argv; // a1
         tf.x[2] = sp;
         tf.sepc = entry_addr;
         tf.sstatus = sstatus::read();
@@ -35,7 +37,34 @@ impl TrapFrame {
         tf
     }
     pub fn is_user(&self) -> bool {
-        unimplemented!()
+        match self.sstatus.spp() {
+            sstatus::SPP::User => true,
+            sstatus::SPP::Supervisor => false,
+        }
+    }
+    /// Whether it is from a 32-bit user program. Always true on RV32.
+    pub fn is_32bit(&self) -> bool {
+        true
+    }
+    pub fn get_sp(&self) -> usize {
+        self.x[2]
+    }
+    /// Value of the return value register
+    pub fn get_ret(&self) -> usize {
+        self.x[10]
+    }
+    /// Return to the signal handler `entry(sig)` with stack `sp`, then to `restorer`
+    pub fn set_signal_handler(&mut self, entry: usize, sig: usize, sp: usize, restorer: usize) {
+        self.sepc = entry;
+        self.x[10] = sig;   // a0
+        self.x[1] = restorer;   // ra
+        self.x[2] = sp;
+    }
+    /// Restore the registers saved in user memory, except the privileged ones
+    pub fn restore_user(&mut self, saved: &TrapFrame) {
+        self.x = saved.x;
+        self.x[0] = 0;
+        self.sepc = saved.sepc;
     }
 }
 
@@ -142,10 +171,10 @@ impl Context {
             tf: TrapFrame::new_kernel_thread(entry, arg, kstack_top),
         }.push_at(kstack_top)
     }
-    pub unsafe fn new_user_thread(entry_addr: usize, ustack_top: usize, kstack_top: usize, is32: bool, cr3: usize) -> Self {
+    pub unsafe fn new_user_thread(entry_addr: usize, ustack_top: usize, kstack_top: usize, is32: bool, cr3: usize, argc: usize, argv: usize) -> Self {
         InitStack {
             context: ContextData::new(cr3),
-            tf: TrapFrame::new_user_thread(entry_addr, ustack_top),
+            tf: TrapFrame::new_user_thread(entry_addr, ustack_top, is32, argc, argv),
         }.push_at(kstack_top)
     }
     pub unsafe fn new_fork(tf: &TrapFrame, kstack_top: usize, cr3: usize) -> Self {
@@ -158,4 +187,17 @@ impl Context {
             },
         }.push_at(kstack_top)
     }
+    /// A thread returning to the user by `tf` with stack `ustack_top` and thread pointer `tls`
+    pub unsafe fn new_clone(tf: &TrapFrame, ustack_top: usize, tls: usize, kstack_top: usize, cr3: usize) -> Self {
+        InitStack {
+            context: ContextData::new(cr3),
+            tf: {
+                let mut tf = tf.clone();
+                tf.x[10] = 0; // a0
+                tf.x[2] = ustack_top; // sp
+                tf.x[4] = tls; // tp
+                tf
+            },
+        }.push_at(kstack_top)
+    }
 }
\ No newline at end of file
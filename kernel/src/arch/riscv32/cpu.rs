@@ -0,0 +1,7 @@
+/// Get the id of this CPU. Only one hart is used.
+pub fn id() -> usize {
+    0
+}
+
+/// Interrupt the CPU `id` to schedule. There is no other hart to interrupt.
+pub fn send_reschedule(_id: usize) {}
@@ -1,4 +1,5 @@
 use super::riscv::register::*;
+use process::signal::{SIGILL, SIGSEGV};
 pub use self::context::*;
 
 #[path = "context.rs"]
@@ -37,6 +38,19 @@ pub unsafe fn restore(flags: usize) {
     }
 }
 
+/// Halt until an interrupt is pending, then enable interrupts to take it
+#[inline(always)]
+pub unsafe fn enable_and_wait() {
+    asm!("wfi" :::: "volatile");
+    sstatus::set_sie();
+}
+
+/// Are interrupts enabled on this CPU ?
+#[inline(always)]
+pub fn enabled() -> bool {
+    sstatus::read().sie()
+}
+
 #[no_mangle]
 pub extern fn rust_trap(tf: &mut TrapFrame) {
     use super::riscv::register::scause::{Trap, Interrupt as I, Exception as E};
@@ -45,15 +59,22 @@ pub extern fn rust_trap(tf: &mut TrapFrame) {
         Trap::Interrupt(I::SupervisorTimer) => timer(),
         Trap::Exception(E::IllegalInstruction) => illegal_inst(tf),
         Trap::Exception(E::UserEnvCall) => syscall(tf),
-        _ => ::trap::error(tf),
+        Trap::Exception(E::InstructionPageFault) => page_fault(tf),
+        Trap::Exception(E::LoadPageFault) => page_fault(tf),
+        Trap::Exception(E::StorePageFault) => page_fault(tf),
+        _ => ::trap::error(tf, SIGSEGV),
     }
-    ::trap::before_return();
+    ::trap::before_return(tf);
     trace!("Interrupt end");
 }
 
 fn timer() {
+    #[cfg(feature = "lockdep")]
+    ::sync::lockdep::irq_enter();
     ::trap::timer();
     super::timer::set_next();
+    #[cfg(feature = "lockdep")]
+    ::sync::lockdep::irq_exit();
 }
 
 fn syscall(tf: &mut TrapFrame) {
@@ -62,9 +83,19 @@ fn syscall(tf: &mut TrapFrame) {
     tf.x[10] = ret as usize;
 }
 
+fn page_fault(tf: &mut TrapFrame) {
+    let addr = tf.sbadaddr;
+    trace!("\nEXCEPTION: Page Fault @ {:#x}", addr);
+
+    use memory::page_fault_handler;
+    if !page_fault_handler(addr) {
+        ::trap::error(tf, SIGSEGV);
+    }
+}
+
 fn illegal_inst(tf: &mut TrapFrame) {
     if !emulate_mul_div(tf) {
-        ::trap::error(tf);
+        ::trap::error(tf, SIGILL);
     }
 }
 
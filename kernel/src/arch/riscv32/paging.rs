@@ -1,6 +1,6 @@
 use consts::{KERNEL_PML4, RECURSIVE_PAGE_PML4};
 // Depends on kernel
-use memory::{active_table, alloc_frame, alloc_stack, dealloc_frame};
+use memory::{active_table, alloc_frame, alloc_stack, dealloc_frame, ActiveTable};
 use super::riscv::addr::*;
 use super::riscv::asm::{sfence_vma, sfence_vma_all};
 use super::riscv::paging::{Mapper, PageTable as RvPageTable, PageTableEntry, PageTableFlags as EF, RecursivePageTable};
@@ -80,15 +80,21 @@ impl ActivePageTable {
         ActivePageTable(RecursivePageTable::new(&mut *ROOT_PAGE_TABLE).unwrap())
     }
     fn with_temporary_map(&mut self, frame: &Frame, f: impl FnOnce(&mut ActivePageTable, &mut RvPageTable)) {
+        let table = unsafe { self.map_temporary(frame) };
+        f(self, table);
+        self.unmap_temporary();
+    }
+    /// Map `frame` to a temporary page, return it as a page table.
+    /// The page is valid until `unmap_temporary`.
+    unsafe fn map_temporary(&mut self, frame: &Frame) -> &'static mut RvPageTable {
         // Create a temporary page
         let page = Page::of_addr(VirtAddr::new(0xcafebabe));
         assert!(self.0.translate_page(page).is_none(), "temporary page is already mapped");
         // Map it to table
         self.map(page.start_address().as_usize(), frame.start_address().as_u32() as usize);
-        // Call f
-        let table = unsafe { &mut *(page.start_address().as_usize() as *mut _) };
-        f(self, table);
-        // Unmap the page
+        &mut *(page.start_address().as_usize() as *mut _)
+    }
+    fn unmap_temporary(&mut self) {
         self.unmap(0xcafebabe);
     }
 }
@@ -140,7 +146,7 @@ pub struct InactivePageTable0 {
 }
 
 impl InactivePageTable for InactivePageTable0 {
-    type Active = ActivePageTable;
+    type Active = ActiveTable;
 
     fn new() -> Self {
         let mut pt = Self::new_bare();
@@ -159,20 +165,21 @@ impl InactivePageTable for InactivePageTable0 {
     }
 
     fn edit(&mut self, f: impl FnOnce(&mut Self::Active)) {
-        active_table().with_temporary_map(&satp::read().frame(), |active_table, p2_table: &mut RvPageTable| {
-            let backup = p2_table[RECURSIVE_PAGE_PML4].clone();
+        let mut active_table = active_table();
+        let p2_table = unsafe { active_table.map_temporary(&satp::read().frame()) };
+        let backup = p2_table[RECURSIVE_PAGE_PML4].clone();
 
-            // overwrite recursive mapping
-            p2_table[RECURSIVE_PAGE_PML4].set(self.p2_frame.clone(), EF::VALID);
-            sfence_vma_all();
+        // overwrite recursive mapping
+        p2_table[RECURSIVE_PAGE_PML4].set(self.p2_frame.clone(), EF::VALID);
+        sfence_vma_all();
 
-            // execute f in the new context
-            f(active_table);
+        // execute f in the new context
+        f(&mut active_table);
 
-            // restore recursive mapping to original p4 table
-            p2_table[RECURSIVE_PAGE_PML4] = backup;
-            sfence_vma_all();
-        });
+        // restore recursive mapping to original p4 table
+        p2_table[RECURSIVE_PAGE_PML4] = backup;
+        sfence_vma_all();
+        active_table.unmap_temporary();
     }
 
     unsafe fn activate(&self) {
@@ -232,6 +239,12 @@ impl InactivePageTable0 {
     }
 }
 
+/// Fill the frame at `target` with zero, e.g. a new frame of shared memory
+pub fn zero_frame(target: usize) {
+    let frame = Frame::of_addr(PhysAddr::new(target as u32));
+    active_table().with_temporary_map(&frame, |_, table: &mut RvPageTable| table.zero());
+}
+
 impl Drop for InactivePageTable0 {
     fn drop(&mut self) {
         info!("PageTable dropping: {:?}", self);
@@ -1,5 +1,9 @@
 use super::riscv::register::*;
 use super::bbl::sbi;
+use time::ClockSource;
+
+/// Frequency of the `time` CSR @ QEMU
+const TIMEBASE_FREQ: u64 = 25_000_000;
 
 #[cfg(target_pointer_width = "64")]
 pub fn get_cycle() -> u64 {
@@ -18,6 +22,16 @@ pub fn get_cycle() -> u64 {
     }
 }
 
+/// The `time` CSR
+pub fn clock_source() -> ClockSource {
+    ClockSource { name: "time", read: get_cycle, freq: TIMEBASE_FREQ }
+}
+
+/// No RTC, the wall clock starts from the epoch
+pub fn read_epoch() -> u64 {
+    0
+}
+
 pub fn init() {
     // Enable supervisor timer interrupt
     unsafe { sie::set_stimer(); }
@@ -28,10 +42,21 @@ pub fn init() {
 
 pub fn set_next() {
     // 100Hz @ QEMU
-    let timebase = 250000;
+    let timebase = TIMEBASE_FREQ / 100;
     set_timer(get_cycle() + timebase);
 }
 
+/// Interrupt once after `ticks`, instead of at the next tick
+pub fn set_oneshot(ticks: usize) {
+    let timebase = TIMEBASE_FREQ / 100;
+    set_timer(get_cycle().saturating_add(timebase.saturating_mul(ticks as u64)));
+}
+
+/// Back to interrupt every tick. The next one is set by each interrupt.
+pub fn set_periodic() {
+    set_next();
+}
+
 fn set_timer(t: u64) {
     #[cfg(feature = "no_bbl")]
     unsafe {
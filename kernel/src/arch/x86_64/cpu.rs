@@ -1,3 +1,30 @@
+/// Get the id of this CPU, its initial local APIC id.
+/// Available before the local APIC is mapped.
+pub fn id() -> usize {
+    use core::arch::x86_64::__cpuid;
+    (unsafe { __cpuid(1) }.ebx >> 24) as usize
+}
+
+/// Interrupt the CPU `id` to schedule, if it is halted.
+/// Without `use_apic` the BSP only takes interrupts from the PIC, it wakes up by its timer.
+pub fn send_reschedule(id: usize) {
+    use super::driver::apic;
+    use super::interrupt::consts::{T_IRQ0, IRQ_RESCHEDULE};
+    if cfg!(feature = "use_apic") || id != 0 {
+        apic::send_ipi(id as u8, T_IRQ0 + IRQ_RESCHEDULE);
+    }
+}
+
+/// Interrupt the CPU `id` to flush the page of a TLB shootdown.
+/// Without `use_apic` the BSP flushes it in its next interrupt.
+pub fn send_tlb_shootdown(id: usize) {
+    use super::driver::apic;
+    use super::interrupt::consts::{T_IRQ0, IRQ_TLB_SHOOTDOWN};
+    if cfg!(feature = "use_apic") || id != 0 {
+        apic::send_ipi(id as u8, T_IRQ0 + IRQ_TLB_SHOOTDOWN);
+    }
+}
+
 /// Exit qemu
 /// See: https://wiki.osdev.org/Shutdown
 /// Must run qemu with `-device isa-debug-exit`
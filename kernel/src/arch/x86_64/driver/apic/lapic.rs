@@ -1,3 +1,6 @@
+use arch::interrupt::consts::{T_IRQ0, IRQ_TIMER};
+use spin::Once;
+
 extern {
 	//noinspection RsStaticConstNaming
 	static mut lapic: *const ();
@@ -8,39 +11,114 @@ extern {
 
 pub fn set_addr(lapic_addr: usize) {
 	unsafe {
-//		lapic = lapic_addr;
+		lapic = lapic_addr as *const ();
 	}
 }
 
 pub fn init() {
     warn!("lapic::init use C lib");
 	unsafe {
-//		lapicinit();
+		lapicinit();
 	}
+	// Replace the uncalibrated period of the C lib
+	set_periodic();
     info!("lapic: init end");
 }
 
+// Timer registers, divided by 4 for use as u32 indices.
+const TIMER: isize = 0x0320 / 4;	// Local Vector Table 0 (TIMER)
+const PERIODIC: u32 = 0x0002_0000;
+const MASKED: u32 = 0x0001_0000;
+const TICR: isize = 0x0380 / 4;		// Timer Initial Count
+const TCCR: isize = 0x0390 / 4;		// Timer Current Count
+const TDCR: isize = 0x03E0 / 4;		// Timer Divide Configuration
+const X1: u32 = 0x0000_000B;		// divide counts by 1
+
+// Interrupt command registers
+const ICRLO: isize = 0x0300 / 4;	// Interrupt Command
+const DELIVS: u32 = 0x0000_1000;	// Delivery status
+const ICRHI: isize = 0x0310 / 4;	// Interrupt Command [63:32]
+
+unsafe fn write(index: isize, value: u32) {
+	let base = lapic as *mut u32;
+	base.offset(index).write_volatile(value);
+	base.offset(0x0020 / 4).read_volatile();	// wait for write to finish, by reading
+}
+
+unsafe fn read(index: isize) -> u32 {
+	(lapic as *const u32).offset(index).read_volatile()
+}
+
+/// Counts of the timer in a tick of 10ms, calibrated against the PIT once.
+/// All local APICs share the bus frequency.
+fn tick_count() -> u32 {
+	static TICK_COUNT: Once<u32> = Once::new();
+	*TICK_COUNT.call_once(|| unsafe {
+		use arch::driver::pit;
+		write(TDCR, X1);
+		write(TIMER, MASKED | (T_IRQ0 + IRQ_TIMER) as u32);
+		write(TICR, u32::max_value());
+		pit::wait_ms(10);
+		let count = u32::max_value() - read(TCCR);
+		info!("lapic: timer {} counts per tick", count);
+		count
+	})
+}
+
+/// Interrupt every tick
+pub fn set_periodic() {
+	if unsafe { lapic.is_null() } {
+		return;
+	}
+	let count = tick_count();
+	unsafe {
+		write(TDCR, X1);
+		write(TIMER, PERIODIC | (T_IRQ0 + IRQ_TIMER) as u32);
+		write(TICR, count);
+	}
+}
+
+/// Interrupt once after `ticks`, instead of every tick
+pub fn set_oneshot(ticks: usize) {
+	if unsafe { lapic.is_null() } {
+		return;
+	}
+	let count = (tick_count() as u64).saturating_mul(ticks as u64).min(u32::max_value() as u64);
+	unsafe {
+		write(TIMER, (T_IRQ0 + IRQ_TIMER) as u32);
+		write(TICR, count as u32);
+	}
+}
+
 pub fn ack(_irq: u8) {
 	unsafe {
-//		lapiceoi();
+		lapiceoi();
+	}
+}
+
+/// Send the interrupt `vector` to the local APIC `apic_id`
+pub fn send_ipi(apic_id: u8, vector: u8) {
+	unsafe {
+		write(ICRHI, (apic_id as u32) << 24);
+		write(ICRLO, vector as u32);
+		while read(ICRLO) & DELIVS != 0 {}
 	}
 }
 
 pub fn start_ap(apicid: u8, addr: u32) {
     warn!("lapic::start_ap use C lib");
 	unsafe {
-//		lapicstartap(apicid, addr);
+		lapicstartap(apicid, addr);
 	}
 }
 
 pub fn lapic_id() -> u8 {
-	0
-//	unsafe{
-//        if lapic.is_null() {
-//            warn!("lapic is null. return lapic id = 0");
-//            return 0;
-//        }
-//        let ptr = (lapic as *const u32).offset(0x0020 / 4);
-//        (ptr.read_volatile() >> 24) as u8
-//	}
-}
\ No newline at end of file
+	unsafe{
+        if lapic.is_null() {
+            warn!("lapic is null. return lapic id = 0");
+            return 0;
+        }
+        let ptr = (lapic as *const u32).offset(0x0020 / 4);
+        (ptr.read_volatile() >> 24) as u8
+	}
+}
@@ -1,5 +1,5 @@
 pub use self::ioapic::IOAPIC;
-pub use self::lapic::{ack, start_ap, lapic_id};
+pub use self::lapic::{ack, start_ap, lapic_id, set_periodic, set_oneshot, send_ipi};
 
 mod lapic;
 mod ioapic;
@@ -12,6 +12,9 @@ pub fn init() {
 	self::ioapic::init();
 }
 
+/// Init the local APIC of an AP, starting its timer
 pub fn other_init() {
+	use consts::KERNEL_OFFSET;
+	self::lapic::set_addr(KERNEL_OFFSET + 0xfee00000);
 	self::lapic::init();
 }
\ No newline at end of file
@@ -6,6 +6,7 @@ pub mod serial;
 pub mod pic;
 pub mod keyboard;
 pub mod pit;
+pub mod rtc;
 pub mod ide;
 
 pub fn init() {
@@ -8,11 +8,28 @@ pub fn init() {
     info!("pit: init end");
 }
 
+/// Interrupt once after `ms` milliseconds, at most 54
+pub fn set_oneshot(ms: u32) {
+    unsafe { PIT.set_oneshot(ms); }
+}
+
+/// Back to interrupt every tick
+pub fn set_periodic() {
+    unsafe { PIT.init(100); }
+}
+
+/// Busy wait `ms` (< 55) milliseconds by channel 2, to calibrate other clocks
+pub fn wait_ms(ms: u32) {
+    unsafe { PIT.wait_ms(ms); }
+}
+
 struct Pit {
     chan0: Pio<u8>,
     chan1: Pio<u8>,
     chan2: Pio<u8>,
     command: Pio<u8>,
+    /// Gate of channel 2, and its output
+    port_b: Pio<u8>,
 }
 
 impl Pit {
@@ -22,6 +39,7 @@ impl Pit {
             chan1: Pio::new(port+1),
             chan2: Pio::new(port+2),
             command: Pio::new(port+3),
+            port_b: Pio::new(0x61),
         }
     }
     pub fn init(&mut self, freq: u32) {
@@ -30,6 +48,26 @@ impl Pit {
         self.chan0.write((div & 0xFF) as u8);
         self.chan0.write((div >> 8) as u8);
     }
+    fn set_oneshot(&mut self, ms: u32) {
+        let count = (TIMER_FREQ / 1000 * ms).min(0xFFFF);
+        self.command.write(TIMER_SEL0 | TIMER_INTTC | TIMER_16BIT);
+        self.chan0.write((count & 0xFF) as u8);
+        self.chan0.write((count >> 8) as u8);
+    }
+    /// Count down on channel 2 with the speaker off, until its output goes high
+    fn wait_ms(&mut self, ms: u32) {
+        let count = TIMER_FREQ / 1000 * ms;
+        assert!(count < 0x10000);
+        let gate = self.port_b.read() & !(PORT_B_SPEAKER | PORT_B_GATE2);
+        self.port_b.write(gate);
+        self.command.write(TIMER_SEL2 | TIMER_INTTC | TIMER_16BIT);
+        self.chan2.write((count & 0xFF) as u8);
+        self.chan2.write((count >> 8) as u8);
+        // Counting starts when the gate goes high
+        self.port_b.write(gate | PORT_B_GATE2);
+        while self.port_b.read() & PORT_B_OUT2 == 0 {}
+        self.port_b.write(gate);
+    }
     fn divisor(freq: u32) -> u16 {
         let div = (TIMER_FREQ + freq / 2) / freq;
         assert!(div < 0x10000);
@@ -39,5 +77,10 @@ impl Pit {
 
 const TIMER_FREQ    : u32 = 1193182;
 const TIMER_SEL0    : u8 = 0x00;                    // select counter 0
+const TIMER_SEL2    : u8 = 0x80;                    // select counter 2
+const TIMER_INTTC   : u8 = 0x00;                    // mode 0, interrupt on terminal count
 const TIMER_RATEGEN : u8 = 0x04;                    // mode 2, rate generator
-const TIMER_16BIT   : u8 = 0x30;                    // r/w counter 16 bits, LSB first
\ No newline at end of file
+const TIMER_16BIT   : u8 = 0x30;                    // r/w counter 16 bits, LSB first
+const PORT_B_GATE2  : u8 = 0x01;                    // gate of counter 2
+const PORT_B_SPEAKER: u8 = 0x02;                    // speaker data enable
+const PORT_B_OUT2   : u8 = 0x20;                    // output of counter 2
\ No newline at end of file
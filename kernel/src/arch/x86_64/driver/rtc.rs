@@ -0,0 +1,85 @@
+//! CMOS real-time clock, read once for the wall clock
+
+use super::redox_syscall::io::{Io, Pio};
+
+/// Seconds since the Unix epoch, at the second the RTC reads
+pub fn read_epoch() -> u64 {
+    let mut cmos = Cmos::new();
+    // Read until two reads agree, so no update happens in the middle
+    let mut time = cmos.read_time();
+    loop {
+        let again = cmos.read_time();
+        if again == time {
+            break;
+        }
+        time = again;
+    }
+    let (sec, min, hour, day, month, year) = (time[0], time[1], time[2], time[3], time[4], time[5]);
+    let days = days_from_civil(2000 + year, month, day);
+    info!("rtc: 20{:02}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, hour, min, sec);
+    days * 86400 + hour * 3600 + min * 60 + sec
+}
+
+/// Days since 1970-01-01 of a date after it, in the Gregorian calendar
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // Count from March, so the leap day is the last one of a year
+    let (year, month) = match month {
+        1 | 2 => (year - 1, month + 9),
+        _ => (year, month - 3),
+    };
+    let year_days = year * 365 + year / 4 - year / 100 + year / 400;
+    let month_days = (153 * month + 2) / 5;
+    // 719468 days from 0000-03-01 to 1970-01-01
+    year_days + month_days + day - 1 - 719468
+}
+
+struct Cmos {
+    addr: Pio<u8>,
+    data: Pio<u8>,
+}
+
+impl Cmos {
+    fn new() -> Self {
+        Cmos { addr: Pio::new(0x70), data: Pio::new(0x71) }
+    }
+    fn read(&mut self, reg: u8) -> u8 {
+        self.addr.write(reg);
+        self.data.read()
+    }
+    /// Second, minute, hour (24-hour), day, month, year of the century, in binary
+    fn read_time(&mut self) -> [u64; 6] {
+        while self.read(REG_STATUS_A) & STATUS_A_UPDATING != 0 {}
+        let status_b = self.read(REG_STATUS_B);
+        let bcd = status_b & STATUS_B_BINARY == 0;
+        let decode = |x: u8| (match bcd {
+            true => (x >> 4) * 10 + (x & 0xf),
+            false => x,
+        }) as u64;
+        let hour = self.read(REG_HOUR);
+        // Bit 7 is PM in the 12-hour format, and 12 o'clock is 0
+        let hour = match status_b & STATUS_B_24HOUR == 0 {
+            true => decode(hour & 0x7f) % 12 + if hour & 0x80 != 0 { 12 } else { 0 },
+            false => decode(hour),
+        };
+        [
+            decode(self.read(REG_SECOND)),
+            decode(self.read(REG_MINUTE)),
+            hour,
+            decode(self.read(REG_DAY)),
+            decode(self.read(REG_MONTH)),
+            decode(self.read(REG_YEAR)),
+        ]
+    }
+}
+
+const REG_SECOND    : u8 = 0x00;
+const REG_MINUTE    : u8 = 0x02;
+const REG_HOUR      : u8 = 0x04;
+const REG_DAY       : u8 = 0x07;
+const REG_MONTH     : u8 = 0x08;
+const REG_YEAR      : u8 = 0x09;
+const REG_STATUS_A  : u8 = 0x0A;
+const REG_STATUS_B  : u8 = 0x0B;
+const STATUS_A_UPDATING : u8 = 0x80;                // update in progress
+const STATUS_B_24HOUR   : u8 = 0x02;                // 24-hour format
+const STATUS_B_BINARY   : u8 = 0x04;                // binary, not BCD
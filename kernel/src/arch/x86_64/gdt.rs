@@ -1,5 +1,5 @@
 use alloc::boxed::Box;
-use arch::driver::apic::lapic_id;
+use arch::cpu;
 use consts::MAX_CPU_NUM;
 use core::fmt;
 use core::fmt::Debug;
@@ -50,7 +50,9 @@ pub fn init() {
         load_tss(TSS_SELECTOR);
     }
 
-    CPUS[lapic_id() as usize].call_once(||
+    let id = cpu::id();
+    assert!(id < MAX_CPU_NUM, "APIC id {} is beyond MAX_CPU_NUM", id);
+    CPUS[id].call_once(||
         Mutex::new(Cpu { gdt, tss: unsafe { &mut *tss } }));
 }
 
@@ -67,7 +69,7 @@ pub struct Cpu {
 
 impl Cpu {
     pub fn current() -> MutexGuard<'static, Cpu> {
-        CPUS[lapic_id() as usize].try().unwrap().lock()
+        CPUS[cpu::id()].try().unwrap().lock()
     }
 
     /// 设置从Ring3跳到Ring0时，自动切换栈的地址
@@ -25,6 +25,8 @@ pub const IRQ_COM2     : u8 =  3;
 pub const IRQ_COM1     : u8 =  4;
 pub const IRQ_IDE      : u8 = 14;
 pub const IRQ_ERROR    : u8 = 19;
+pub const IRQ_TLB_SHOOTDOWN : u8 = 29;  // IPI to flush a page in the TLB
+pub const IRQ_RESCHEDULE : u8 = 30;     // IPI to wake up an idle CPU
 pub const IRQ_SPURIOUS : u8 = 31;
 pub const T_SYSCALL: u8 = 0x40;
 // xv6 x86_64 syscall
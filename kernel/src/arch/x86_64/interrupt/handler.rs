@@ -80,19 +80,31 @@ pub extern fn rust_trap(tf: &mut TrapFrame) {
         T_PGFLT => page_fault(tf),
         T_IRQ0...63 => {
             let irq = tf.trap_num as u8 - T_IRQ0;
+            #[cfg(feature = "lockdep")]
+            ::sync::lockdep::irq_enter();
+            // Any interrupt takes a TLB shootdown, the BSP may not get the IPI without `use_apic`
+            ::arch::paging::handle_shootdown();
             match irq {
                 IRQ_TIMER => ::trap::timer(),
                 IRQ_KBD => keyboard(),
                 IRQ_COM1 => com1(),
                 IRQ_COM2 => com2(),
                 IRQ_IDE => ide(),
+                // Only to wake up, it schedules before return
+                IRQ_RESCHEDULE => {}
+                IRQ_TLB_SHOOTDOWN => {}
                 _ => panic!("Invalid IRQ number: {}", irq),
             }
-            #[cfg(feature = "use_apic")]
-            use arch::driver::apic::ack;
-            #[cfg(not(feature = "use_apic"))]
-            use arch::driver::pic::ack;
-            ack(irq);
+            // APs always use their local APIC, and IPIs come from it
+            use arch::{cpu, driver::{apic, pic}};
+            let ipi = irq == IRQ_RESCHEDULE || irq == IRQ_TLB_SHOOTDOWN;
+            if cfg!(feature = "use_apic") || cpu::id() != 0 || ipi {
+                apic::ack(irq);
+            } else {
+                pic::ack(irq);
+            }
+            #[cfg(feature = "lockdep")]
+            ::sync::lockdep::irq_exit();
         }
         T_SWITCH_TOK => to_kernel(tf),
         T_SWITCH_TOU => to_user(tf),
@@ -101,7 +113,7 @@ pub extern fn rust_trap(tf: &mut TrapFrame) {
         T_DIVIDE | T_GPFLT | T_ILLOP => error(tf),
         _ => panic!("Unhandled interrupt {:x}", tf.trap_num),
     }
-    ::trap::before_return();
+    ::trap::before_return(tf);
 }
 
 fn breakpoint() {
@@ -116,7 +128,7 @@ fn double_fault(tf: &TrapFrame) {
 fn page_fault(tf: &mut TrapFrame) {
     let addr: usize;
     unsafe { asm!("mov %cr2, $0" : "=r" (addr)); }
-    error!("\nEXCEPTION: Page Fault @ {:#x}, code: {:#x}", addr, tf.error_code);
+    trace!("\nEXCEPTION: Page Fault @ {:#x}, code: {:#x}", addr, tf.error_code);
 
     use memory::page_fault_handler;
     if page_fault_handler(addr) {
@@ -178,7 +190,13 @@ fn syscall32(tf: &mut TrapFrame) {
 }
 
 fn error(tf: &TrapFrame) {
-    ::trap::error(tf);
+    use process::signal::*;
+    let signal = match tf.trap_num as u8 {
+        T_DIVIDE => SIGFPE,
+        T_ILLOP => SIGILL,
+        _ => SIGSEGV,
+    };
+    ::trap::error(tf, signal);
 }
 
 #[no_mangle]
@@ -30,6 +30,20 @@ pub unsafe fn restore(flags: usize) {
     asm!("pushq $0; popfq" :: "r"(flags) : "memory" "flags");
 }
 
+/// Are interrupts enabled on this CPU ?
+#[inline(always)]
+pub fn enabled() -> bool {
+    let r: usize;
+    unsafe { asm!("pushfq; popq $0" : "=r"(r) :: "memory") };
+    r & 0x200 != 0
+}
+
+/// Enable interrupts and halt until one comes, without missing one in between
+#[inline(always)]
+pub unsafe fn enable_and_wait() {
+    asm!("sti; hlt" :::: "volatile");
+}
+
 #[inline(always)]
 pub fn no_interrupt(f: impl FnOnce()) {
     let flags = unsafe { disable_and_store() };
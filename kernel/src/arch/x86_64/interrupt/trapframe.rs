@@ -46,9 +46,11 @@ impl TrapFrame {
         tf.rflags = 0x282;
         tf
     }
-    fn new_user_thread(entry_addr: usize, rsp: usize, is32: bool) -> Self {
+    pub fn new_user_thread(entry_addr: usize, rsp: usize, is32: bool, argc: usize, argv: usize) -> Self {
         use arch::gdt;
         let mut tf = TrapFrame::default();
+        tf.rdi = argc;
+        tf.rsi = argv;
         tf.cs = if is32 { gdt::UCODE32_SELECTOR.0 } else { gdt::UCODE_SELECTOR.0 } as usize;
         tf.rip = entry_addr;
         tf.ss = if is32 { gdt::UDATA32_SELECTOR.0 } else { gdt::UDATA_SELECTOR.0 } as usize;
@@ -59,11 +61,42 @@ impl TrapFrame {
     pub fn is_user(&self) -> bool {
         self.cs & 0x3 == 0x3
     }
+    /// Whether it is from a 32-bit (compatibility mode) user program
+    pub fn is_32bit(&self) -> bool {
+        use arch::gdt;
+        self.cs == gdt::UCODE32_SELECTOR.0 as usize
+    }
+    pub fn get_sp(&self) -> usize {
+        self.rsp
+    }
+    /// Value of the return value register
+    pub fn get_ret(&self) -> usize {
+        self.rax
+    }
+    /// Return to the signal handler `entry(sig)` with stack `sp`.
+    /// The return address is at `sp` already.
+    pub fn set_signal_handler(&mut self, entry: usize, sig: usize, sp: usize, _restorer: usize) {
+        self.rip = entry;
+        self.rdi = sig;
+        self.rsp = sp;
+    }
+    /// Restore the registers saved in user memory, except the privileged ones
+    pub fn restore_user(&mut self, saved: &TrapFrame) {
+        // CF PF AF ZF SF TF DF OF
+        const USER_FLAGS: usize = 0xdd5;
+        let (cs, ss, rflags) = (self.cs, self.ss, self.rflags);
+        *self = saved.clone();
+        self.cs = cs;
+        self.ss = ss;
+        self.rflags = (saved.rflags & USER_FLAGS) | (rflags & !USER_FLAGS);
+    }
 }
 
 #[derive(Debug, Default)]
 #[repr(C)]
 struct ContextData {
+    /// Base of `fs`, the thread pointer of the user
+    fsbase: usize,
     cr3: usize,
     r15: usize,
     r14: usize,
@@ -75,11 +108,20 @@ struct ContextData {
 }
 
 impl ContextData {
-    fn new(cr3: usize) -> Self {
-        ContextData { rip: forkret as usize, cr3, ..ContextData::default() }
+    fn new(cr3: usize, fsbase: usize) -> Self {
+        ContextData { rip: forkret as usize, cr3, fsbase, ..ContextData::default() }
     }
 }
 
+const MSR_FS_BASE: u32 = 0xC000_0100;
+
+/// Base of `fs` of the current thread
+unsafe fn read_fsbase() -> usize {
+    let (low, high): (u32, u32);
+    asm!("rdmsr" : "={eax}" (low), "={edx}" (high) : "{ecx}" (MSR_FS_BASE) : : "volatile");
+    (high as usize) << 32 | low as usize
+}
+
 /// 新线程的内核栈初始内容
 #[derive(Debug)]
 #[repr(C)]
@@ -115,9 +157,9 @@ impl Context {
     ///
     /// Defined in `trap.asm`.
     ///
-    /// Push all callee-saved registers at the current kernel stack.
+    /// Push all callee-saved registers and the base of `fs` at the current kernel stack.
     /// Store current rsp, switch to target.
-    /// Pop all callee-saved registers, then return to the target.
+    /// Pop all callee-saved registers and the base of `fs`, then return to the target.
     #[naked]
     #[inline(never)]
     pub unsafe extern fn switch(&mut self, target: &mut Self) {
@@ -134,12 +176,22 @@ impl Context {
         push r15
         mov r15, cr3
         push r15
+        mov ecx, 0xC0000100 // MSR_FS_BASE
+        rdmsr
+        shl rdx, 32
+        or rax, rdx
+        push rax
 
         // Switch stacks
         mov [rdi], rsp      // rdi = from_rsp
         mov rsp, [rsi]      // rsi = to_rsp
 
         // Save old callee-save registers
+        pop rax
+        mov rdx, rax
+        shr rdx, 32
+        mov ecx, 0xC0000100 // MSR_FS_BASE
+        wrmsr
         pop r15
         mov cr3, r15
         pop r15
@@ -160,25 +212,38 @@ impl Context {
 
     pub unsafe fn new_kernel_thread(entry: extern fn(usize) -> !, arg: usize, kstack_top: usize, cr3: usize) -> Self {
         InitStack {
-            context: ContextData::new(cr3),
+            context: ContextData::new(cr3, 0),
             trapret: trap_ret as usize,
             tf: TrapFrame::new_kernel_thread(entry, arg, kstack_top),
         }.push_at(kstack_top)
     }
-    pub unsafe fn new_user_thread(entry_addr: usize, ustack_top: usize, kstack_top: usize, is32: bool, cr3: usize) -> Self {
+    pub unsafe fn new_user_thread(entry_addr: usize, ustack_top: usize, kstack_top: usize, is32: bool, cr3: usize, argc: usize, argv: usize) -> Self {
         InitStack {
-            context: ContextData::new(cr3),
+            context: ContextData::new(cr3, 0),
             trapret: trap_ret as usize,
-            tf: TrapFrame::new_user_thread(entry_addr, ustack_top, is32),
+            tf: TrapFrame::new_user_thread(entry_addr, ustack_top, is32, argc, argv),
         }.push_at(kstack_top)
     }
     pub unsafe fn new_fork(tf: &TrapFrame, kstack_top: usize, cr3: usize) -> Self {
         InitStack {
-            context: ContextData::new(cr3),
+            context: ContextData::new(cr3, read_fsbase()),
+            trapret: trap_ret as usize,
+            tf: {
+                let mut tf = tf.clone();
+                tf.rax = 0;
+                tf
+            },
+        }.push_at(kstack_top)
+    }
+    /// A thread returning to the user by `tf` with stack `ustack_top` and thread pointer `tls`
+    pub unsafe fn new_clone(tf: &TrapFrame, ustack_top: usize, tls: usize, kstack_top: usize, cr3: usize) -> Self {
+        InitStack {
+            context: ContextData::new(cr3, tls),
             trapret: trap_ret as usize,
             tf: {
                 let mut tf = tf.clone();
                 tf.rax = 0;
+                tf.rsp = ustack_top;
                 tf
             },
         }.push_at(kstack_top)
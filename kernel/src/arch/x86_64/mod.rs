@@ -12,6 +12,8 @@ pub mod idt;
 //pub mod smp;
 pub mod memory;
 pub mod io;
+pub mod swap;
+pub mod timer;
 
 /// The entry point of kernel
 #[no_mangle] // don't mangle the name of this function
@@ -29,21 +31,30 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
 
     // Now heap is available
     gdt::init();
+    paging::enable_shootdown();
 
     driver::init();
 
     ::kmain();
 }
 
-/// The entry point for another processors
+/// The entry point for another processors, started by the bootloader on its own boot stack.
+/// Each of them loads its own GDT/TSS, starts its LAPIC timer, then joins scheduling.
 #[no_mangle]
 pub extern "C" fn other_main() -> ! {
+    // Per-CPU states are indexed by the APIC id, leave out the CPUs beyond them
+    if cpu::id() >= ::consts::MAX_CPU_NUM {
+        warn!("CPU {} is beyond MAX_CPU_NUM, halt", cpu::id());
+        loop {
+            unsafe { asm!("cli; hlt" :::: "volatile"); }
+        }
+    }
     idt::init();
     gdt::init();
     driver::apic::other_init();
-    let cpu_id = driver::apic::lapic_id();
-//    let ms = unsafe { smp::notify_started(cpu_id) };
-    println!("Hello world! from CPU {}!", cpu_id);
-//    unsafe{ let a = *(0xdeadbeaf as *const u8); } // Page fault
-    loop {}
+    paging::enable_shootdown();
+    info!("Hello world! from CPU {}!", cpu::id());
+    ::process::init_other();
+    // Become the idle process of this CPU
+    ::process::idle::run()
 }
\ No newline at end of file
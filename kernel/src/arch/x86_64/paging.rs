@@ -1,13 +1,15 @@
+use arch::cpu;
 use bit_allocator::{BitAlloc, BitAlloc64K};
+use consts::MAX_CPU_NUM;
+use core::sync::atomic::{spin_loop_hint, ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
 // Depends on kernel
-use memory::{active_table, alloc_frame, alloc_stack, dealloc_frame};
+use memory::{active_table, alloc_frame, alloc_stack, dealloc_frame, ActiveTable};
 use spin::{Mutex, MutexGuard};
-use ucore_memory::cow::CowExt;
 use ucore_memory::memory_set::*;
 use ucore_memory::PAGE_SIZE;
 use ucore_memory::paging::*;
 use x86_64::instructions::tlb;
-use x86_64::PhysAddr;
+use x86_64::{PhysAddr, VirtAddr};
 use x86_64::registers::control::{Cr3, Cr3Flags};
 use x86_64::structures::paging::{Mapper, PageTable as x86PageTable, PageTableEntry, PageTableFlags as EF, RecursivePageTable};
 use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Page, PageRange, PhysFrame as Frame, Size4KiB};
@@ -54,7 +56,8 @@ impl PageTable for ActivePageTable {
 
     fn unmap(&mut self, addr: usize) {
         let (frame, flush) = self.0.unmap(Page::of_addr(addr)).unwrap();
-        flush.flush();
+        flush.ignore();
+        shootdown(addr);
     }
 
     fn get_entry(&mut self, addr: usize) -> &mut PageEntry {
@@ -81,24 +84,30 @@ impl ActivePageTable {
         ActivePageTable(RecursivePageTable::new(&mut *(0xffffffff_fffff000 as *mut _)).unwrap())
     }
     fn with_temporary_map(&mut self, frame: &Frame, f: impl FnOnce(&mut ActivePageTable, &mut x86PageTable)) {
+        let table = unsafe { self.map_temporary(frame) };
+        f(self, table);
+        self.unmap_temporary();
+    }
+    /// Map `frame` to a temporary page, return it as a page table.
+    /// The page is valid until `unmap_temporary`.
+    unsafe fn map_temporary(&mut self, frame: &Frame) -> &'static mut x86PageTable {
         // Create a temporary page
         let page = Page::of_addr(0xcafebabe);
         assert!(self.0.translate_page(page).is_none(), "temporary page is already mapped");
         // Map it to table
         self.map(page.start_address().as_u64() as usize, frame.start_address().as_u64() as usize);
-        // Call f
-        let table = unsafe { &mut *page.start_address().as_mut_ptr() };
-        f(self, table);
-        // Unmap the page
+        &mut *page.start_address().as_mut_ptr()
+    }
+    fn unmap_temporary(&mut self) {
         self.unmap(0xcafebabe);
     }
 }
 
 impl Entry for PageEntry {
     fn update(&mut self) {
-        use x86_64::{VirtAddr, instructions::tlb::flush};
-        let addr = VirtAddr::new_unchecked((self as *const _ as u64) << 9);
-        flush(addr);
+        // Sign extended from bit 47, `invlpg` does nothing to a non-canonical address
+        let addr = (((self as *const _ as usize) << 9 << 16) as isize >> 16) as usize;
+        shootdown(addr);
     }
     fn accessed(&self) -> bool { self.0.flags().contains(EF::ACCESSED) }
     fn dirty(&self) -> bool { self.0.flags().contains(EF::DIRTY) }
@@ -152,7 +161,7 @@ pub struct InactivePageTable0 {
 }
 
 impl InactivePageTable for InactivePageTable0 {
-    type Active = ActivePageTable;
+    type Active = ActiveTable;
 
     fn new() -> Self {
         let mut pt = Self::new_bare();
@@ -171,21 +180,12 @@ impl InactivePageTable for InactivePageTable0 {
         InactivePageTable0 { p4_frame: frame }
     }
 
+    /// Load the table on this CPU to edit it, the kernel must have been mapped.
+    /// The recursive entry of the current table is left alone, other CPUs may have loaded it.
     fn edit(&mut self, f: impl FnOnce(&mut Self::Active)) {
-        active_table().with_temporary_map(&Cr3::read().0, |active_table, p4_table: &mut x86PageTable| {
-            let backup = p4_table[0o777].clone();
-
-            // overwrite recursive mapping
-            p4_table[0o777].set_frame(self.p4_frame.clone(), EF::PRESENT | EF::WRITABLE);
-            tlb::flush_all();
-
-            // execute f in the new context
-            f(active_table);
-
-            // restore recursive mapping to original p4 table
-            p4_table[0o777] = backup;
-            tlb::flush_all();
-        });
+        // Interrupts are disabled holding the active table, so this CPU keeps the table
+        let mut active_table = active_table();
+        unsafe { self.with(|| f(&mut active_table)); }
     }
 
     unsafe fn activate(&self) {
@@ -230,18 +230,64 @@ impl InactivePageTable for InactivePageTable0 {
 
 impl InactivePageTable0 {
     fn map_kernel(&mut self) {
-        let mut table = unsafe { &mut *(0xffffffff_fffff000 as *mut x86PageTable) };
+        let table = unsafe { &mut *(0xffffffff_fffff000 as *mut x86PageTable) };
         // Kernel at 0xffff_ff00_0000_0000
         // Kernel stack at 0x0000_57ac_0000_0000 (defined in bootloader crate)
         let e510 = table[510].clone();
         let estack = table[175].clone();
-        self.edit(|_| {
+        active_table().with_temporary_map(&self.p4_frame, |_, table: &mut x86PageTable| {
             table[510].set_addr(e510.addr(), e510.flags() | EF::GLOBAL);
             table[175].set_addr(estack.addr(), estack.flags() | EF::GLOBAL);
         });
     }
 }
 
+/// CPUs taking part in TLB shootdowns, a bit for each APIC id
+static ONLINE: AtomicUsize = ATOMIC_USIZE_INIT;
+/// CPUs which have not flushed the page of the current shootdown
+static PENDING: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The page of the current shootdown
+static SHOOTDOWN_ADDR: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Let this CPU take TLB shootdowns, before it accesses any page which may be unmapped
+pub fn enable_shootdown() {
+    ONLINE.fetch_or(1 << cpu::id(), Ordering::SeqCst);
+}
+
+/// Flush the page of `addr` in the TLB of every CPU, after its entry is changed.
+/// The active table must be held, so there is one shootdown at a time.
+fn shootdown(addr: usize) {
+    tlb::flush(VirtAddr::new(addr as u64));
+    let others = ONLINE.load(Ordering::SeqCst) & !(1 << cpu::id());
+    if others == 0 {
+        return;
+    }
+    SHOOTDOWN_ADDR.store(addr, Ordering::SeqCst);
+    PENDING.store(others, Ordering::SeqCst);
+    for id in (0..MAX_CPU_NUM).filter(|id| others & (1 << id) != 0) {
+        cpu::send_tlb_shootdown(id);
+    }
+    // Other CPUs flush it in any interrupt, or while spinning for a lock with interrupts disabled
+    while PENDING.load(Ordering::SeqCst) != 0 {
+        spin_loop_hint();
+    }
+}
+
+/// Flush the page of the current shootdown if this CPU has not
+pub fn handle_shootdown() {
+    let bit = 1 << cpu::id();
+    if PENDING.load(Ordering::SeqCst) & bit != 0 {
+        tlb::flush(VirtAddr::new(SHOOTDOWN_ADDR.load(Ordering::SeqCst) as u64));
+        PENDING.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+/// Fill the frame at `target` with zero, e.g. a new frame of shared memory
+pub fn zero_frame(target: usize) {
+    let frame = Frame::of_addr(target);
+    active_table().with_temporary_map(&frame, |_, table: &mut x86PageTable| table.zero());
+}
+
 impl Drop for InactivePageTable0 {
     fn drop(&mut self) {
         info!("PageTable dropping: {:?}", self);
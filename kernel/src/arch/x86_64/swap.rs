@@ -0,0 +1,65 @@
+//! Swap space on the boot disk
+//!
+//! The bootimage is padded to `minimum-image-size` in `Cargo.toml`,
+//! the tail of it is used as swap space.
+
+use bit_allocator::{BitAlloc, BitAlloc4K};
+use core::slice;
+use super::driver::ide::{self, DISK0};
+use ucore_memory::PAGE_SIZE;
+use ucore_memory::swap::Swapper;
+
+/// The first sector of swap space (at 16MiB)
+const SWAP_START_SECTOR: usize = 0x1000000 / ide::BLOCK_SIZE;
+/// Number of pages in swap space (16MiB)
+const SWAP_PAGE_COUNT: usize = 0x1000;
+const SECTORS_PER_PAGE: usize = PAGE_SIZE / ide::BLOCK_SIZE;
+
+/// Swapper writing pages to `DISK0`, the token is the page index in swap space
+pub struct IdeSwapper {
+    allocator: BitAlloc4K,
+}
+
+impl IdeSwapper {
+    pub fn new() -> Self {
+        let mut allocator = BitAlloc4K::default();
+        allocator.insert(0..SWAP_PAGE_COUNT);
+        IdeSwapper { allocator }
+    }
+    fn sector(token: usize) -> u64 {
+        (SWAP_START_SECTOR + token * SECTORS_PER_PAGE) as u64
+    }
+}
+
+impl Swapper for IdeSwapper {
+    fn swap_out(&mut self, data: &[u8]) -> Result<usize, ()> {
+        let token = self.allocator.alloc().ok_or(())?;
+        if self.swap_update(token, data).is_err() {
+            self.allocator.dealloc(token);
+            return Err(());
+        }
+        Ok(token)
+    }
+
+    fn swap_update(&mut self, token: usize, data: &[u8]) -> Result<(), ()> {
+        assert_eq!(data.len(), PAGE_SIZE);
+        let buf = unsafe { slice::from_raw_parts(data.as_ptr() as *const u32, PAGE_SIZE / 4) };
+        DISK0.0.lock().write(Self::sector(token), SECTORS_PER_PAGE, buf)?;
+        Ok(())
+    }
+
+    fn swap_in(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()> {
+        assert_eq!(data.len(), PAGE_SIZE);
+        let buf = unsafe { slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u32, PAGE_SIZE / 4) };
+        DISK0.0.lock().read(Self::sector(token), SECTORS_PER_PAGE, buf)?;
+        self.swap_drop(token)
+    }
+
+    fn swap_drop(&mut self, token: usize) -> Result<(), ()> {
+        if token >= SWAP_PAGE_COUNT || self.allocator.test(token) {
+            return Err(());
+        }
+        self.allocator.dealloc(token);
+        Ok(())
+    }
+}
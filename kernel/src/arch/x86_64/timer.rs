@@ -0,0 +1,47 @@
+//! TSC as the clock source, calibrated against the PIT.
+//! The timer interrupt is the PIT on the BSP without `use_apic`, otherwise the local APIC.
+
+use super::cpu;
+use super::driver::{apic, pit, rtc};
+use time::ClockSource;
+
+pub fn get_cycle() -> u64 {
+    let (hi, lo): (u32, u32);
+    unsafe { asm!("rdtsc" : "={edx}"(hi), "={eax}"(lo) ::: "volatile"); }
+    (hi as u64) << 32 | lo as u64
+}
+
+/// The TSC, assumed invariant and synchronized between CPUs
+pub fn clock_source() -> ClockSource {
+    const CALIBRATE_MS: u64 = 50;
+    let begin = get_cycle();
+    pit::wait_ms(CALIBRATE_MS as u32);
+    let freq = (get_cycle() - begin) * 1000 / CALIBRATE_MS;
+    ClockSource { name: "tsc", read: get_cycle, freq }
+}
+
+/// Seconds since the Unix epoch, from the RTC
+pub fn read_epoch() -> u64 {
+    rtc::read_epoch()
+}
+
+fn use_apic() -> bool {
+    cfg!(feature = "use_apic") || cpu::id() != 0
+}
+
+/// Interrupt once after `ticks`, instead of every tick.
+/// The PIT counts 16 bits, so it wakes up after 5 ticks at most.
+pub fn set_oneshot(ticks: usize) {
+    match use_apic() {
+        true => apic::set_oneshot(ticks),
+        false => pit::set_oneshot(ticks.min(5) as u32 * 10),
+    }
+}
+
+/// Back to interrupt every tick
+pub fn set_periodic() {
+    match use_apic() {
+        true => apic::set_periodic(),
+        false => pit::set_periodic(),
+    }
+}
@@ -1,6 +1,9 @@
 use core::ops::Deref;
 use alloc::string::String;
 use arch::io::getchar;
+use process::{processor, Pid};
+use process::signal::{self, SIGINT, SIGQUIT, SIGTSTP, SIGTTIN};
+use sync::{LockClass, SpinNoIrqLock};
 
 pub fn get_line() -> String {
     let mut s = String::new();
@@ -23,4 +26,71 @@ pub fn get_line() -> String {
             _ => {}
         }
     }
-}
\ No newline at end of file
+}
+
+static FOREGROUND_CLASS: LockClass = LockClass::new("FOREGROUND");
+
+lazy_static! {
+    /// The foreground process group of the console, the only terminal.
+    /// `None` if no one controls the jobs, then every process reads it.
+    static ref FOREGROUND: SpinNoIrqLock<Option<Pid>> = SpinNoIrqLock::new_with_class(None, &FOREGROUND_CLASS);
+}
+
+pub fn foreground() -> Option<Pid> {
+    *FOREGROUND.lock()
+}
+
+pub fn set_foreground(pgid: Option<Pid>) {
+    info!("foreground process group: {:?}", pgid);
+    *FOREGROUND.lock() = pgid;
+}
+
+/// Read the console for the current process, block until at least one char arrives,
+/// and stop at the end of a line.
+///
+/// A process out of the foreground group gets `SIGTTIN` instead.
+/// Ctrl-C, Ctrl-\ and Ctrl-Z send `SIGINT`, `SIGQUIT` and `SIGTSTP` to the foreground group.
+/// Input is polled, so they work only when the foreground group reads.
+/// Return 0 if interrupted by a signal before anything is read.
+pub fn read(buf: &mut [u8]) -> usize {
+    let foreground = foreground();
+    if let Some(fg) = foreground {
+        let pgid = {
+            let processor = processor();
+            processor.getpgid(processor.current_pid()).unwrap()
+        };
+        if pgid != fg {
+            signal::send_pgroup(pgid, SIGTTIN);
+            return 0;
+        }
+    }
+    let mut len = 0;
+    while len < buf.len() {
+        let c = match getchar() {
+            '\r' => '\n',
+            c => c,
+        };
+        let sig = match c {
+            '\x03' => Some(SIGINT),
+            '\x1c' => Some(SIGQUIT),
+            '\x1a' => Some(SIGTSTP),
+            _ => None,
+        };
+        match (foreground, sig) {
+            (Some(fg), Some(sig)) => {
+                signal::send_pgroup(fg, sig);
+                if signal::interrupted() {
+                    break;
+                }
+            }
+            _ => {
+                buf[len] = c as u8;
+                len += 1;
+                if c == '\n' {
+                    break;
+                }
+            }
+        }
+    }
+    len
+}
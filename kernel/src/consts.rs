@@ -23,7 +23,12 @@ mod riscv {
     pub const MEMORY_END: usize = 0x8080_0000;
     pub const USER_STACK_OFFSET: usize = 0x70000000;
     pub const USER_STACK_SIZE: usize = 0x10000;
+    /// Max size of user stack, pages below `USER_STACK_SIZE` are mapped on fault
+    pub const USER_STACK_MAX_SIZE: usize = 0x100000;
     pub const USER32_STACK_OFFSET: usize = USER_STACK_OFFSET;
+    pub const USER_MMAP_OFFSET: usize = 0x40000000;
+    /// End of user space, the kernel is above it
+    pub const USER_TOP: usize = 0x80000000;
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -87,6 +92,14 @@ mod x86_64 {
     pub const USER_STACK_PML4: usize = (USER_STACK_OFFSET & PML4_MASK) / PML4_SIZE;
     /// Size of user stack
     pub const USER_STACK_SIZE: usize = 1024 * 1024; // 1 MB
+    /// Max size of user stack, pages below `USER_STACK_SIZE` are mapped on fault
+    pub const USER_STACK_MAX_SIZE: usize = 8 * 1024 * 1024; // 8 MB
+
+    /// Offset to search free space for `sys_mmap`, below 4GB for 32-bit programs
+    pub const USER_MMAP_OFFSET: usize = 0x4000_0000;
+
+    /// End of user space, the lower 256 PML4 entries
+    pub const USER_TOP: usize = USER_OFFSET + 256 * PML4_SIZE;
 
     /// Offset to user sigstack
     pub const USER_SIGSTACK_OFFSET: usize = USER_STACK_OFFSET + PML4_SIZE;
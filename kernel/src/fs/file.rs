@@ -0,0 +1,244 @@
+//! Files opened by processes
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use console;
+use simple_filesystem::FileType;
+use sync::SpinNoIrqLock;
+use super::{INodeRef, ROOT_INODE};
+use super::pipe::{PipeReader, PipeWriter};
+
+// Flags of `sys_open`, the same as ucore
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_ACCMODE: usize = 3;
+pub const O_CREAT: usize = 0x4;
+pub const O_EXCL: usize = 0x8;
+pub const O_TRUNC: usize = 0x10;
+pub const O_APPEND: usize = 0x20;
+
+// `whence` of `sys_seek`
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// Something a file descriptor refers to
+#[derive(Clone)]
+pub enum FileLike {
+    Stdin,
+    Stdout,
+    /// Shared by `fork` and `dup`, together with its offset
+    File(Arc<SpinNoIrqLock<File>>),
+    /// Closed when the last fd of it is closed
+    PipeReader(Arc<PipeReader>),
+    PipeWriter(Arc<PipeWriter>),
+}
+
+impl FileLike {
+    pub fn read(&self, buf: &mut [u8]) -> Option<usize> {
+        match self {
+            FileLike::Stdin => Some(console::read(buf)),
+            FileLike::Stdout => None,
+            FileLike::File(file) => file.lock().read(buf),
+            FileLike::PipeReader(pipe) => Some(pipe.read(buf)),
+            FileLike::PipeWriter(_) => None,
+        }
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Option<usize> {
+        use core::str;
+        match self {
+            FileLike::Stdin => None,
+            FileLike::Stdout => {
+                // A character cut at the end is left to the next write
+                let len = match str::from_utf8(buf) {
+                    Ok(_) => buf.len(),
+                    Err(e) if e.error_len().is_none() && e.valid_up_to() > 0 => e.valid_up_to(),
+                    Err(_) => return None,
+                };
+                print!("{}", str::from_utf8(&buf[..len]).unwrap());
+                Some(len)
+            }
+            FileLike::File(file) => file.lock().write(buf),
+            FileLike::PipeReader(_) => None,
+            FileLike::PipeWriter(pipe) => pipe.write(buf),
+        }
+    }
+}
+
+/// An opened SFS file with its own offset
+pub struct File {
+    inode: INodeRef,
+    offset: usize,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+/// `struct stat` of ucore
+#[repr(C)]
+pub struct Stat {
+    mode: u32,
+    nlinks: u32,
+    blocks: u32,
+    size: u32,
+}
+
+const S_IFREG: u32 = 0o010000;
+const S_IFDIR: u32 = 0o020000;
+
+/// Size of a block in `Stat`
+const BLOCK_SIZE: usize = 4096;
+
+/// Bytes read or written holding SFS at a time
+const CHUNK_SIZE: usize = 512;
+
+impl File {
+    /// Open the file at `path` of the mounted SFS
+    pub fn open(path: &str, flags: usize) -> Option<Self> {
+        let path = path.trim_left_matches('/');
+        let (readable, writable) = match flags & O_ACCMODE {
+            O_RDONLY => (true, false),
+            O_WRONLY => (false, true),
+            O_RDWR => (true, true),
+            _ => return None,
+        };
+        let inode = {
+            let root = ROOT_INODE.lock();
+            let found = root.borrow().lookup(path);
+            let inode = match found {
+                Ok(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => None,
+                Ok(inode) => Some(inode),
+                Err(_) if flags & O_CREAT != 0 => root.borrow_mut().create(path, FileType::File).ok(),
+                Err(_) => None,
+            };
+            // Fail to open if it can not be truncated
+            match inode {
+                Some(inode) => {
+                    let truncate = flags & O_TRUNC != 0 && writable;
+                    if truncate && inode.borrow_mut().resize(0).is_err() {
+                        None
+                    } else {
+                        Some(INodeRef::new(inode))
+                    }
+                }
+                None => None,
+            }
+        };
+        Some(File {
+            inode: inode?,
+            offset: 0,
+            readable,
+            writable,
+            append: flags & O_APPEND != 0,
+        })
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if !self.readable {
+            return None;
+        }
+        // Through a kernel buffer, `buf` may fault and must not be touched holding SFS
+        let mut data = [0u8; CHUNK_SIZE];
+        let mut read = 0;
+        while read < buf.len() {
+            let chunk = (buf.len() - read).min(CHUNK_SIZE);
+            let len = self.inode.lock().borrow().read_at(self.offset, &mut data[..chunk]);
+            let len = match len {
+                Ok(len) => len,
+                Err(_) if read > 0 => break,
+                Err(_) => return None,
+            };
+            buf[read..read + len].copy_from_slice(&data[..len]);
+            read += len;
+            self.offset += len;
+            if len < chunk {
+                break;
+            }
+        }
+        Some(read)
+    }
+
+    /// Read the whole file from the beginning
+    pub fn read_all(&mut self) -> Option<Vec<u8>> {
+        if !self.readable {
+            return None;
+        }
+        let inode = self.inode.lock();
+        let size = inode.borrow().info().ok()?.size;
+        let mut buf = vec![0u8; size];
+        let len = inode.borrow().read_at(0, &mut buf).ok()?;
+        buf.truncate(len);
+        Some(buf)
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Option<usize> {
+        if !self.writable {
+            return None;
+        }
+        if self.append {
+            self.offset = self.inode.lock().borrow().info().ok()?.size;
+        }
+        // Through a kernel buffer, the same as `read`
+        let mut data = [0u8; CHUNK_SIZE];
+        let mut written = 0;
+        while written < buf.len() {
+            let chunk = (buf.len() - written).min(CHUNK_SIZE);
+            data[..chunk].copy_from_slice(&buf[written..written + chunk]);
+            let len = match self.inode.lock().borrow().write_at(self.offset, &data[..chunk]) {
+                Ok(len) => len,
+                Err(_) if written > 0 => break,
+                Err(_) => return None,
+            };
+            written += len;
+            self.offset += len;
+            if len < chunk {
+                break;
+            }
+        }
+        Some(written)
+    }
+
+    /// Set the offset, return the new one
+    pub fn seek(&mut self, pos: isize, whence: usize) -> Option<usize> {
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => self.offset as isize,
+            SEEK_END => self.inode.lock().borrow().info().ok()?.size as isize,
+            _ => return None,
+        };
+        let offset = base.checked_add(pos)?;
+        if offset < 0 {
+            return None;
+        }
+        self.offset = offset as usize;
+        Some(self.offset)
+    }
+
+    pub fn stat(&self) -> Option<Stat> {
+        let info = self.inode.lock().borrow().info().ok()?;
+        Some(Stat {
+            mode: match info.type_ {
+                FileType::File => S_IFREG,
+                FileType::Dir => S_IFDIR,
+            },
+            nlinks: 1,
+            blocks: ((info.size + BLOCK_SIZE - 1) / BLOCK_SIZE) as u32,
+            size: info.size as u32,
+        })
+    }
+
+    /// The inode if the file is readable, used by `sys_mmap`
+    pub fn inode(&self) -> Option<INodeRef> {
+        if self.readable {
+            Some(self.inode.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Get the name of the `id`th entry if this is a directory
+    pub fn get_entry(&self, id: usize) -> Option<String> {
+        self.inode.lock().borrow().list().ok()?.into_iter().nth(id)
+    }
+}
@@ -0,0 +1,242 @@
+use simple_filesystem::*;
+use alloc::{boxed::Box, vec::Vec};
+#[cfg(target_arch = "x86_64")]
+use arch::driver::ide;
+use core::mem::ManuallyDrop;
+use core::ops::Deref;
+use console;
+use process::{processor, signal, Context, Pid, WaitOptions, WaitResult};
+use sync::{LockClass, MutexGuard, SpinNoIrq, SpinNoIrqLock};
+
+pub use self::file::*;
+pub use self::pipe::pipe;
+
+mod file;
+mod pipe;
+
+// Hard link user program
+#[cfg(target_arch = "riscv32")]
+global_asm!(r#"
+    .section .rodata
+    .align 12
+_binary_user_riscv_img_start:
+    .incbin "../user/user-riscv.img"
+_binary_user_riscv_img_end:
+"#);
+
+lazy_static! {
+    /// Root INode of the mounted SFS, shared by the kernel shell and file syscalls
+    pub static ref ROOT_INODE: INodeRef = {
+        #[cfg(target_arch = "riscv32")]
+        let device = {
+            extern {
+                fn _binary_user_riscv_img_start();
+                fn _binary_user_riscv_img_end();
+            }
+            Box::new(unsafe { MemBuf::new(_binary_user_riscv_img_start, _binary_user_riscv_img_end) })
+        };
+        #[cfg(target_arch = "x86_64")]
+        let device = Box::new(&ide::DISK1);
+        let _sfs = SFS.lock();
+        let sfs = SimpleFileSystem::open(device).expect("failed to open SFS");
+        INodeRef::new(sfs.root_inode())
+    };
+    /// Serializes every access to SFS, which is not thread safe
+    static ref SFS: SpinNoIrqLock<()> = SpinNoIrqLock::new_with_class((), &SFS_CLASS);
+}
+
+static SFS_CLASS: LockClass = LockClass::new("SFS");
+
+/// An inode shared between processes.
+///
+/// `INodePtr` is `Rc<RefCell<..>>` and SFS is not thread safe, so it is only
+/// accessed holding `SFS`, including cloning and dropping it.
+/// Do not touch user memory holding `SFS`, a page fault of an mmaped file takes it again.
+pub struct INodeRef(ManuallyDrop<INodePtr>);
+
+unsafe impl Send for INodeRef {}
+unsafe impl Sync for INodeRef {}
+
+impl INodeRef {
+    /// Wrap an inode got holding `SFS`
+    pub fn new(inode: INodePtr) -> Self {
+        INodeRef(ManuallyDrop::new(inode))
+    }
+
+    /// Lock SFS to access the inode
+    pub fn lock(&self) -> INodeGuard {
+        INodeGuard { inode: &self.0, _sfs: SFS.lock() }
+    }
+}
+
+impl Clone for INodeRef {
+    fn clone(&self) -> Self {
+        let _sfs = SFS.lock();
+        INodeRef::new((*self.0).clone())
+    }
+}
+
+impl Drop for INodeRef {
+    fn drop(&mut self) {
+        let _sfs = SFS.lock();
+        unsafe { ManuallyDrop::drop(&mut self.0); }
+    }
+}
+
+/// Access to an inode holding `SFS`.
+/// Inodes got from it must be dropped or wrapped by `INodeRef::new` before it.
+pub struct INodeGuard<'a> {
+    inode: &'a INodePtr,
+    _sfs: MutexGuard<'static, (), SpinNoIrq>,
+}
+
+impl<'a> Deref for INodeGuard<'a> {
+    type Target = INodePtr;
+    fn deref(&self) -> &INodePtr {
+        self.inode
+    }
+}
+
+pub fn shell() {
+    let files = ROOT_INODE.lock().borrow().list().unwrap();
+    println!("Available programs: {:?}", files);
+
+    // Avoid stack overflow in release mode
+    // Equal to: `buf = Box::new([0; 64 << 12])`
+    use alloc::alloc::{alloc, dealloc, Layout};
+    const BUF_SIZE: usize = 0x40000;
+    let layout = Layout::from_size_align(BUF_SIZE, 0x1000).unwrap();
+    let buf = unsafe{ slice::from_raw_parts_mut(alloc(layout), BUF_SIZE) };
+    // Jobs in the background or stopped, each in its own process group
+    let mut jobs: Vec<Pid> = Vec::new();
+    loop {
+        reap_jobs(&mut jobs);
+        print!(">> ");
+        use console::get_line;
+        let line = get_line();
+        let mut args: Vec<&str> = line.split(' ').filter(|s| !s.is_empty()).collect();
+        let background = args.last() == Some(&"&");
+        if background {
+            args.pop();
+        }
+        if args.is_empty() {
+            continue;
+        }
+        match args[0] {
+            "jobs" => for &pid in jobs.iter() {
+                println!("[{}]", pid);
+            },
+            "fg" | "bg" => {
+                let pid = match args.get(1) {
+                    Some(arg) => arg.parse::<Pid>().ok().filter(|pid| jobs.contains(pid)),
+                    None => jobs.last().cloned(),
+                };
+                let pid = match pid {
+                    Some(pid) => pid,
+                    None => {
+                        println!("No such job");
+                        continue;
+                    }
+                };
+                if args[0] == "fg" {
+                    console::set_foreground(Some(pid));
+                }
+                signal::send_pgroup(pid, signal::SIGCONT);
+                if args[0] == "fg" {
+                    jobs.retain(|&job| job != pid);
+                    wait_foreground(pid, &mut jobs);
+                }
+            }
+            _ => if let Some(len) = read_program(args[0], buf) {
+                let pid = processor().add(Context::new_user(&buf[..len], &args, &[]));
+                processor().setpgid(pid, pid);
+                match background {
+                    true => {
+                        println!("[{}]", pid);
+                        jobs.push(pid);
+                    }
+                    false => {
+                        console::set_foreground(Some(pid));
+                        wait_foreground(pid, &mut jobs);
+                    }
+                }
+            } else {
+                println!("Program not exist");
+            }
+        }
+    }
+    unsafe { dealloc(buf.as_mut_ptr(), layout) };
+}
+
+/// Read the program `name` into `buf`, return its length
+fn read_program(name: &str, buf: &mut [u8]) -> Option<usize> {
+    let root = ROOT_INODE.lock();
+    let file = root.borrow().lookup(name).ok()?;
+    let len = file.borrow().read_at(0, buf).unwrap();
+    Some(len)
+}
+
+/// Wait for the foreground job `pid` to exit or stop, then take the terminal back
+fn wait_foreground(pid: Pid, jobs: &mut Vec<Pid>) {
+    let options = WaitOptions { stopped: true, ..WaitOptions::default() };
+    let result = processor().current_wait(pid, options);
+    console::set_foreground(None);
+    if let WaitResult::Stopped(_, _) = result {
+        println!("\n[{}] Stopped", pid);
+        jobs.push(pid);
+    }
+}
+
+/// Remove the background jobs exited
+fn reap_jobs(jobs: &mut Vec<Pid>) {
+    let options = WaitOptions { nohang: true, ..WaitOptions::default() };
+    jobs.retain(|&pid| {
+        let result = processor().current_wait(pid, options);
+        match result {
+            WaitResult::Ok(_, code) => {
+                println!("[{}] Done {:#x}", pid, code);
+                false
+            }
+            WaitResult::NotExist => false,
+            _ => true,
+        }
+    });
+}
+
+struct MemBuf(&'static [u8]);
+
+impl MemBuf {
+    unsafe fn new(begin: unsafe extern fn(), end: unsafe extern fn()) -> Self {
+        use core::slice;
+        MemBuf(slice::from_raw_parts(begin as *const u8, end as usize - begin as usize))
+    }
+}
+
+impl Device for MemBuf {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let slice = self.0;
+        let len = buf.len().min(slice.len() - offset);
+        buf[..len].copy_from_slice(&slice[offset..offset + len]);
+        Some(len)
+    }
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Option<usize> {
+        None
+    }
+}
+
+use core::slice;
+
+#[cfg(target_arch = "x86_64")]
+impl BlockedDevice for &'static ide::DISK1 {
+    const BLOCK_SIZE_LOG2: u8 = 9;
+    fn read_at(&mut self, block_id: usize, buf: &mut [u8]) -> bool {
+        assert!(buf.len() >= ide::BLOCK_SIZE);
+        let buf = unsafe { slice::from_raw_parts_mut(buf.as_ptr() as *mut u32, ide::BLOCK_SIZE / 4) };
+        self.0.lock().read(block_id as u64, 1, buf).is_ok()
+    }
+    fn write_at(&mut self, block_id: usize, buf: &[u8]) -> bool {
+        assert!(buf.len() >= ide::BLOCK_SIZE);
+        let buf = unsafe { slice::from_raw_parts(buf.as_ptr() as *mut u32, ide::BLOCK_SIZE / 4) };
+        self.0.lock().write(block_id as u64, 1, buf).is_ok()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,98 @@
+//! Pipes between processes
+//!
+//! A pipe is a bounded byte queue with a read end and a write end.
+//! Each end is closed when the last fd referring to it is closed.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use sync::{Condvar, LockClass, SpinNoIrqLock};
+
+/// Capacity of the buffer, the same as `PIPE_BUF` of Linux
+const PIPE_SIZE: usize = 4096;
+
+struct Pipe {
+    data: SpinNoIrqLock<PipeData>,
+    /// Notified when data is pushed or the write end is closed
+    pushed: Condvar,
+    /// Notified when data is popped or the read end is closed
+    popped: Condvar,
+}
+
+static DATA_CLASS: LockClass = LockClass::new("Pipe::data");
+
+struct PipeData {
+    buf: VecDeque<u8>,
+    reader_closed: bool,
+    writer_closed: bool,
+}
+
+/// The read end of a pipe
+pub struct PipeReader(Arc<Pipe>);
+
+/// The write end of a pipe
+pub struct PipeWriter(Arc<Pipe>);
+
+/// Make a new pipe, return its two ends
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        data: SpinNoIrqLock::new_with_class(PipeData {
+            buf: VecDeque::with_capacity(PIPE_SIZE),
+            reader_closed: false,
+            writer_closed: false,
+        }, &DATA_CLASS),
+        pushed: Condvar::new(),
+        popped: Condvar::new(),
+    });
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+impl PipeReader {
+    /// Block until some data arrives, return 0 if the write end is closed (EOF)
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut data = self.0.data.lock();
+        while data.buf.is_empty() && !data.writer_closed && !buf.is_empty() {
+            data = self.0.pushed.wait(data);
+        }
+        let len = buf.len().min(data.buf.len());
+        for (dst, src) in buf.iter_mut().zip(data.buf.drain(..len)) {
+            *dst = src;
+        }
+        self.0.popped.notify_all();
+        len
+    }
+}
+
+impl PipeWriter {
+    /// Block until all of `buf` is written, fail if the read end is closed (EPIPE)
+    pub fn write(&self, buf: &[u8]) -> Option<usize> {
+        let mut data = self.0.data.lock();
+        let mut written = 0;
+        while written < buf.len() {
+            if data.reader_closed {
+                return None;
+            }
+            if data.buf.len() == PIPE_SIZE {
+                data = self.0.popped.wait(data);
+                continue;
+            }
+            let len = (buf.len() - written).min(PIPE_SIZE - data.buf.len());
+            data.buf.extend(buf[written..written + len].iter());
+            written += len;
+            self.0.pushed.notify_all();
+        }
+        Some(written)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.data.lock().reader_closed = true;
+        self.0.popped.notify_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.data.lock().writer_closed = true;
+        self.0.pushed.notify_all();
+    }
+}
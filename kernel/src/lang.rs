@@ -1,7 +1,11 @@
 // Rust language features implementions
 
+use alloc::{boxed::Box, string::ToString};
+use arch::interrupt;
 use core::panic::PanicInfo;
 use core::alloc::Layout;
+use process::PROCESSOR;
+use thread;
 
 #[lang = "eh_personality"] 
 extern fn eh_personality() {
@@ -13,11 +17,18 @@ pub fn panic(info: &PanicInfo) -> ! {
     let location = info.location().unwrap();
     let message = info.message().unwrap();
     error!("\n\nPANIC in {} at line {}\n    {}", location.file(), location.line(), message);
+    // A thread by `thread::spawn` exits, and `join` gets the message.
+    // With interrupts enabled, the processor is not locked on this CPU.
+    if PROCESSOR.try().is_some() && interrupt::enabled() {
+        thread::exit_panicking(Box::new(message.to_string()));
+    }
     loop { }
 }
 
+/// Out of memory is not caught, as a panic message can not be allocated
 #[lang = "oom"]
 #[no_mangle]
 pub fn oom(_: Layout) -> ! {
-    panic!("out of memory");
+    error!("\n\nPANIC: out of memory");
+    loop { }
 }
@@ -9,6 +9,7 @@
 #![feature(panic_info_message)]
 #![feature(global_asm)]
 #![feature(compiler_builtins_lib)]
+#![cfg_attr(feature = "lockdep", feature(link_llvm_intrinsics))]
 #![no_std]
 
 
@@ -40,7 +41,6 @@ use linked_list_allocator::LockedHeap;
 pub mod logging;
 mod memory;
 mod lang;
-mod util;
 mod consts;
 mod process;
 mod syscall;
@@ -50,6 +50,7 @@ use process::{thread, thread_};
 mod sync;
 mod trap;
 mod console;
+mod time;
 
 #[allow(dead_code)]
 #[cfg(target_arch = "x86_64")]
@@ -61,13 +62,17 @@ pub mod arch;
 pub mod arch;
 
 pub fn kmain() -> ! {
+    #[cfg(feature = "lockdep")]
+    sync::lockdep::init();
+    time::init();
     process::init();
     unsafe { arch::interrupt::enable(); }
 
     fs::shell();
 
-//    thread::test::local_key();
-//    thread::test::unpack();
+//    process::test::local_key();
+//    process::test::unpack();
+//    process::test::join_panic();
 //    sync::test::philosopher_using_mutex();
 //    sync::test::philosopher_using_monitor();
 //    sync::mpsc::test::test_all();
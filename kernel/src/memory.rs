@@ -1,10 +1,14 @@
 pub use arch::paging::*;
 use bit_allocator::{BitAlloc, BitAlloc4K, BitAlloc64K};
 use consts::MEMORY_OFFSET;
-use spin::{Mutex, MutexGuard};
+use sync::{LockClass, MutexGuard, SpinNoIrq, SpinNoIrqLock};
 use super::HEAP_ALLOCATOR;
 use ucore_memory::{*, paging::PageTable};
 use ucore_memory::cow::CowExt;
+#[cfg(target_arch = "x86_64")]
+use ucore_memory::swap::{EnhancedClockSwapManager, SwapExt};
+#[cfg(target_arch = "x86_64")]
+use arch::swap::IdeSwapper;
 pub use ucore_memory::memory_set::{MemoryArea, MemoryAttr, MemorySet as MemorySet_, Stack};
 
 pub type MemorySet = MemorySet_<InactivePageTable0>;
@@ -17,46 +21,132 @@ pub type FrameAlloc = BitAlloc64K;
 #[cfg(target_arch = "riscv32")]
 pub type FrameAlloc = BitAlloc4K;
 
+static FRAME_ALLOCATOR_CLASS: LockClass = LockClass::new("FRAME_ALLOCATOR");
+
 lazy_static! {
-    pub static ref FRAME_ALLOCATOR: Mutex<FrameAlloc> = Mutex::new(FrameAlloc::default());
+    pub static ref FRAME_ALLOCATOR: SpinNoIrqLock<FrameAlloc> = SpinNoIrqLock::new_with_class(FrameAlloc::default(), &FRAME_ALLOCATOR_CLASS);
 }
 
 pub fn alloc_frame() -> Option<usize> {
     let ret = FRAME_ALLOCATOR.lock().alloc().map(|id| id * PAGE_SIZE + MEMORY_OFFSET);
     trace!("Allocate frame: {:x?}", ret);
+    #[cfg(target_arch = "x86_64")]
+    let ret = ret.or_else(|| swap_out_any());
+    ret
+}
+
+/// Swap out a page of the current address space to release its frame
+///
+/// Unavailable when the active table is in use, e.g. in `InactivePageTable::edit`,
+/// where the pages seen by the active table are not the ones of the current address space.
+/// So `MemorySet` allocates the frames it maps before `edit`.
+#[cfg(target_arch = "x86_64")]
+fn swap_out_any() -> Option<usize> {
+    let mut table = ACTIVE_TABLE.try_lock()?;
+    let ret = table.swap_out_any().ok();
+    debug!("Swap out a page, release frame: {:x?}", ret);
     ret
 }
 
+/// Swap in a page for `MemorySet::clone_cow`, in `edit` of the current memory set.
+/// If no frame is free, a page is swapped out by `table` itself.
+#[cfg(target_arch = "x86_64")]
+pub fn swap_in(table: &mut SwapExt<ActivePageTable, EnhancedClockSwapManager, IdeSwapper>, addr: usize) {
+    table.page_fault_handler(addr, alloc_frame);
+}
+
+#[cfg(target_arch = "riscv32")]
+pub fn swap_in(_table: &mut ActivePageTable, _addr: usize) {
+    unreachable!("no page is swapped out");
+}
+
 pub fn dealloc_frame(target: usize) {
     trace!("Deallocate frame: {:x}", target);
     FRAME_ALLOCATOR.lock().dealloc((target - MEMORY_OFFSET) / PAGE_SIZE);
 }
 
+const STACK_SIZE: usize = 0x8000;
+
 // alloc from heap
 pub fn alloc_stack() -> Stack {
     use alloc::alloc::{alloc, Layout};
-    const STACK_SIZE: usize = 0x8000;
     let bottom = unsafe{ alloc(Layout::from_size_align(STACK_SIZE, 0x8000).unwrap()) } as usize;
     let top = bottom + STACK_SIZE;
     Stack { top, bottom }
 }
 
+/// Free a stack from `alloc_stack`, which must not be in use
+pub unsafe fn dealloc_stack(stack: &Stack) {
+    use alloc::alloc::{dealloc, Layout};
+    dealloc(stack.bottom as *mut u8, Layout::from_size_align(STACK_SIZE, 0x8000).unwrap());
+}
+
+/// Active page table with copy-on-write and swap support
+#[cfg(target_arch = "x86_64")]
+pub type ActiveTable = CowExt<SwapExt<ActivePageTable, EnhancedClockSwapManager, IdeSwapper>>;
+
+/// Active page table with copy-on-write support
+#[cfg(target_arch = "riscv32")]
+pub type ActiveTable = CowExt<ActivePageTable>;
+
+static ACTIVE_TABLE_CLASS: LockClass = LockClass::new("ACTIVE_TABLE");
+
 lazy_static! {
-    static ref ACTIVE_TABLE: Mutex<CowExt<ActivePageTable>> = Mutex::new(unsafe {
+    #[cfg(target_arch = "x86_64")]
+    static ref ACTIVE_TABLE: SpinNoIrqLock<ActiveTable> = SpinNoIrqLock::new_with_class(unsafe {
+        CowExt::new(SwapExt::new(ActivePageTable::new(), EnhancedClockSwapManager::default(), IdeSwapper::new()))
+    }, &ACTIVE_TABLE_CLASS);
+    #[cfg(target_arch = "riscv32")]
+    static ref ACTIVE_TABLE: SpinNoIrqLock<ActiveTable> = SpinNoIrqLock::new_with_class(unsafe {
         CowExt::new(ActivePageTable::new())
-    });
+    }, &ACTIVE_TABLE_CLASS);
 }
 
 /// The only way to get active page table
-pub fn active_table() -> MutexGuard<'static, CowExt<ActivePageTable>> {
+pub fn active_table() -> MutexGuard<'static, ActiveTable, SpinNoIrq> {
     ACTIVE_TABLE.lock()
 }
 
-// Return true to continue, false to halt
+/// Allocate a frame when holding the active table, swap out a page if no free frame
+#[cfg(target_arch = "x86_64")]
+fn alloc_frame_for(table: &mut ActiveTable) -> Option<usize> {
+    alloc_frame().or_else(|| table.swap_out_any().ok())
+}
+
+#[cfg(target_arch = "riscv32")]
+fn alloc_frame_for(_table: &mut ActiveTable) -> Option<usize> {
+    alloc_frame()
+}
+
+// Return true to continue, false to halt.
+// The active table and the processor must not be held by this CPU, it deadlocks.
 pub fn page_fault_handler(addr: usize) -> bool {
+    let mut table = active_table();
     // Handle copy on write
-    unsafe { ACTIVE_TABLE.force_unlock(); }
-    active_table().page_fault_handler(addr, || alloc_frame().unwrap())
+    if table.get_entry(addr).writable_shared() {
+        let frame = alloc_frame_for(&mut table).expect("failed to allocate frame");
+        let mut used = false;
+        table.page_fault_handler(addr, || { used = true; frame });
+        if !used {
+            dealloc_frame(frame);
+        }
+        return true;
+    }
+    // Handle swap
+    #[cfg(target_arch = "x86_64")]
+    {
+        // `page_fault_handler` of `SwapExt` under `CowExt`
+        if (**table).page_fault_handler(addr, alloc_frame) {
+            return true;
+        }
+    }
+    drop(table);
+    // Handle lazy areas of the current process
+    use process::PROCESSOR;
+    match PROCESSOR.try() {
+        Some(processor) => processor.lock().current_context_mut().page_fault_handler(addr),
+        None => false,
+    }
 }
 
 pub fn init_heap() {
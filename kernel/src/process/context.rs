@@ -1,17 +1,65 @@
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use arch::interrupt::{TrapFrame, Context as ArchContext};
-use memory::{MemoryArea, MemoryAttr, MemorySet};
+use fs::{FileLike, INodeRef};
+use memory::{MemoryArea, MemoryAttr, MemorySet, Stack};
+use sync::{LockClass, SpinNoIrqLock as Mutex};
+use super::shm::SharedMemory;
+use super::signal::Signals;
 use xmas_elf::{ElfFile, header, program::{Flags, ProgramHeader, Type}};
 use core::fmt::{Debug, Error, Formatter};
 
 pub struct Context {
     arch: ArchContext,
+    /// Own kernel stack of a thread made by `clone`.
+    /// The others run on the one of their memory set.
+    kstack: Option<KernelStack>,
+    /// The user address space, shared by the threads of a process
+    vm: Arc<Mutex<Vm>>,
+    /// File descriptor table, shared by the threads of a process
+    files: Arc<Mutex<BTreeMap<usize, FileLike>>>,
+    signals: Signals,
+    /// Runs only in the kernel, such as idle and init. It takes no signals from the user.
+    kernel: bool,
+    /// May change the settings of the whole system, such as the scheduler.
+    /// Given to the programs run by the kernel shell, kept by their threads but not by `fork`.
+    admin: bool,
+}
+
+static VM_CLASS: LockClass = LockClass::new("Context::vm");
+static FILES_CLASS: LockClass = LockClass::new("Context::files");
+
+/// A user address space with what is mapped in it
+struct Vm {
     memory_set: MemorySet,
+    /// Pages mapped from files by `mmap`, with the file and offset
+    file_pages: BTreeMap<usize, (INodeRef, usize)>,
+    /// Shared memory objects mapped by `shmem`, by the start address.
+    /// Declared after `memory_set`, so the frames are unmapped before freed.
+    shms: BTreeMap<usize, (usize, Arc<SharedMemory>)>,
+    /// Start of the heap, above the ELF segments
+    heap_start: usize,
+    /// Program break, the end of the heap
+    brk: usize,
+}
+
+/// A kernel stack freed with its thread
+struct KernelStack(Stack);
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        // A context is dropped only when it is not running
+        unsafe { ::memory::dealloc_stack(&self.0); }
+    }
 }
 
 impl ::ucore_process::processor::Context for Context {
     unsafe fn switch(&mut self, target: &mut Self) {
         super::PROCESSOR.try().unwrap().force_unlock();
+        #[cfg(feature = "lockdep")]
+        ::sync::lockdep::switch_out(self as *const Self as usize);
         self.arch.switch(&mut target.arch);
+        #[cfg(feature = "lockdep")]
+        ::sync::lockdep::switch_in(self as *const Self as usize);
         use core::mem::forget;
         forget(super::processor());
     }
@@ -20,7 +68,12 @@ impl ::ucore_process::processor::Context for Context {
         let ms = MemorySet::new();
         Context {
             arch: unsafe { ArchContext::new_kernel_thread(entry, arg, ms.kstack_top(), ms.token()) },
-            memory_set: ms,
+            kstack: None,
+            vm: Vm::new(ms, 0),
+            files: Arc::new(Mutex::new_with_class(BTreeMap::new(), &FILES_CLASS)),
+            signals: Signals::default(),
+            kernel: true,
+            admin: false,
         }
     }
 }
@@ -29,98 +82,470 @@ impl Context {
     pub unsafe fn new_init() -> Self {
         Context {
             arch: ArchContext::null(),
-            memory_set: MemorySet::new(),
+            kstack: None,
+            vm: Vm::new(MemorySet::new(), 0),
+            files: Arc::new(Mutex::new_with_class(BTreeMap::new(), &FILES_CLASS)),
+            signals: Signals::default(),
+            kernel: true,
+            admin: false,
+        }
+    }
+
+    /// Make a new user thread from ELF data, with arguments and environment
+    pub fn new_user(data: &[u8], args: &[&str], envs: &[&str]) -> Self {
+        let image = UserImage::from_elf(data, args, envs).expect("failed to load elf");
+        let memory_set = image.memory_set;
+        Context {
+            arch: unsafe {
+                ArchContext::new_user_thread(
+                    image.entry_addr, image.ustack_top, memory_set.kstack_top(), image.is32, memory_set.token(),
+                    image.argc, image.argv)
+            },
+            kstack: None,
+            vm: Vm::new(memory_set, image.heap_start),
+            files: {
+                let mut files = BTreeMap::new();
+                files.insert(0, FileLike::Stdin);
+                files.insert(1, FileLike::Stdout);
+                files.insert(2, FileLike::Stdout);
+                Arc::new(Mutex::new_with_class(files, &FILES_CLASS))
+            },
+            signals: Signals::default(),
+            kernel: false,
+            admin: true,
+        }
+    }
+
+    /// Replace the user memory with `image`, and return to its entry through `tf`.
+    ///
+    /// The fd table is kept. The other threads must have exited.
+    pub fn exec(&mut self, mut image: UserImage, tf: &mut TrapFrame) {
+        // Keep running on the kernel stack of the old memory set, which moves to the new one.
+        // The new stack is never used, free it now.
+        // NOTE: Kernel stacks of memory sets are never freed by `MemorySet`,
+        //       so the old one keeps the freed stack without freeing it again.
+        {
+            let mut vm = self.vm.lock();
+            image.memory_set.swap_kstack(&mut vm.memory_set);
+            unsafe { ::memory::dealloc_stack(vm.memory_set.kstack()); }
+        }
+        // Switch to the new page table before the old one is dropped.
+        unsafe { image.memory_set.activate(); }
+        self.vm = Vm::new(image.memory_set, image.heap_start);
+        self.signals.exec();
+        *tf = TrapFrame::new_user_thread(image.entry_addr, image.ustack_top, image.is32, image.argc, image.argv);
+    }
+
+    /// Fork
+    pub fn fork(&mut self, tf: &TrapFrame) -> Self {
+        let vm = {
+            let mut vm = self.vm.lock();
+            // Share the frames copy-on-write, make a new page table
+            let memory_set = vm.memory_set.clone_cow(::memory::swap_in);
+            Vm {
+                memory_set,
+                file_pages: vm.file_pages.clone(),
+                shms: vm.shms.clone(),
+                heap_start: vm.heap_start,
+                brk: vm.brk,
+            }
+        };
+
+        Context {
+            arch: unsafe { ArchContext::new_fork(tf, vm.memory_set.kstack_top(), vm.memory_set.token()) },
+            kstack: None,
+            vm: Arc::new(Mutex::new_with_class(vm, &VM_CLASS)),
+            files: Arc::new(Mutex::new_with_class(self.files.lock().clone(), &FILES_CLASS)),
+            signals: self.signals.fork(),
+            kernel: false,
+            admin: false,
+        }
+    }
+
+    /// Make a thread sharing the address space, fd table and signal actions.
+    /// It returns to the user by `tf` with stack `ustack_top` and thread pointer `tls`,
+    /// and runs on its own kernel stack.
+    pub fn clone_thread(&self, tf: &TrapFrame, ustack_top: usize, tls: usize) -> Self {
+        let kstack = ::memory::alloc_stack();
+        let token = self.vm.lock().memory_set.token();
+        Context {
+            arch: unsafe { ArchContext::new_clone(tf, ustack_top, tls, kstack.top, token) },
+            kstack: Some(KernelStack(kstack)),
+            vm: self.vm.clone(),
+            files: self.files.clone(),
+            signals: self.signals.clone_thread(),
+            kernel: false,
+            admin: self.admin,
+        }
+    }
+
+    /// Map `len` bytes at `addr` lazily, read from `file` at the offset if given.
+    /// Look for free space above `addr` unless `fixed`, return the address.
+    pub fn mmap(&mut self, addr: usize, len: usize, attr: MemoryAttr, fixed: bool, file: Option<(INodeRef, usize)>) -> Option<usize> {
+        self.vm.lock().mmap(addr, len, attr, fixed, file)
+    }
+
+    /// Unmap `[addr, addr + len)`
+    pub fn munmap(&mut self, addr: usize, len: usize) {
+        self.vm.lock().munmap(addr, len)
+    }
+
+    /// Set the program break to `addr` if it is valid, return the new one.
+    pub fn brk(&mut self, addr: usize) -> usize {
+        self.vm.lock().brk(addr)
+    }
+
+    /// Map `len` bytes of the shared memory object `shm` at `addr`,
+    /// or free space if `addr` is 0. Return the address, or `None` if no free space.
+    pub fn shmem(&mut self, addr: usize, len: usize, attr: MemoryAttr, shm: Arc<SharedMemory>) -> Option<usize> {
+        self.vm.lock().shmem(addr, len, attr, shm)
+    }
+
+    /// Whether `[addr, addr + len)` is accessible by the user, writable if `write`
+    pub fn check_user_range(&self, addr: usize, len: usize, write: bool) -> bool {
+        self.vm.lock().memory_set.check_user_range(addr, len, write)
+    }
+
+    /// Map the page of `addr` on its first access if it is in a lazy area
+    pub fn page_fault_handler(&mut self, addr: usize) -> bool {
+        let mut guard = self.vm.lock();
+        let vm = &mut *guard;
+        let Vm { ref mut memory_set, ref file_pages, .. } = *vm;
+        memory_set.page_fault_handler(addr, |page_addr, data| {
+            if let Some(&(ref inode, offset)) = file_pages.get(&page_addr) {
+                // The rest of the page is zero if the file ends
+                let _ = inode.lock().borrow().read_at(offset, data);
+            }
+        })
+    }
+
+    /// Put `file` at the lowest free fd, return the fd
+    pub fn add_file(&mut self, file: FileLike) -> usize {
+        let mut files = self.files.lock();
+        let fd = (0..).find(|fd| !files.contains_key(fd)).unwrap();
+        files.insert(fd, file);
+        fd
+    }
+
+    /// Put `file` at `fd`, return the old one if exists
+    pub fn set_file(&mut self, fd: usize, file: FileLike) -> Option<FileLike> {
+        self.files.lock().insert(fd, file)
+    }
+
+    pub fn get_file(&self, fd: usize) -> Option<FileLike> {
+        self.files.lock().get(&fd).cloned()
+    }
+
+    pub fn close_file(&mut self, fd: usize) -> Option<FileLike> {
+        self.files.lock().remove(&fd)
+    }
+
+    pub fn signals_mut(&mut self) -> &mut Signals {
+        &mut self.signals
+    }
+
+    pub fn is_kernel(&self) -> bool {
+        self.kernel
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    /// Take the fd table out when exiting, it is closed if no other thread shares it.
+    ///
+    /// Drop it without the processor locked, closing a pipe may wake up other processes.
+    pub fn take_files(&mut self) -> Arc<Mutex<BTreeMap<usize, FileLike>>> {
+        use core::mem::replace;
+        replace(&mut self.files, Arc::new(Mutex::new_with_class(BTreeMap::new(), &FILES_CLASS)))
+    }
+}
+
+impl Vm {
+    fn new(memory_set: MemorySet, heap_start: usize) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new_with_class(Vm {
+            memory_set,
+            file_pages: BTreeMap::new(),
+            shms: BTreeMap::new(),
+            heap_start,
+            brk: heap_start,
+        }, &VM_CLASS))
+    }
+
+    fn mmap(&mut self, addr: usize, len: usize, attr: MemoryAttr, fixed: bool, file: Option<(INodeRef, usize)>) -> Option<usize> {
+        use ucore_memory::Page;
+        let addr = match fixed {
+            true => {
+                self.munmap(addr, len);
+                addr
+            }
+            false => self.find_free_area(addr, len)?,
+        };
+        self.memory_set.push(MemoryArea::new_lazy(addr, addr + len, attr, "mmap"));
+        if let Some((inode, offset)) = file {
+            for page in Page::range_of(addr, addr + len) {
+                let page_addr = page.start_address();
+                self.file_pages.insert(page_addr, (inode.clone(), offset + page_addr - addr));
+            }
+        }
+        Some(addr)
+    }
+
+    /// Free space of `len` bytes in user space, from the hint `addr` if it is there
+    fn find_free_area(&self, addr: usize, len: usize) -> Option<usize> {
+        use consts::{USER_MMAP_OFFSET, USER_TOP};
+        let hint = match addr < USER_TOP {
+            true => addr.max(USER_MMAP_OFFSET),
+            false => USER_MMAP_OFFSET,
+        };
+        let addr = self.memory_set.find_free_area(hint, len)?;
+        match addr + len <= USER_TOP {
+            true => Some(addr),
+            false => None,
         }
     }
 
-    /// Make a new user thread from ELF data
-    pub fn new_user(data: &[u8]) -> Self {
+    fn munmap(&mut self, addr: usize, len: usize) {
+        use ucore_memory::Page;
+        self.memory_set.remove(addr, addr + len);
+        let start = Page::of_addr(addr).start_address();
+        let pages: Vec<usize> = self.file_pages.range(start..addr + len).map(|(&addr, _)| addr).collect();
+        for addr in pages {
+            self.file_pages.remove(&addr);
+        }
+        // Release the shared memory objects no longer mapped at all
+        let memory_set = &self.memory_set;
+        let unmapped: Vec<usize> = self.shms.iter()
+            .filter(|&(&start, &(end, _))| !memory_set.iter().any(|area| area.start_addr() < end && start < area.end_addr()))
+            .map(|(&start, _)| start).collect();
+        for start in unmapped {
+            self.shms.remove(&start);
+        }
+    }
+
+    /// The heap is mapped lazily, pages above the new break are unmapped when shrinking.
+    /// The break stays in `[heap_start, USER_TOP]`.
+    fn brk(&mut self, addr: usize) -> usize {
+        use consts::USER_TOP;
+        use ucore_memory::PAGE_SIZE;
+        let page_end = |addr: usize| addr.checked_add(PAGE_SIZE - 1).map(|end| end / PAGE_SIZE * PAGE_SIZE);
+        let new_end = match page_end(addr) {
+            Some(end) if addr >= self.heap_start && end <= USER_TOP => end,
+            _ => return self.brk,
+        };
+        let old_end = page_end(self.brk).unwrap();
+        if new_end > old_end {
+            if self.memory_set.iter().any(|area| area.start_addr() < new_end && old_end < area.end_addr()) {
+                return self.brk;
+            }
+            self.memory_set.push(MemoryArea::new_lazy(old_end, new_end, MemoryAttr::default().user(), "heap"));
+        } else if new_end < old_end {
+            self.memory_set.remove(new_end, old_end);
+        }
+        self.brk = addr;
+        self.brk
+    }
+
+    fn shmem(&mut self, addr: usize, len: usize, attr: MemoryAttr, shm: Arc<SharedMemory>) -> Option<usize> {
+        use ucore_memory::PAGE_SIZE;
+        let addr = match addr {
+            0 => self.find_free_area(0, len)?,
+            _ => {
+                self.munmap(addr, len);
+                addr
+            }
+        };
+        let end = addr + (len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        self.memory_set.push_shared(MemoryArea::new_shared(addr, end, attr, "shmem"), shm.frames());
+        self.shms.insert(addr, (end, shm));
+        Some(addr)
+    }
+}
+
+impl Debug for Context {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{:x?}", self.arch)
+    }
+}
+
+/// The user memory made from an ELF file
+pub struct UserImage {
+    memory_set: MemorySet,
+    entry_addr: usize,
+    ustack_top: usize,
+    is32: bool,
+    argc: usize,
+    /// Address of `argv[0]` on the user stack
+    argv: usize,
+    /// The page after the highest segment
+    heap_start: usize,
+}
+
+impl UserImage {
+    /// Load ELF `data` to a new memory set, with `args` and `envs` on its user stack
+    pub fn from_elf(data: &[u8], args: &[&str], envs: &[&str]) -> Result<Self, &'static str> {
         // Parse elf
-        let elf = ElfFile::new(data).expect("failed to read elf");
+        let elf = ElfFile::new(data)?;
         let is32 = match elf.header.pt2 {
             header::HeaderPt2::Header32(_) => true,
             header::HeaderPt2::Header64(_) => false,
         };
-        assert_eq!(elf.header.pt2.type_().as_type(), header::Type::Executable, "ELF is not executable");
+        if elf.header.pt2.type_().as_type() != header::Type::Executable {
+            return Err("ELF is not executable");
+        }
 
         // User stack
-        use consts::{USER_STACK_OFFSET, USER_STACK_SIZE, USER32_STACK_OFFSET};
+        use consts::{USER_STACK_OFFSET, USER_STACK_SIZE, USER_STACK_MAX_SIZE, USER32_STACK_OFFSET};
         let (user_stack_buttom, user_stack_top) = match is32 {
             true => (USER32_STACK_OFFSET, USER32_STACK_OFFSET + USER_STACK_SIZE),
             false => (USER_STACK_OFFSET, USER_STACK_OFFSET + USER_STACK_SIZE),
         };
 
+        // Reserved for the stack to grow, mapped on fault
+        let user_stack_limit = user_stack_top - USER_STACK_MAX_SIZE;
+        check_segments(&elf, data, (user_stack_limit, user_stack_top))?;
+        if args_size(args, envs, is32) > USER_STACK_SIZE {
+            return Err("arguments too long");
+        }
+
         // Make page table
         let mut memory_set = memory_set_from(&elf);
         memory_set.push(MemoryArea::new(user_stack_buttom, user_stack_top, MemoryAttr::default().user(), "user_stack"));
+        memory_set.push(MemoryArea::new_lazy(user_stack_limit, user_stack_buttom, MemoryAttr::default().user(), "user_stack_reserved"));
         trace!("{:#x?}", memory_set);
 
         let entry_addr = elf.header.pt2.entry_point() as usize;
 
+        use ucore_memory::PAGE_SIZE;
+        let heap_start = elf.program_iter()
+            .filter(|ph| ph.get_type() == Ok(Type::Load))
+            .map(|ph| (ph.virtual_addr() + ph.mem_size()) as usize)
+            .max().unwrap_or(0);
+        let heap_start = (heap_start + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+
         // Temporary switch to it, in order to copy data
+        let mut stack = (0, 0);
         unsafe {
             memory_set.with(|| {
-                for ph in elf.program_iter() {
+                for ph in elf.program_iter().filter(|ph| ph.get_type() == Ok(Type::Load)) {
                     let virt_addr = ph.virtual_addr() as usize;
                     let offset = ph.offset() as usize;
                     let file_size = ph.file_size() as usize;
                     if file_size == 0 {
-                        return;
+                        continue;
                     }
                     use core::slice;
                     let target = unsafe { slice::from_raw_parts_mut(virt_addr as *mut u8, file_size) };
                     target.copy_from_slice(&data[offset..offset + file_size]);
                 }
-                if is32 {
-                    unsafe {
-                        // TODO: full argc & argv
-                        *(user_stack_top as *mut u32).offset(-1) = 0; // argv
-                        *(user_stack_top as *mut u32).offset(-2) = 0; // argc
-                    }
-                }
+                stack = unsafe { push_args_at(user_stack_top, args, envs, is32) };
             });
         }
+        // Protect the segments after the data is copied
+        for ph in elf.program_iter().filter(|ph| ph.get_type() == Ok(Type::Load) && ph.mem_size() > 0) {
+            memory_set.set_flags(ph.virtual_addr() as usize, memory_attr_from(ph.flags()));
+        }
+        let (ustack_top, argv) = stack;
 
-        Context {
-            arch: unsafe {
-                ArchContext::new_user_thread(
-                    entry_addr, user_stack_top - 8, memory_set.kstack_top(), is32, memory_set.token())
-            },
-            memory_set,
+        Ok(UserImage { memory_set, entry_addr, ustack_top, is32, argc: args.len(), argv, heap_start })
+    }
+}
+
+/// Check the loadable segments of `elf` are in `data` and in user space,
+/// and overlap neither each other nor the user stack `[stack.0, stack.1)`.
+fn check_segments(elf: &ElfFile, data: &[u8], stack: (usize, usize)) -> Result<(), &'static str> {
+    use consts::USER_TOP;
+    use ucore_memory::PAGE_SIZE;
+    let mut ranges = vec![stack];
+    for ph in elf.program_iter().filter(|ph| ph.get_type() == Ok(Type::Load)) {
+        let (virt_addr, mem_size) = (ph.virtual_addr() as usize, ph.mem_size() as usize);
+        let (offset, file_size) = (ph.offset() as usize, ph.file_size() as usize);
+        if file_size > mem_size {
+            return Err("segment larger in file than in memory");
+        }
+        if offset.checked_add(file_size).map_or(true, |end| end > data.len()) {
+            return Err("segment out of file");
+        }
+        if mem_size == 0 {
+            continue;
         }
+        let end = match virt_addr.checked_add(mem_size) {
+            Some(end) if end <= USER_TOP => end,
+            _ => return Err("segment out of user space"),
+        };
+        // Areas are mapped by pages
+        let range = (virt_addr / PAGE_SIZE * PAGE_SIZE, (end - 1) / PAGE_SIZE * PAGE_SIZE + PAGE_SIZE);
+        if ranges.iter().any(|&(start, end)| range.0 < end && start < range.1) {
+            return Err("segments overlap");
+        }
+        ranges.push(range);
     }
+    Ok(())
+}
 
-    /// Fork
-    pub fn fork(&self, tf: &TrapFrame) -> Self {
-        // Clone memory set, make a new page table
-        let memory_set = self.memory_set.clone();
+/// Bytes taken by `args` and `envs` on the user stack, see `push_args_at`
+fn args_size(args: &[&str], envs: &[&str], is32: bool) -> usize {
+    use core::mem::size_of;
+    let word_size = if is32 { 4 } else { size_of::<usize>() };
+    let strings: usize = args.iter().chain(envs.iter()).map(|s| s.len() + 1).sum();
+    // Besides the pointers: argc, the NULLs, and the words of 64-bit and the alignment
+    strings + (args.len() + envs.len() + 5) * word_size + 0x20
+}
 
-        // Copy data to temp space
-        use alloc::vec::Vec;
-        let datas: Vec<Vec<u8>> = memory_set.iter().map(|area| {
-            Vec::from(unsafe { area.as_slice() })
-        }).collect();
+/// Push `args` and `envs` to the user stack below `stack_top`, return `(sp, argv)`.
+///
+/// The page table of the stack must be active. The layout from low to high address:
+///
+/// * ucore32: `argc, argv[0..argc], NULL, envp[..], NULL, strings`
+/// * xv6 64-bit: `fake return address, argc, argv, argv[0..argc], NULL, envp[..], NULL, strings`
+///
+/// `argc` and `argv` are passed in registers as well, see `TrapFrame::new_user_thread`.
+unsafe fn push_args_at(stack_top: usize, args: &[&str], envs: &[&str], is32: bool) -> (usize, usize) {
+    use alloc::vec::Vec;
+    use core::{mem::size_of, slice};
+    let word_size = if is32 { 4 } else { size_of::<usize>() };
 
-        // Temporary switch to it, in order to copy data
-        unsafe {
-            memory_set.with(|| {
-                for (area, data) in memory_set.iter().zip(datas.iter()) {
-                    unsafe { area.as_slice_mut() }.copy_from_slice(data.as_slice())
-                }
-            });
-        }
+    // Strings
+    let mut sp = stack_top;
+    let mut str_ptrs = Vec::new();
+    for s in args.iter().chain(envs.iter()) {
+        sp -= s.len() + 1;
+        slice::from_raw_parts_mut(sp as *mut u8, s.len()).copy_from_slice(s.as_bytes());
+        *((sp + s.len()) as *mut u8) = 0;
+        str_ptrs.push(sp);
+    }
+    let (arg_ptrs, env_ptrs) = str_ptrs.split_at(args.len());
 
-        Context {
-            arch: unsafe { ArchContext::new_fork(tf, memory_set.kstack_top(), memory_set.token()) },
-            memory_set,
-        }
+    // Pointers
+    let mut words = Vec::new();
+    match is32 {
+        true => words.push(args.len()),
+        false => words.extend_from_slice(&[0, args.len(), 0]),
     }
-}
+    let argv_offset = words.len() * word_size;
+    words.extend_from_slice(arg_ptrs);
+    words.push(0);
+    words.extend_from_slice(env_ptrs);
+    words.push(0);
 
-impl Debug for Context {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "{:x?}", self.arch)
+    // Keep the stack aligned to 16 bytes at function entry:
+    // `rsp + 8` on x86_64 (after pushing the return address), `sp` on others.
+    let mut sp = (sp - words.len() * word_size) & !0xf;
+    if !is32 && cfg!(target_arch = "x86_64") {
+        sp -= 8;
+    }
+    let argv = sp + argv_offset;
+    if !is32 {
+        words[2] = argv;
     }
+    for (i, &word) in words.iter().enumerate() {
+        let addr = sp + i * word_size;
+        match is32 {
+            true => *(addr as *mut u32) = word as u32,
+            false => *(addr as *mut usize) = word,
+        }
+    }
+    (sp, argv)
 }
 
 fn memory_set_from<'a>(elf: &'a ElfFile<'a>) -> MemorySet {
@@ -129,18 +554,22 @@ fn memory_set_from<'a>(elf: &'a ElfFile<'a>) -> MemorySet {
         if ph.get_type() != Ok(Type::Load) {
             continue;
         }
-        let (virt_addr, mem_size, flags) = match ph {
-            ProgramHeader::Ph32(ph) => (ph.virtual_addr as usize, ph.mem_size as usize, ph.flags),
-            ProgramHeader::Ph64(ph) => (ph.virtual_addr as usize, ph.mem_size as usize, ph.flags),
+        let (virt_addr, mem_size) = match ph {
+            ProgramHeader::Ph32(ph) => (ph.virtual_addr as usize, ph.mem_size as usize),
+            ProgramHeader::Ph64(ph) => (ph.virtual_addr as usize, ph.mem_size as usize),
         };
-        set.push(MemoryArea::new(virt_addr, virt_addr + mem_size, memory_attr_from(flags), ""));
+        if mem_size == 0 {
+            continue;
+        }
+        // Writable until the data is copied, see `UserImage::from_elf`
+        set.push(MemoryArea::new(virt_addr, virt_addr + mem_size, MemoryAttr::default().user(), ""));
     }
     set
 }
 
 fn memory_attr_from(elf_flags: Flags) -> MemoryAttr {
     let mut flags = MemoryAttr::default().user();
-    // TODO: handle readonly
+    if !elf_flags.is_write() { flags = flags.readonly(); }
     if elf_flags.is_execute() { flags = flags.execute(); }
     flags
 }
\ No newline at end of file
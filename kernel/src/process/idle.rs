@@ -0,0 +1,53 @@
+//! Tickless idle
+//!
+//! An idle CPU halts until an interrupt comes, with its timer programmed
+//! one-shot at the earliest timer event instead of ticking.
+//! The first interrupt after the sleep restarts the tick,
+//! and counts the ticks missed by the monotonic clock.
+//! A CPU sleeps until a process is queued to it, which sends it a reschedule IPI.
+//! The first CPU keeps time, so it sleeps only while all CPUs are idle.
+
+use alloc::collections::BTreeMap;
+use arch::{cpu, interrupt, timer};
+use core::time::Duration;
+use sync::{LockClass, SpinNoIrqLock};
+use super::processor;
+
+/// Nanoseconds of a tick, at 100Hz
+const TICK_NANOS: u64 = 10_000_000;
+
+static SLEEPING_CLASS: LockClass = LockClass::new("SLEEPING");
+
+lazy_static! {
+    /// The time each sleeping CPU stopped its tick
+    static ref SLEEPING: SpinNoIrqLock<BTreeMap<usize, Duration>> = SpinNoIrqLock::new_with_class(BTreeMap::new(), &SLEEPING_CLASS);
+}
+
+/// The body of idle processes
+pub fn run() -> ! {
+    loop {
+        unsafe { interrupt::disable_and_store(); }
+        let ticks = processor().idle_ticks();
+        if let Some(ticks) = ticks {
+            SLEEPING.lock().insert(cpu::id(), ::time::monotonic());
+            timer::set_oneshot(ticks);
+        }
+        unsafe { interrupt::enable_and_wait(); }
+    }
+}
+
+/// Called by every interrupt. If this CPU has been sleeping, restart its tick and
+/// count the ticks missed, then return true.
+pub fn wake() -> bool {
+    let since = SLEEPING.lock().remove(&cpu::id());
+    let since = match since {
+        Some(since) => since,
+        None => return false,
+    };
+    timer::set_periodic();
+    let elapsed = ::time::monotonic() - since;
+    let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    let ticks = (nanos + TICK_NANOS / 2) / TICK_NANOS;
+    processor().tick_by(ticks as usize);
+    true
+}
@@ -1,33 +1,102 @@
+use alloc::{boxed::Box, vec::Vec};
+use arch::cpu;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Once;
-use sync::{SpinNoIrqLock, Mutex, MutexGuard, SpinNoIrq};
-pub use self::context::Context;
+use sync::{LockClass, SpinNoIrqLock, Mutex, MutexGuard, SpinNoIrq};
+pub use self::context::{Context, UserImage};
 pub use ucore_process::processor::{*, Context as _whatever};
 pub use ucore_process::scheduler::*;
 pub use ucore_process::thread::*;
 
 mod context;
+pub mod idle;
+pub mod shm;
+pub mod signal;
+pub mod test;
+pub mod user;
 
-type Processor = Processor_<Context, StrideScheduler>;
+type Processor = Processor_<Context, BoxScheduler>;
+type BoxScheduler = Box<Scheduler + Send>;
+
+/// Scheduling policies, chosen by `sys_set_scheduler`
+pub const SCHED_STRIDE: usize = 0;
+pub const SCHED_RR: usize = 1;
+pub const SCHED_MLFQ: usize = 2;
+
+/// The current policy, for the CPUs joining later
+static SCHED_POLICY: AtomicUsize = AtomicUsize::new(SCHED_STRIDE);
+
+fn new_scheduler(policy: usize) -> Option<BoxScheduler> {
+    match policy {
+        // NOTE: max_time_slice <= 5 to ensure 'priority' test pass
+        SCHED_STRIDE => Some(Box::new(StrideScheduler::new(5))),
+        SCHED_RR => Some(Box::new(RRScheduler::new(5))),
+        SCHED_MLFQ => Some(Box::new(MLFQScheduler::new(4, 1, 100))),
+        _ => None,
+    }
+}
+
+/// Switch all CPUs to the scheduler of `policy`, keeping the processes ready to run.
+/// Return false if the policy is unknown.
+pub fn set_scheduler(policy: usize) -> bool {
+    if new_scheduler(policy).is_none() {
+        return false;
+    }
+    let mut processor = processor();
+    SCHED_POLICY.store(policy, Ordering::SeqCst);
+    processor.set_scheduler(|| new_scheduler(policy).unwrap());
+    info!("scheduler policy: {}", policy);
+    true
+}
 
 pub fn init() {
     PROCESSOR.call_once(||
-        SpinNoIrqLock::new({
-            let mut processor = Processor::new(
+        SpinNoIrqLock::new_with_class({
+            let mut processor = Processor::new(cpu::id, cpu::send_reschedule);
+            extern fn idle(_arg: usize) -> ! {
+                self::idle::run()
+            }
+            processor.add_cpu(
                 unsafe { Context::new_init() },
-                // NOTE: max_time_slice <= 5 to ensure 'priority' test pass
-                StrideScheduler::new(5),
+                Some(Context::new_kernel(idle, 0)),
+                new_scheduler(SCHED_POLICY.load(Ordering::SeqCst)).unwrap(),
             );
-            extern fn idle(arg: usize) -> ! {
-                loop {}
-            }
-            processor.add(Context::new_kernel(idle, 0));
             processor
-        })
+        }, &PROCESSOR_CLASS)
     );
     info!("process init end");
 }
 
+/// Let an AP join scheduling. The current context becomes its idle process.
+pub fn init_other() {
+    // Wait for the BSP
+    while PROCESSOR.try().is_none() {}
+    let mut processor = processor();
+    let scheduler = new_scheduler(SCHED_POLICY.load(Ordering::SeqCst)).unwrap();
+    processor.add_cpu(unsafe { Context::new_init() }, None, scheduler);
+    info!("process init_other end on CPU {}", cpu::id());
+}
+
+/// Exit all threads in the group of `pid` with `code`
+pub fn exit_group(pid: Pid, code: usize) {
+    // Close the files first, see `Context::take_files`
+    let files: Vec<_> = {
+        let mut processor = processor();
+        let members = processor.group_members(pid);
+        members.into_iter()
+            .filter_map(|tid| processor.context_mut(tid).map(|context| context.take_files()))
+            .collect()
+    };
+    drop(files);
+    let mut processor = processor();
+    // It may have exited and been removed in the meantime
+    if processor.group(pid).is_some() {
+        processor.exit_group(pid, code);
+    }
+}
+
 pub static PROCESSOR: Once<SpinNoIrqLock<Processor>> = Once::new();
+static PROCESSOR_CLASS: LockClass = LockClass::new("PROCESSOR");
 
 pub fn processor() -> MutexGuard<'static, Processor, SpinNoIrq> {
     PROCESSOR.try().unwrap().lock()
@@ -38,13 +107,14 @@ pub type thread = ThreadMod<ThreadSupportImpl>;
 
 pub mod thread_ {
     pub type Thread = super::Thread<super::ThreadSupportImpl>;
+    pub type LocalKey<T> = super::LocalKey<T, super::ThreadSupportImpl>;
 }
 
 pub struct ThreadSupportImpl;
 
 impl ThreadSupport for ThreadSupportImpl {
     type Context = Context;
-    type Scheduler = StrideScheduler;
+    type Scheduler = BoxScheduler;
     type ProcessorGuard = MutexGuard<'static, Processor, SpinNoIrq>;
 
     fn processor() -> Self::ProcessorGuard {
@@ -0,0 +1,72 @@
+//! Shared memory objects, mapped by `sys_shmem`
+//!
+//! An object is named by a key, and held by the processes mapping it.
+//! Its frames are freed when the last process unmaps it, or exits.
+
+use alloc::{collections::BTreeMap, sync::{Arc, Weak}, vec::Vec};
+use memory::{alloc_frame, dealloc_frame, zero_frame};
+use sync::{LockClass, SpinNoIrqLock};
+use ucore_memory::PAGE_SIZE;
+
+/// The largest size of an object, all frames of which are allocated on creation
+pub const MAX_LEN: usize = 4 * 1024 * 1024;
+
+/// Frames of a shared memory object, deallocated on drop
+pub struct SharedMemory {
+    frames: Vec<usize>,
+}
+
+impl SharedMemory {
+    /// Allocate `len` bytes of zeroed frames
+    fn new(len: usize) -> Option<Self> {
+        let mut shm = SharedMemory { frames: Vec::new() };
+        for _ in 0..(len + PAGE_SIZE - 1) / PAGE_SIZE {
+            // Allocated frames are released by drop on failure
+            let frame = alloc_frame()?;
+            zero_frame(frame);
+            shm.frames.push(frame);
+        }
+        Some(shm)
+    }
+
+    pub fn frames(&self) -> &[usize] {
+        &self.frames
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for &frame in self.frames.iter() {
+            dealloc_frame(frame);
+        }
+    }
+}
+
+static SHARED_MEMORIES_CLASS: LockClass = LockClass::new("SHARED_MEMORIES");
+
+lazy_static! {
+    /// Living objects by key. An entry is stale once all processes dropped the object.
+    static ref SHARED_MEMORIES: SpinNoIrqLock<BTreeMap<usize, Weak<SharedMemory>>> =
+        SpinNoIrqLock::new_with_class(BTreeMap::new(), &SHARED_MEMORIES_CLASS);
+}
+
+/// Get the object of `key`, or create one of `len` bytes if it does not exist.
+///
+/// Fail if the existing one is shorter than `len`, or out of memory.
+pub fn get_or_create(key: usize, len: usize) -> Option<Arc<SharedMemory>> {
+    assert!(len <= MAX_LEN, "shared memory too large");
+    let mut shms = SHARED_MEMORIES.lock();
+    if let Some(shm) = shms.get(&key).and_then(|shm| shm.upgrade()) {
+        return match shm.len() >= len {
+            true => Some(shm),
+            false => None,
+        };
+    }
+    let shm = Arc::new(SharedMemory::new(len)?);
+    shms.insert(key, Arc::downgrade(&shm));
+    Some(shm)
+}
@@ -0,0 +1,420 @@
+//! POSIX-style signals
+//!
+//! Each process has a pending set, a blocked set, and an action for every signal.
+//! Signals are delivered by `handle` when the process returns to the user.
+//! A user handler runs on the user stack above a `SignalFrame` saving the `TrapFrame`,
+//! and returns to the restorer given by `sigaction`, which must call `sys_sigreturn`.
+//!
+//! Signals terminating the process by default are done at once when sent,
+//! so a process blocked in the kernel is killed too, with all its threads.
+//! The actions are shared by the threads of a process, while each thread has its own pending and blocked sets.
+//! Stopping and continuing apply to all threads of a process, and are reported to the parent by `wait`.
+
+use alloc::sync::Arc;
+use arch::interrupt::TrapFrame;
+use core::{mem::size_of, ptr};
+use sync::{LockClass, SpinNoIrqLock as Mutex};
+use super::{exit_group, processor, user, Pid};
+
+pub const NSIG: usize = 32;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+/// Handlers with special meanings
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+/// Do not block the signal during its handler
+pub const SA_NODEFER: u32 = 0x4000_0000;
+/// Reset the action to default when the handler is called
+pub const SA_RESETHAND: u32 = 0x8000_0000;
+
+/// How `sys_sigprocmask` changes the blocked set
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// The exit code of a process killed by a signal is `KILLED | signal`.
+/// Other exit codes are kept as they are, for ucore programs.
+pub const KILLED: usize = 0x1000;
+
+/// Size of the area below the user stack pointer that a handler must not touch
+#[cfg(target_arch = "x86_64")]
+const RED_ZONE: usize = 128;
+#[cfg(target_arch = "riscv32")]
+const RED_ZONE: usize = 0;
+
+/// Can the program returning by `tf` have handlers ?
+/// 32-bit programs on x86_64 can not, `SigAction` and `SignalFrame` are only for 64-bit ones.
+#[cfg(target_arch = "x86_64")]
+pub fn handlers_supported(tf: &TrapFrame) -> bool {
+    !tf.is_32bit()
+}
+#[cfg(target_arch = "riscv32")]
+pub fn handlers_supported(_tf: &TrapFrame) -> bool {
+    true
+}
+
+/// A set of signals, bit `n` for signal `n`
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SigSet(pub u32);
+
+impl SigSet {
+    pub fn contains(&self, sig: usize) -> bool {
+        self.0 & (1 << sig) != 0
+    }
+    pub fn add(&mut self, sig: usize) {
+        self.0 |= 1 << sig;
+    }
+    pub fn remove(&mut self, sig: usize) {
+        self.0 &= !(1 << sig);
+    }
+    /// Remove the signals can not be blocked
+    fn blockable(self) -> Self {
+        SigSet(self.0 & !(1 << SIGKILL | 1 << SIGSTOP))
+    }
+}
+
+/// Action of a signal, the same layout as the user's
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SigAction {
+    /// Address of `extern fn(signal: usize)`, or `SIG_DFL`, `SIG_IGN`
+    pub handler: usize,
+    /// Blocked during the handler, besides the signal itself
+    pub mask: SigSet,
+    pub flags: u32,
+    /// Where the handler returns to
+    pub restorer: usize,
+}
+
+/// Signal states of a thread
+#[derive(Debug)]
+pub struct Signals {
+    pending: SigSet,
+    blocked: SigSet,
+    /// Shared by the threads of a process
+    actions: Arc<Mutex<[SigAction; NSIG]>>,
+}
+
+static ACTIONS_CLASS: LockClass = LockClass::new("Signals::actions");
+
+impl Default for Signals {
+    fn default() -> Self {
+        Signals {
+            pending: SigSet::default(),
+            blocked: SigSet::default(),
+            actions: Arc::new(Mutex::new_with_class([SigAction::default(); NSIG], &ACTIONS_CLASS)),
+        }
+    }
+}
+
+impl Signals {
+    pub fn action(&self, sig: usize) -> SigAction {
+        self.actions.lock()[sig]
+    }
+    /// Set the action of `sig`, return false if it can not be caught or ignored
+    pub fn set_action(&mut self, sig: usize, action: SigAction) -> bool {
+        if sig == SIGKILL || sig == SIGSTOP {
+            return false;
+        }
+        self.actions.lock()[sig] = action;
+        // Ignored signals are discarded
+        if self.is_ignored(sig) {
+            self.pending.remove(sig);
+        }
+        true
+    }
+    pub fn blocked(&self) -> SigSet {
+        self.blocked
+    }
+    pub fn set_blocked(&mut self, blocked: SigSet) {
+        self.blocked = blocked.blockable();
+    }
+    /// Fork: the child has a copy of the actions and the blocked set, but nothing pending
+    pub fn fork(&self) -> Self {
+        Signals {
+            pending: SigSet::default(),
+            blocked: self.blocked,
+            actions: Arc::new(Mutex::new_with_class(*self.actions.lock(), &ACTIONS_CLASS)),
+        }
+    }
+    /// Clone a thread: it shares the actions, and has the blocked set
+    pub fn clone_thread(&self) -> Self {
+        Signals {
+            pending: SigSet::default(),
+            blocked: self.blocked,
+            actions: self.actions.clone(),
+        }
+    }
+    /// Exec: handlers are reset to default, as they are gone with the memory
+    pub fn exec(&mut self) {
+        for action in self.actions.lock().iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+    }
+    /// Is `sig` discarded when sent ? Continuing by default is done at once when sent.
+    fn is_ignored(&self, sig: usize) -> bool {
+        match self.action(sig).handler {
+            SIG_IGN => true,
+            SIG_DFL => match default_action(sig) {
+                DefaultAction::Ignore | DefaultAction::Continue => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+    /// Is there a pending signal not blocked ?
+    fn has_ready(&self) -> bool {
+        self.pending.0 & !self.blocked.0 != 0
+    }
+    /// Take a pending signal not blocked
+    fn take(&mut self) -> Option<usize> {
+        let ready = self.pending.0 & !self.blocked.0;
+        match ready {
+            0 => None,
+            _ => {
+                let sig = ready.trailing_zeros() as usize;
+                self.pending.remove(sig);
+                Some(sig)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+fn default_action(sig: usize) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+pub fn is_valid(sig: usize) -> bool {
+    0 < sig && sig < NSIG
+}
+
+/// Saved on the user stack when a handler is called
+#[repr(C)]
+struct SignalFrame {
+    tf: TrapFrame,
+    /// Blocked set before the handler
+    blocked: SigSet,
+}
+
+const STOP_SIGNALS: [usize; 4] = [SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU];
+
+/// May the user send `sig` to `pid` ? Not to the kernel threads, and not to init
+/// unless it handles `sig`, or an existence check by signal 0. False if `pid` does not exist.
+pub fn permitted(pid: Pid, sig: usize) -> bool {
+    let mut processor = processor();
+    let init = processor.group(pid) == Some(processor.init_pid());
+    match processor.context_mut(pid) {
+        Some(context) if context.is_kernel() => false,
+        Some(_) if !init || sig == 0 => true,
+        Some(context) => {
+            let handler = context.signals_mut().action(sig).handler;
+            handler != SIG_DFL && handler != SIG_IGN
+        }
+        None => false,
+    }
+}
+
+/// Send `sig` to `pid`, return false if it does not exist
+pub fn send(pid: Pid, sig: usize) -> bool {
+    assert!(is_valid(sig));
+    let terminate = {
+        let mut processor = processor();
+        let terminate = {
+            let signals = match processor.context_mut(pid) {
+                Some(context) => context.signals_mut(),
+                None => return false,
+            };
+            if STOP_SIGNALS.contains(&sig) {
+                signals.pending.remove(SIGCONT);
+            }
+            let action = signals.action(sig);
+            if sig == SIGKILL || (action.handler == SIG_DFL && !signals.blocked.contains(sig)
+                && default_action(sig) == DefaultAction::Terminate) {
+                true
+            } else {
+                if !signals.is_ignored(sig) {
+                    signals.pending.add(sig);
+                }
+                false
+            }
+        };
+        // Continue all threads, including the ones going to stop
+        if sig == SIGCONT {
+            for tid in processor.group_members(pid) {
+                {
+                    let signals = processor.context_mut(tid).unwrap().signals_mut();
+                    for &stop in STOP_SIGNALS.iter() {
+                        signals.pending.remove(stop);
+                    }
+                }
+                processor.cont(tid);
+            }
+        }
+        terminate
+    };
+    if terminate {
+        exit_group(pid, KILLED | sig);
+    }
+    true
+}
+
+/// Send `sig` to every process in the process group `pgid`, return false if the group is empty
+pub fn send_pgroup(pgid: Pid, sig: usize) -> bool {
+    let members = processor().pgroup_members(pgid);
+    for &pid in members.iter() {
+        send(pid, sig);
+    }
+    !members.is_empty()
+}
+
+/// Should a blocking syscall of the current process return early ?
+/// It is true if the process is killed, or has a signal to deliver.
+pub fn interrupted() -> bool {
+    let mut processor = processor();
+    let pid = processor.current_pid();
+    processor.exit_code(pid).is_some() || processor.current_context_mut().signals_mut().has_ready()
+}
+
+/// Send `sig` to the current process for a fault of it.
+/// It can not be blocked or ignored, or the fault happens again.
+pub fn force(sig: usize) {
+    let mut processor = processor();
+    let signals = processor.current_context_mut().signals_mut();
+    signals.blocked.remove(sig);
+    {
+        let mut actions = signals.actions.lock();
+        if actions[sig].handler == SIG_IGN {
+            actions[sig] = SigAction::default();
+        }
+    }
+    signals.pending.add(sig);
+}
+
+/// Exit the current process killed by `sig`
+pub fn exit_by(sig: usize) -> ! {
+    let pid = processor().current_pid();
+    exit_group(pid, KILLED | sig);
+    processor().schedule();
+    unreachable!();
+}
+
+/// Deliver the pending signals of the current process, before it returns to the user by `tf`
+pub fn handle(tf: &mut TrapFrame) {
+    loop {
+        let (sig, action, blocked) = {
+            let mut processor = processor();
+            let signals = processor.current_context_mut().signals_mut();
+            let sig = match signals.take() {
+                Some(sig) => sig,
+                None => return,
+            };
+            let action = signals.action(sig);
+            let blocked = signals.blocked;
+            if action.handler != SIG_DFL && action.handler != SIG_IGN {
+                let mut mask = action.mask;
+                if action.flags & SA_NODEFER == 0 {
+                    mask.add(sig);
+                }
+                signals.set_blocked(SigSet(blocked.0 | mask.0));
+                if action.flags & SA_RESETHAND != 0 {
+                    signals.actions.lock()[sig] = SigAction::default();
+                }
+            }
+            (sig, action, blocked)
+        };
+        info!("signal {} delivered, action: {:x?}", sig, action);
+        match action.handler {
+            SIG_IGN => {}
+            SIG_DFL => match default_action(sig) {
+                DefaultAction::Terminate => exit_by(sig),
+                DefaultAction::Stop => stop(sig),
+                DefaultAction::Ignore | DefaultAction::Continue => {}
+            },
+            _ => {
+                if setup_frame(tf, sig, &action, blocked).is_err() {
+                    exit_by(SIGSEGV);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Stop the current thread by `sig`, the other threads stop when they return to the user
+fn stop(sig: usize) {
+    let mut processor = processor();
+    let pid = processor.current_pid();
+    for tid in processor.group_members(pid).into_iter().filter(|&tid| tid != pid) {
+        processor.context_mut(tid).unwrap().signals_mut().pending.add(sig);
+    }
+    processor.stop(pid, sig);
+    processor.schedule();
+}
+
+/// Save `tf` on the user stack, and let it return to the handler
+fn setup_frame(tf: &mut TrapFrame, sig: usize, action: &SigAction, blocked: SigSet) -> Result<(), user::Fault> {
+    let frame_addr = (tf.get_sp().wrapping_sub(RED_ZONE + size_of::<SignalFrame>())) & !0xf;
+    user::write_user(frame_addr as *mut SignalFrame, SignalFrame { tf: tf.clone(), blocked })?;
+    // The handler is called with the restorer as its return address
+    #[cfg(target_arch = "x86_64")]
+    let sp = {
+        let sp = frame_addr.wrapping_sub(size_of::<usize>());
+        user::write_user(sp as *mut usize, action.restorer)?;
+        sp
+    };
+    #[cfg(target_arch = "riscv32")]
+    let sp = frame_addr;
+    tf.set_signal_handler(action.handler, sig, sp, action.restorer);
+    Ok(())
+}
+
+/// Return from a handler by the frame at the user stack pointer of `tf`.
+/// Return the restored value of the return value register.
+pub fn sigreturn(tf: &mut TrapFrame) -> Result<usize, user::Fault> {
+    let frame_addr = tf.get_sp();
+    let data = user::copy_from_user(frame_addr as *const u8, size_of::<SignalFrame>())?;
+    let frame = unsafe { ptr::read_unaligned(data.as_ptr() as *const SignalFrame) };
+    tf.restore_user(&frame.tf);
+    processor().current_context_mut().signals_mut().set_blocked(frame.blocked);
+    Ok(tf.get_ret())
+}
@@ -0,0 +1,63 @@
+//! Tests of kernel threads
+
+use alloc::string::String;
+use core::cell::RefCell;
+use core::time::Duration;
+use super::thread_::LocalKey;
+use thread;
+
+pub fn unpack() {
+    let parked_thread = thread::spawn(|| {
+        println!("Parking thread");
+        thread::park();
+        println!("Thread unparked");
+        5
+    });
+
+    // Let some time pass for the thread to be spawned.
+    thread::sleep(Duration::from_secs(2));
+
+    println!("Unpark the thread");
+    parked_thread.thread().unpark();
+
+    let ret = parked_thread.join().unwrap();
+    assert_eq!(ret, 5);
+}
+
+pub fn local_key() {
+    static FOO: LocalKey<RefCell<usize>> = LocalKey::new(|| RefCell::new(1));
+
+    FOO.with(|f| {
+        assert_eq!(*f.borrow(), 1);
+        *f.borrow_mut() = 2;
+    });
+
+    // each thread starts out with the initial value of 1
+    thread::spawn(move || {
+        FOO.with(|f| {
+            assert_eq!(*f.borrow(), 1);
+            *f.borrow_mut() = 3;
+        });
+    }).join().unwrap();
+
+    // we retain our original value of 2 despite the child thread
+    FOO.with(|f| {
+        assert_eq!(*f.borrow(), 2);
+    });
+    println!("local key success");
+}
+
+pub fn join_panic() {
+    let h = thread::spawn(|| {
+        if true {
+            panic!("worker {} failed", 1);
+        }
+        0
+    });
+    let payload = h.join().unwrap_err();
+    assert_eq!(payload.downcast_ref::<String>().map(|s| s.as_str()), Some("worker 1 failed"));
+
+    // The others keep working
+    assert_eq!(thread::spawn(|| 1).join().unwrap(), 1);
+    println!("join panic success");
+}
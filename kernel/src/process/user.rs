@@ -0,0 +1,91 @@
+//! Access user memory in syscalls
+//!
+//! Addresses are checked against the memory areas of the current process.
+//! Lazy and swapped pages are mapped by page fault when touched,
+//! so the processor must not be locked when calling these functions.
+
+use alloc::{string::String, vec::Vec};
+use core::{mem::{align_of, size_of}, ptr, slice};
+use super::processor;
+use ucore_memory::PAGE_SIZE;
+
+/// Max length of strings from user, including the ending NUL
+const MAX_STR_LEN: usize = 4096;
+
+/// Bad address of user memory
+#[derive(Debug)]
+pub struct Fault;
+
+fn check(addr: usize, len: usize, write: bool) -> Result<(), Fault> {
+    match processor().current_context().check_user_range(addr, len, write) {
+        true => Ok(()),
+        false => Err(Fault),
+    }
+}
+
+/// Check `[ptr, ptr + len)` is writable before doing something can not be undone
+pub fn check_write(ptr: *mut u8, len: usize) -> Result<(), Fault> {
+    check(ptr as usize, len, true)
+}
+
+/// Copy `len` bytes at `ptr` to the kernel
+pub fn copy_from_user(ptr: *const u8, len: usize) -> Result<Vec<u8>, Fault> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    check(ptr as usize, len, false)?;
+    Ok(Vec::from(unsafe { slice::from_raw_parts(ptr, len) }))
+}
+
+/// Copy `data` to `ptr` in user memory
+pub fn copy_to_user(ptr: *mut u8, data: &[u8]) -> Result<(), Fault> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    check(ptr as usize, data.len(), true)?;
+    unsafe { slice::from_raw_parts_mut(ptr, data.len()) }.copy_from_slice(data);
+    Ok(())
+}
+
+/// Read a value at `ptr`, which must be aligned
+pub fn read_user<T: Copy>(ptr: *const T) -> Result<T, Fault> {
+    if ptr as usize % align_of::<T>() != 0 {
+        return Err(Fault);
+    }
+    check(ptr as usize, size_of::<T>(), false)?;
+    Ok(unsafe { ptr::read(ptr) })
+}
+
+/// Write `value` to `ptr`, which must be aligned
+pub fn write_user<T>(ptr: *mut T, value: T) -> Result<(), Fault> {
+    if ptr as usize % align_of::<T>() != 0 {
+        return Err(Fault);
+    }
+    check(ptr as usize, size_of::<T>(), true)?;
+    unsafe { ptr::write(ptr, value) };
+    Ok(())
+}
+
+/// Copy the NUL-terminated UTF-8 string at `ptr` to the kernel
+pub fn copy_str_from_user(ptr: *const u8) -> Result<String, Fault> {
+    let mut bytes = Vec::new();
+    let mut addr = ptr as usize;
+    loop {
+        // Check a page at a time, the string may end before the next area
+        let page_end = (addr / PAGE_SIZE + 1).checked_mul(PAGE_SIZE).ok_or(Fault)?;
+        check(addr, page_end - addr, false)?;
+        let chunk = unsafe { slice::from_raw_parts(addr as *const u8, page_end - addr) };
+        match chunk.iter().position(|&c| c == 0) {
+            Some(len) => {
+                bytes.extend_from_slice(&chunk[..len]);
+                break;
+            }
+            None => bytes.extend_from_slice(chunk),
+        }
+        if bytes.len() >= MAX_STR_LEN {
+            return Err(Fault);
+        }
+        addr = page_end;
+    }
+    String::from_utf8(bytes).map_err(|_| Fault)
+}
@@ -1,37 +1,89 @@
-use alloc::collections::VecDeque;
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 use super::*;
 use thread;
 use thread_;
 
-#[derive(Default)]
 pub struct Condvar {
-    wait_queue: SpinNoIrqLock<VecDeque<thread_::Thread>>,
+    wait_queue: SpinNoIrqLock<VecDeque<Waiter>>,
+}
+
+/// A thread in the wait queue, marked when a notification takes it out
+struct Waiter {
+    thread: thread_::Thread,
+    notified: Arc<AtomicBool>,
+}
+
+impl Waiter {
+    fn current() -> Self {
+        Waiter { thread: thread::current(), notified: Arc::new(AtomicBool::new(false)) }
+    }
+    fn notify(self) {
+        self.notified.store(true, Ordering::Relaxed);
+        self.thread.unpark();
+    }
+}
+
+static WAIT_QUEUE_CLASS: LockClass = LockClass::new("Condvar::wait_queue");
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
 }
 
 impl Condvar {
     pub fn new() -> Self {
-        Condvar::default()
+        Condvar {
+            wait_queue: SpinNoIrqLock::new_with_class(VecDeque::new(), &WAIT_QUEUE_CLASS),
+        }
     }
     pub fn _wait(&self) {
-        self.wait_queue.lock().push_back(thread::current());
+        self.wait_queue.lock().push_back(Waiter::current());
         thread::park();
     }
+    /// Release `guard` and wait until notified, then lock it again.
+    ///
+    /// Enqueued and blocked before releasing it,
+    /// so a notification by the holder next is not lost.
     pub fn wait<'a, T, S>(&self, guard: MutexGuard<'a, T, S>) -> MutexGuard<'a, T, S>
         where S: MutexSupport
     {
         let mutex = guard.mutex;
-        drop(guard);
-        self._wait();
+        self.wait_queue.lock().push_back(Waiter::current());
+        thread::park_then(None, || drop(guard));
         mutex.lock()
     }
+    /// Wait like `wait` for at most `dur`, the second returned is true if timed out.
+    /// A notification coming after the timeout but before it leaves the queue still wins.
+    pub fn wait_timeout<'a, T, S>(&self, guard: MutexGuard<'a, T, S>, dur: Duration) -> (MutexGuard<'a, T, S>, bool)
+        where S: MutexSupport
+    {
+        let mutex = guard.mutex;
+        let waiter = Waiter::current();
+        let notified = waiter.notified.clone();
+        self.wait_queue.lock().push_back(waiter);
+        thread::park_then(Some(dur), || drop(guard));
+        // Leave the queue and check the mark under its lock, so no notification slips in between
+        let timed_out = {
+            let mut queue = self.wait_queue.lock();
+            let position = queue.iter().position(|w| Arc::ptr_eq(&w.notified, &notified));
+            if let Some(i) = position {
+                queue.remove(i);
+            }
+            !notified.load(Ordering::Relaxed)
+        };
+        (mutex.lock(), timed_out)
+    }
     pub fn notify_one(&self) {
-        if let Some(t) = self.wait_queue.lock().pop_front() {
-            t.unpark();
+        if let Some(waiter) = self.wait_queue.lock().pop_front() {
+            waiter.notify();
         }
     }
     pub fn notify_all(&self) {
-        while let Some(t) = self.wait_queue.lock().pop_front() {
-            t.unpark();
+        while let Some(waiter) = self.wait_queue.lock().pop_front() {
+            waiter.notify();
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,272 @@
+//! Lock dependency validator, enabled by the feature `lockdep`
+//!
+//! Every `Mutex` is in the `LockClass` given to `new_with_class`, a static shared by the
+//! locks of a declaration, or else a class by its own address. Taking a lock while holding others adds the dependencies from their
+//! classes to its class, and a dependency closing a cycle is a possible deadlock,
+//! reported before spinning on it.
+//! The locks held are recorded per CPU, swapped with the thread on context switch,
+//! and the ones taken by an interrupt handler are apart from the ones it interrupts.
+//!
+//! Besides deadlocks, it reports:
+//! * a lock which may sleep (`ThreadLock`) taken in an interrupt handler
+//! * a `SpinLock` taken with interrupts enabled, and also in an interrupt handler,
+//!   which deadlocks if the interrupt comes while holding it
+//!
+//! Call sites are return addresses of `lock()`, find them by `make asm` or `addr2line`.
+//! Reports are printed once, and nothing stops.
+
+use alloc::{collections::{BTreeMap, BTreeSet}, vec::Vec};
+use arch::{cpu, interrupt};
+use consts::MAX_CPU_NUM;
+use spin::{Mutex, Once};
+
+extern {
+    /// The return address of the function calling it with `level` 0
+    #[link_name = "llvm.returnaddress"]
+    pub fn return_address(level: i32) -> *const u8;
+}
+
+/// A lock held
+#[derive(Debug, Clone)]
+struct Held {
+    class: usize,
+    name: &'static str,
+    site: usize,
+}
+
+#[derive(Default)]
+struct CpuState {
+    /// Locks held by the current thread, then by nested interrupt handlers
+    held: Vec<Held>,
+    /// Where the locks of each nested interrupt handler start in `held`
+    irq_bases: Vec<usize>,
+    /// Working or reporting. Locks taken meanwhile are not tracked.
+    busy: bool,
+}
+
+impl CpuState {
+    /// Locks held by the current context, the innermost interrupt handler or the thread
+    fn context(&self) -> &[Held] {
+        &self.held[*self.irq_bases.last().unwrap_or(&0)..]
+    }
+}
+
+struct Class {
+    name: &'static str,
+    /// The first sites taking it out of interrupt handlers, with interrupts enabled, and in one
+    task_site: Option<usize>,
+    irq_enabled_site: Option<usize>,
+    irq_site: Option<usize>,
+    reported: bool,
+}
+
+#[derive(Default)]
+struct Graph {
+    classes: BTreeMap<usize, Class>,
+    /// `deps[a][b]` is the sites taking `a` then `b` while holding `a`
+    deps: BTreeMap<usize, BTreeMap<usize, (usize, usize)>>,
+    /// Locks held by the threads switched out, by their contexts
+    switched_out: BTreeMap<usize, Vec<Held>>,
+}
+
+impl Graph {
+    fn name(&self, class: usize) -> &'static str {
+        self.classes.get(&class).map_or("?", |c| c.name)
+    }
+
+    /// A path of dependencies from `from` to `to`, as (class, site, class, site)
+    fn path(&self, from: usize, to: usize) -> Option<Vec<(usize, usize, usize, usize)>> {
+        let mut visited = BTreeSet::new();
+        let mut path = Vec::new();
+        match self.dfs(from, to, &mut visited, &mut path) {
+            true => Some(path),
+            false => None,
+        }
+    }
+
+    fn dfs(&self, from: usize, to: usize, visited: &mut BTreeSet<usize>, path: &mut Vec<(usize, usize, usize, usize)>) -> bool {
+        if from == to {
+            return true;
+        }
+        if !visited.insert(from) {
+            return false;
+        }
+        let deps = match self.deps.get(&from) {
+            Some(deps) => deps,
+            None => return false,
+        };
+        for (&next, &(from_site, next_site)) in deps.iter() {
+            path.push((from, from_site, next, next_site));
+            if self.dfs(next, to, visited, path) {
+                return true;
+            }
+            path.pop();
+        }
+        false
+    }
+}
+
+struct Lockdep {
+    cpus: Vec<Mutex<CpuState>>,
+    graph: Mutex<Graph>,
+}
+
+static LOCKDEP: Once<Lockdep> = Once::new();
+
+/// Start tracking, once the heap is available
+pub fn init() {
+    LOCKDEP.call_once(|| Lockdep {
+        cpus: (0..MAX_CPU_NUM).map(|_| Mutex::new(CpuState::default())).collect(),
+        graph: Mutex::new(Graph::default()),
+    });
+    info!("lockdep: init end");
+}
+
+/// Run `f` with the state of this CPU, unless it is busy or not initialized.
+/// Interrupts are disabled meanwhile, and locks taken by `f` are not tracked.
+fn with_cpu(f: impl FnOnce(&Lockdep, &Mutex<CpuState>)) {
+    let lockdep = match LOCKDEP.try() {
+        Some(lockdep) => lockdep,
+        None => return,
+    };
+    let flags = unsafe { interrupt::disable_and_store() };
+    let state = &lockdep.cpus[cpu::id()];
+    let busy = {
+        let mut state = state.lock();
+        let busy = state.busy;
+        state.busy = true;
+        busy
+    };
+    if !busy {
+        f(lockdep, state);
+        state.lock().busy = false;
+    }
+    unsafe { interrupt::restore(flags) };
+}
+
+/// Called before taking the lock `class` named `name` at `site`.
+/// `irq_safe` if holding it keeps interrupts disabled, `may_sleep` if it sleeps on contention.
+/// A `try_lock` does not wait, so it depends on nothing.
+pub fn acquire(class: usize, name: &'static str, irq_safe: bool, may_sleep: bool, trylock: bool, site: usize) {
+    // Read before disabled by `with_cpu`
+    let irq_enabled = interrupt::enabled();
+    with_cpu(|lockdep, state| {
+        let (held, in_irq) = {
+            let state = state.lock();
+            (state.context().to_vec(), !state.irq_bases.is_empty())
+        };
+        let mut graph = lockdep.graph.lock();
+        if !trylock {
+            for prev in held.iter() {
+                add_dep(&mut graph, prev, class, name, site);
+            }
+        }
+        check_irq(&mut graph, class, name, irq_safe, may_sleep, in_irq, irq_enabled, site);
+        drop(graph);
+        state.lock().held.push(Held { class, name, site });
+    });
+}
+
+/// Called when releasing the lock `class`, or forcing it unlocked
+pub fn release(class: usize) {
+    with_cpu(|_, state| {
+        let mut state = state.lock();
+        // Not recorded if taken before `init`, or while busy
+        if let Some(i) = state.held.iter().rposition(|h| h.class == class) {
+            state.held.remove(i);
+        }
+    });
+}
+
+/// Called by an interrupt handler before handling
+pub fn irq_enter() {
+    with_cpu(|_, state| {
+        let mut state = state.lock();
+        let base = state.held.len();
+        state.irq_bases.push(base);
+    });
+}
+
+/// Called by an interrupt handler after handling
+pub fn irq_exit() {
+    with_cpu(|_, state| {
+        let mut state = state.lock();
+        if let Some(base) = state.irq_bases.pop() {
+            if state.held.len() > base {
+                error!("lockdep: interrupt handler returns holding {:#x?}", &state.held[base..]);
+                state.held.truncate(base);
+            }
+        }
+    });
+}
+
+/// Called before switching out of the thread of `context`, take its locks away from this CPU
+pub fn switch_out(context: usize) {
+    with_cpu(|lockdep, state| {
+        let held = ::core::mem::replace(&mut state.lock().held, Vec::new());
+        if !held.is_empty() {
+            lockdep.graph.lock().switched_out.insert(context, held);
+        }
+    });
+}
+
+/// Called after switching back to the thread of `context`, bring its locks back
+pub fn switch_in(context: usize) {
+    with_cpu(|lockdep, state| {
+        let held = lockdep.graph.lock().switched_out.remove(&context);
+        if let Some(held) = held {
+            state.lock().held = held;
+        }
+    });
+}
+
+/// Add the dependency `prev` -> `class`, report if it makes a cycle
+fn add_dep(graph: &mut Graph, prev: &Held, class: usize, name: &'static str, site: usize) {
+    let (from, to) = (prev.class, class);
+    if from == to {
+        error!("lockdep: lock {} taken at {:#x} again at {:#x}, it may deadlock", name, prev.site, site);
+        return;
+    }
+    if graph.deps.get(&from).map_or(false, |deps| deps.contains_key(&to)) {
+        return;
+    }
+    if let Some(path) = graph.path(to, from) {
+        error!("lockdep: possible deadlock: lock {} taken at {:#x}, then lock {} at {:#x}",
+               prev.name, prev.site, name, site);
+        for (a, a_site, b, b_site) in path {
+            error!("lockdep:   while elsewhere lock {} taken at {:#x}, then lock {} at {:#x}",
+                   graph.name(a), a_site, graph.name(b), b_site);
+        }
+    }
+    graph.deps.entry(from).or_insert_with(BTreeMap::new).insert(to, (prev.site, site));
+}
+
+/// Record where `class` is taken about interrupts, report if it may deadlock with an interrupt
+fn check_irq(graph: &mut Graph, class: usize, name: &'static str, irq_safe: bool, may_sleep: bool, in_irq: bool, irq_enabled: bool, site: usize) {
+    let c = graph.classes.entry(class).or_insert_with(|| Class {
+        name,
+        task_site: None,
+        irq_enabled_site: None,
+        irq_site: None,
+        reported: false,
+    });
+    match in_irq {
+        true => { c.irq_site.get_or_insert(site); }
+        false => { c.task_site.get_or_insert(site); }
+    }
+    if !in_irq && irq_enabled {
+        c.irq_enabled_site.get_or_insert(site);
+    }
+    if c.reported || irq_safe {
+        return;
+    }
+    if in_irq && may_sleep {
+        c.reported = true;
+        error!("lockdep: lock {} may sleep, but taken in an interrupt handler at {:#x}, and by a thread at {:#x?}",
+               name, site, c.task_site);
+    } else if let (Some(enabled_site), Some(irq_site)) = (c.irq_enabled_site, c.irq_site) {
+        c.reported = true;
+        error!("lockdep: spin lock {} taken with interrupts enabled at {:#x}, and in an interrupt handler at {:#x}",
+               name, enabled_site, irq_site);
+    }
+}
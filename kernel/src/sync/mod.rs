@@ -20,6 +20,9 @@
 //! * `mpsc`: 消息传递通道。
 //!     多生产者-单消费者的FIFO队列。用于在线程间传递数据。
 //!
+//! * `lockdep`: 锁依赖检查，由feature `lockdep`开启。
+//!     记录各锁的获取顺序，报告可能的死锁，以及在中断中误用的锁。
+//!
 //! * `test`: 测试。
 //!     目前分别用`Mutex`和`Condvar`(Monitor)实现了哲学家就餐问题。
 //!
@@ -58,3 +61,5 @@ mod condvar;
 mod semaphore;
 pub mod mpsc;
 pub mod test;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
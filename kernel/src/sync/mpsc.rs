@@ -1,5 +1,5 @@
 use alloc::{sync::Arc, sync::Weak, collections::VecDeque};
-use super::Condvar;
+use super::{Condvar, LockClass};
 use super::SpinLock as Mutex;
 
 struct Channel<T> {
@@ -7,10 +7,12 @@ struct Channel<T> {
     pushed: Condvar,
 }
 
+static DEQUE_CLASS: LockClass = LockClass::new("Channel::deque");
+
 impl<T> Default for Channel<T> {
     fn default() -> Self {
         Channel {
-            deque: Mutex::<_>::default(),
+            deque: Mutex::new_with_class(VecDeque::new(), &DEQUE_CLASS),
             pushed: Condvar::default(),
         }
     }
@@ -19,31 +19,53 @@
 //! * `ThreadLock`: 线程调度锁。
 //!     等价于`std::sync::Mutex`，依赖于`thread`模块提供线程调度支持。
 //!     在获取锁失败时，将自己加入等待队列，让出CPU；在解锁时，唤醒一个等待队列中的线程。
+//!     记录持有者，等待时将自己的实时优先级借给持有者（优先级继承），避免优先级反转。
 //!
 //! # 实现方法
 //!
 //! 由一个struct提供底层支持，它impl trait `MutexSupport`，并嵌入`Mutex`中。
 //! `MutexSupport`提供了若干接口，它们会在操作锁的不同时间点被调用。
 //! 注意这个接口实际是取了几种实现的并集，并不是很通用。
+//!
+//! 用`new_with_class`创建的锁属于给出的`LockClass`，同一处声明的锁共用一个，lockdep按类记录依赖；
+//! 用`new`创建的锁自成一类。
 
 use arch::interrupt;
 use core::cell::UnsafeCell;
 use core::fmt;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
+use core::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, AtomicUsize, Ordering};
+use process::processor;
 use super::Condvar;
+#[cfg(feature = "lockdep")]
+use super::lockdep;
 
 pub type SpinLock<T> = Mutex<T, Spin>;
 pub type SpinNoIrqLock<T> = Mutex<T, SpinNoIrq>;
-pub type ThreadLock<T> = Mutex<T, Condvar>;
+pub type ThreadLock<T> = Mutex<T, PriorityInherit>;
 
 pub struct Mutex<T: ?Sized, S: MutexSupport>
 {
     lock: AtomicBool,
     support: S,
+    #[cfg_attr(not(feature = "lockdep"), allow(dead_code))]
+    class: Option<&'static LockClass>,
     data: UnsafeCell<T>,
 }
 
+/// The class of a lock for lockdep, shared by all locks of a declaration,
+/// such as the `vm` of every process. It is keyed by its address, so it must be a `static`.
+#[derive(Debug)]
+pub struct LockClass {
+    pub name: &'static str,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> Self {
+        LockClass { name }
+    }
+}
+
 /// A guard to which the protected data can be accessed
 ///
 /// When the guard falls out of scope it will release the lock.
@@ -81,6 +103,24 @@ impl<T, S: MutexSupport> Mutex<T, S>
             lock: ATOMIC_BOOL_INIT,
             data: UnsafeCell::new(user_data),
             support: S::new(),
+            class: None,
+        }
+    }
+
+    /// Creates a new lock wrapping the supplied data, in the lock class `class`.
+    ///
+    /// ```
+    /// static FOO_CLASS: LockClass = LockClass::new("foo");
+    ///
+    /// fn demo() {
+    ///     let foo = SpinLock::new_with_class(0, &FOO_CLASS);
+    ///     *foo.lock() += 1;
+    /// }
+    /// ```
+    pub fn new_with_class(user_data: T, class: &'static LockClass) -> Mutex<T, S> {
+        Mutex {
+            class: Some(class),
+            ..Mutex::new(user_data)
         }
     }
 
@@ -95,6 +135,15 @@ impl<T, S: MutexSupport> Mutex<T, S>
 
 impl<T: ?Sized, S: MutexSupport> Mutex<T, S>
 {
+    /// The lock class of lockdep, its own address if not given
+    #[cfg(feature = "lockdep")]
+    fn class(&self) -> (usize, &'static str) {
+        match self.class {
+            Some(class) => (class as *const LockClass as usize, class.name),
+            None => (self as *const Self as *const u8 as usize, "?"),
+        }
+    }
+
     fn obtain_lock(&self) {
         while self.lock.compare_and_swap(false, true, Ordering::Acquire) != false {
             // Wait until the lock looks unlocked before retrying
@@ -119,10 +168,16 @@ impl<T: ?Sized, S: MutexSupport> Mutex<T, S>
     /// }
     ///
     /// ```
+    #[cfg_attr(feature = "lockdep", inline(never))]
     pub fn lock(&self) -> MutexGuard<T, S>
     {
         let support_guard = S::before_lock();
+        #[cfg(feature = "lockdep")]
+        let (class, name) = self.class();
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(class, name, S::IRQ_SAFE, S::MAY_SLEEP, false, unsafe { lockdep::return_address(0) } as usize);
         self.obtain_lock();
+        self.support.after_lock();
         MutexGuard {
             mutex: self,
             support_guard,
@@ -137,14 +192,22 @@ impl<T: ?Sized, S: MutexSupport> Mutex<T, S>
     ///
     /// If the lock isn't held, this is a no-op.
     pub unsafe fn force_unlock(&self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.class().0);
         self.lock.store(false, Ordering::Release);
     }
 
     /// Tries to lock the mutex. If it is already locked, it will return None. Otherwise it returns
     /// a guard within Some.
+    #[cfg_attr(feature = "lockdep", inline(never))]
     pub fn try_lock(&self) -> Option<MutexGuard<T, S>> {
         let support_guard = S::before_lock();
         if self.lock.compare_and_swap(false, true, Ordering::Acquire) == false {
+            #[cfg(feature = "lockdep")]
+            let (class, name) = self.class();
+            #[cfg(feature = "lockdep")]
+            lockdep::acquire(class, name, S::IRQ_SAFE, S::MAY_SLEEP, true, unsafe { lockdep::return_address(0) } as usize);
+            self.support.after_lock();
             Some(MutexGuard {
                 mutex: self,
                 support_guard,
@@ -186,6 +249,8 @@ impl<'a, T: ?Sized, S: MutexSupport> Drop for MutexGuard<'a, T, S>
 {
     /// The dropping of the MutexGuard will release the lock it was created from.
     fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.mutex.class().0);
         self.mutex.lock.store(false, Ordering::Release);
         self.mutex.support.after_unlock();
     }
@@ -194,11 +259,17 @@ impl<'a, T: ?Sized, S: MutexSupport> Drop for MutexGuard<'a, T, S>
 /// Low-level support for mutex
 pub trait MutexSupport {
     type GuardData;
+    /// Are interrupts disabled while holding it ? For lockdep
+    const IRQ_SAFE: bool = false;
+    /// Does it sleep when failing to acquire ? For lockdep
+    const MAY_SLEEP: bool = false;
     fn new() -> Self;
     /// Called when failing to acquire the lock
     fn cpu_relax(&self);
     /// Called before lock() & try_lock()
     fn before_lock() -> Self::GuardData;
+    /// Called when lock() & try_lock() got the lock
+    fn after_lock(&self);
     /// Called when MutexGuard dropping
     fn after_unlock(&self);
 }
@@ -220,6 +291,7 @@ impl MutexSupport for Spin {
         }
     }
     fn before_lock() -> Self::GuardData {}
+    fn after_lock(&self) {}
     fn after_unlock(&self) {}
 }
 
@@ -238,10 +310,14 @@ impl Drop for FlagsGuard {
 
 impl MutexSupport for SpinNoIrq {
     type GuardData = FlagsGuard;
+    const IRQ_SAFE: bool = true;
     fn new() -> Self {
         SpinNoIrq
     }
     fn cpu_relax(&self) {
+        // The holder may be waiting for this CPU to take a TLB shootdown
+        #[cfg(target_arch = "x86_64")]
+        ::arch::paging::handle_shootdown();
         unsafe {
             #[cfg(target_arch = "x86_64")]
                 asm!("pause" :::: "volatile");
@@ -252,11 +328,13 @@ impl MutexSupport for SpinNoIrq {
     fn before_lock() -> Self::GuardData {
         FlagsGuard(unsafe { interrupt::disable_and_store() })
     }
+    fn after_lock(&self) {}
     fn after_unlock(&self) {}
 }
 
 impl MutexSupport for Condvar {
     type GuardData = ();
+    const MAY_SLEEP: bool = true;
     fn new() -> Self {
         Condvar::new()
     }
@@ -264,7 +342,57 @@ impl MutexSupport for Condvar {
         self._wait();
     }
     fn before_lock() -> Self::GuardData {}
+    fn after_lock(&self) {}
     fn after_unlock(&self) {
         self.notify_one();
     }
+}
+
+/// Thread lock with priority inheritance
+///
+/// Waiters sleep in a `Condvar`, lending their real-time priorities to the owner,
+/// so a low-priority owner can not block a high-priority waiter forever.
+#[derive(Default)]
+pub struct PriorityInherit {
+    condvar: Condvar,
+    /// Pid of the owner plus 1, 0 if unlocked
+    owner: AtomicUsize,
+}
+
+impl PriorityInherit {
+    /// Identify the lock to the processor
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+impl MutexSupport for PriorityInherit {
+    type GuardData = ();
+    const MAY_SLEEP: bool = true;
+    fn new() -> Self {
+        PriorityInherit::default()
+    }
+    fn cpu_relax(&self) {
+        let owner = self.owner.load(Ordering::Relaxed);
+        if owner != 0 {
+            processor().lend_priority(owner - 1, self.id());
+        }
+        self.condvar._wait();
+    }
+    fn before_lock() -> Self::GuardData {}
+    fn after_lock(&self) {
+        let mut processor = processor();
+        self.owner.store(processor.current_pid() + 1, Ordering::Relaxed);
+        processor.stop_lending();
+    }
+    fn after_unlock(&self) {
+        {
+            let mut processor = processor();
+            // It may have been taken by another one since unlocked
+            let pid = processor.current_pid();
+            self.owner.compare_and_swap(pid + 1, 0, Ordering::Relaxed);
+            processor.release(self.id());
+        }
+        self.condvar.notify_one();
+    }
 }
\ No newline at end of file
@@ -2,7 +2,7 @@
 //!
 //! Same as [std::sync::Semaphore at rust 1.7.0](https://docs.rs/std-semaphore/0.1.0/std_semaphore/)
 
-use super::Condvar;
+use super::{Condvar, LockClass};
 use super::SpinNoIrqLock as Mutex;
 
 /// A counting, blocking, semaphore.
@@ -11,6 +11,8 @@ pub struct Semaphore {
     cvar: Condvar,
 }
 
+static LOCK_CLASS: LockClass = LockClass::new("Semaphore::lock");
+
 /// An RAII guard which will release a resource acquired from a semaphore when
 /// dropped.
 pub struct SemaphoreGuard<'a> {
@@ -25,7 +27,7 @@ impl Semaphore {
     /// available. It is valid to initialize a semaphore with a negative count.
     pub fn new(count: isize) -> Semaphore {
         Semaphore {
-            lock: Mutex::new(count),
+            lock: Mutex::new_with_class(count, &LOCK_CLASS),
             cvar: Condvar::new(),
         }
     }
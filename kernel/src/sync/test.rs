@@ -4,7 +4,7 @@
 
 use alloc::{sync::Arc, vec::Vec};
 use core::time::Duration;
-use sync::Condvar;
+use sync::{Condvar, LockClass};
 use sync::ThreadLock as Mutex;
 use thread;
 
@@ -108,11 +108,18 @@ fn philosopher(table: Arc<Table>) {
     println!("philosophers dining end");
 }
 
+/// A class for each fork, as a philosopher holds two of them
+static FORK_CLASSES: [LockClass; 5] = [
+    LockClass::new("fork 0"), LockClass::new("fork 1"), LockClass::new("fork 2"),
+    LockClass::new("fork 3"), LockClass::new("fork 4"),
+];
+static FORK_STATUS_CLASS: LockClass = LockClass::new("MonitorTable::fork_status");
+
 pub fn philosopher_using_mutex() {
     println!("philosophers using mutex");
 
     let table = Arc::new(MutexTable {
-        forks: vec![Mutex::new(()), Mutex::new(()), Mutex::new(()), Mutex::new(()), Mutex::new(())]
+        forks: FORK_CLASSES.iter().map(|class| Mutex::new_with_class((), class)).collect()
     });
     philosopher(table);
 }
@@ -121,7 +128,7 @@ pub fn philosopher_using_monitor() {
     println!("philosophers using monitor");
 
     let table = Arc::new(MonitorTable {
-        fork_status: Mutex::new(vec![false; 5]),
+        fork_status: Mutex::new_with_class(vec![false; 5], &FORK_STATUS_CLASS),
         fork_condvar: vec![Condvar::new(), Condvar::new(), Condvar::new(), Condvar::new(), Condvar::new()],
     });
     philosopher(table);
@@ -3,127 +3,835 @@
 #![allow(unused)]
 
 use arch::interrupt::TrapFrame;
+use console;
+use fs::{self, File, FileLike, Stat, O_RDONLY};
+use memory::MemoryAttr;
 use process::*;
+use process::signal::{self, SigAction, SigSet};
 use thread;
-use util;
+use time::{self, TimeSpec};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::mem::size_of;
+use sync::{LockClass, SpinNoIrqLock};
 
 /// 系统调用入口点
 ///
 /// 当发生系统调用中断时，中断服务例程将控制权转移到这里。
-pub fn syscall(id: usize, args: [usize; 6], tf: &TrapFrame) -> i32 {
-    match id {
+/// 返回值写入返回值寄存器，错误编码为 `-errno`。
+pub fn syscall(id: usize, args: [usize; 6], tf: &mut TrapFrame) -> isize {
+    let ret = match id {
+        SYS_READ => sys_read(args[0], args[1] as *mut u8, args[2]),
         SYS_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
         SYS_OPEN => sys_open(args[0] as *const u8, args[1]),
         SYS_CLOSE => sys_close(args[0]),
-        SYS_WAIT => sys_wait(args[0], args[1] as *mut i32),
+        SYS_SEEK => sys_seek(args[0], args[1] as isize, args[2]),
+        SYS_FSTAT => sys_fstat(args[0], args[1] as *mut Stat),
+        SYS_GETDIRENTRY => sys_getdirentry(args[0], args[1] as *mut DirEntry),
+        SYS_DUP => sys_dup(args[0], args[1]),
+        SYS_PIPE => sys_pipe(args[0] as *mut i32),
+        SYS_WAIT => sys_wait(args[0], args[1] as *mut i32, args[2]),
         SYS_FORK => sys_fork(tf),
+        SYS_CLONE => sys_clone(args[0], args[1], args[2], tf),
+        SYS_EXEC => sys_exec(args[0] as *const u8, args[1], args[2] as *const usize, tf),
+        SYS_MMAP => sys_mmap(args[0], args[1], args[2], args[3], args[4], args[5]),
+        SYS_MUNMAP => sys_munmap(args[0], args[1]),
+        SYS_SHMEM => sys_shmem(args[0], args[1], args[2], args[3]),
+        SYS_BRK => sys_brk(args[0]),
         SYS_KILL => sys_kill(args[0]),
+        SYS_SIGSEND => sys_sigsend(args[0], args[1]),
+        SYS_SIGACTION => sys_sigaction(args[0], args[1] as *const SigAction, args[2] as *mut SigAction, tf),
+        SYS_SIGPROCMASK => sys_sigprocmask(args[0], args[1] as *const SigSet, args[2] as *mut SigSet),
+        SYS_SIGRETURN => sys_sigreturn(tf),
         SYS_EXIT => sys_exit(args[0]),
+        SYS_EXIT_THREAD => sys_exit_thread(args[0]),
         SYS_YIELD => sys_yield(),
         SYS_GETPID => sys_getpid(),
+        SYS_GETPPID => sys_getppid(),
+        SYS_SETPGID => sys_setpgid(args[0], args[1]),
+        SYS_GETPGID => sys_getpgid(args[0]),
+        SYS_SETSID => sys_setsid(),
+        SYS_GETSID => sys_getsid(args[0]),
+        SYS_TCGETPGRP => sys_tcgetpgrp(args[0]),
+        SYS_TCSETPGRP => sys_tcsetpgrp(args[0], args[1]),
         SYS_SLEEP => sys_sleep(args[0]),
         SYS_GETTIME => sys_get_time(),
+        SYS_CLOCK_GETTIME => sys_clock_gettime(args[0], args[1] as *mut TimeSpec),
+        SYS_NANOSLEEP => sys_nanosleep(args[0] as *const TimeSpec),
+        SYS_SET_SCHEDULER => sys_set_scheduler(args[0]),
+        SYS_SET_SCHED_CLASS => sys_set_sched_class(args[0], args[1], args[2]),
         SYS_LAB6_SET_PRIORITY => sys_lab6_set_priority(args[0]),
         SYS_PUTC => sys_putc(args[0] as u8 as char),
         _ => {
-            error!("unknown syscall id: {:#x?}, args: {:x?}", id, args);
-            ::trap::error(tf);
+            warn!("unknown syscall id: {:#x?}, args: {:x?}", id, args);
+            Err(SysError::ENOSYS)
         }
+    };
+    match ret {
+        Ok(value) => value,
+        Err(err) => -(err as isize),
     }
 }
 
-fn sys_write(fd: usize, base: *const u8, len: usize) -> i32 {
+pub type SysResult = Result<isize, SysError>;
+
+/// Error numbers of syscalls, the same as Linux
+#[repr(isize)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SysError {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EEXIST = 17,
+    EINVAL = 22,
+    ENOTTY = 25,
+    ESPIPE = 29,
+    EPIPE = 32,
+    ENOSYS = 38,
+}
+
+impl From<user::Fault> for SysError {
+    fn from(_: user::Fault) -> Self {
+        SysError::EFAULT
+    }
+}
+
+/// Read at most `len` bytes by pieces of a page, so that the kernel buffer is bounded.
+/// A file is read on to `len` or its end, others return the data available.
+fn sys_read(fd: usize, base: *mut u8, len: usize) -> SysResult {
+    use ucore_memory::PAGE_SIZE;
+    info!("read: fd: {}, base: {:?}, len: {:#x}", fd, base, len);
+    let file = get_file(fd)?;
+    // Check the buffer before anything is read
+    user::check_write(base, len)?;
+    // Read to a kernel buffer first, no lock is held when touching user memory
+    let mut buf = vec![0u8; len.min(PAGE_SIZE)];
+    let mut read = 0;
+    while read < len {
+        let chunk = (len - read).min(PAGE_SIZE);
+        let n = match file.read(&mut buf[..chunk]) {
+            Some(n) => n,
+            None if read == 0 => return Err(SysError::EBADF),
+            None => break,
+        };
+        // Nothing is read before a signal comes
+        if read == 0 && n == 0 && signal::interrupted() {
+            return Err(SysError::EINTR);
+        }
+        user::copy_to_user(base.wrapping_add(read), &buf[..n])?;
+        read += n;
+        // Don't block for more
+        match file {
+            FileLike::File(_) if n == chunk => {}
+            _ => break,
+        }
+    }
+    Ok(read as isize)
+}
+
+/// Write `len` bytes by pieces of a page, so that the kernel buffer is bounded.
+/// Return a short count if it stops halfway, e.g. by a fault or the read end closed.
+fn sys_write(fd: usize, base: *const u8, len: usize) -> SysResult {
+    use ucore_memory::PAGE_SIZE;
     info!("write: fd: {}, base: {:?}, len: {:#x}", fd, base, len);
-    use core::slice;
-    use core::str;
-    let slice = unsafe { slice::from_raw_parts(base, len) };
-    print!("{}", str::from_utf8(slice).unwrap());
-    0
+    let file = get_file(fd)?;
+    let mut written = 0;
+    while written < len {
+        let chunk = (len - written).min(PAGE_SIZE);
+        let buf = match user::copy_from_user(base.wrapping_add(written), chunk) {
+            Ok(buf) => buf,
+            Err(e) if written == 0 => return Err(e.into()),
+            Err(_) => break,
+        };
+        match file.write(&buf) {
+            Some(0) => break,
+            Some(n) => written += n,
+            None if written > 0 => break,
+            None => return match file {
+                // The read end is closed
+                FileLike::PipeWriter(_) => {
+                    signal::send(thread::current().id(), signal::SIGPIPE);
+                    Err(SysError::EPIPE)
+                }
+                _ => Err(SysError::EBADF),
+            },
+        }
+    }
+    Ok(written as isize)
 }
 
-fn sys_open(path: *const u8, flags: usize) -> i32 {
-    let path = unsafe { util::from_cstr(path) };
+static FILE_CLASS: LockClass = LockClass::new("FileLike::File");
+
+fn sys_open(path: *const u8, flags: usize) -> SysResult {
+    let path = user::copy_str_from_user(path)?;
     info!("open: path: {:?}, flags: {:?}", path, flags);
-    match path {
-        "stdin:" => 0,
-        "stdout:" => 1,
-        _ => -1,
-    }
+    let file = match path.as_str() {
+        "stdin:" => FileLike::Stdin,
+        "stdout:" => FileLike::Stdout,
+        _ => {
+            let file = File::open(&path, flags).ok_or(SysError::ENOENT)?;
+            FileLike::File(Arc::new(SpinNoIrqLock::new_with_class(file, &FILE_CLASS)))
+        }
+    };
+    Ok(processor().current_context_mut().add_file(file) as isize)
 }
 
-fn sys_close(fd: usize) -> i32 {
+fn sys_close(fd: usize) -> SysResult {
     info!("close: fd: {:?}", fd);
-    0
+    // Drop the file after the processor is unlocked
+    let file = processor().current_context_mut().close_file(fd);
+    file.ok_or(SysError::EBADF)?;
+    Ok(0)
+}
+
+fn sys_seek(fd: usize, pos: isize, whence: usize) -> SysResult {
+    info!("seek: fd: {}, pos: {}, whence: {}", fd, pos, whence);
+    match get_file(fd)? {
+        FileLike::File(file) => {
+            file.lock().seek(pos, whence).ok_or(SysError::EINVAL)?;
+            Ok(0)
+        }
+        _ => Err(SysError::ESPIPE),
+    }
+}
+
+fn sys_fstat(fd: usize, stat: *mut Stat) -> SysResult {
+    info!("fstat: fd: {}", fd);
+    match get_file(fd)? {
+        FileLike::File(file) => {
+            let s = file.lock().stat().ok_or(SysError::EIO)?;
+            user::write_user(stat, s)?;
+            Ok(0)
+        }
+        _ => Err(SysError::EBADF),
+    }
+}
+
+/// `struct dirent` of ucore
+#[repr(C)]
+struct DirEntry {
+    /// Bytes consumed by previous calls, the same as ucore
+    offset: u32,
+    name: [u8; DIRENT_NAME_LEN],
+}
+
+const DIRENT_NAME_LEN: usize = 256;
+
+/// Read the next entry of a directory into `entry`.
+fn sys_getdirentry(fd: usize, entry: *mut DirEntry) -> SysResult {
+    info!("getdirentry: fd: {}", fd);
+    let file = match get_file(fd)? {
+        FileLike::File(file) => file,
+        _ => return Err(SysError::EBADF),
+    };
+    user::check_write(entry as *mut u8, size_of::<DirEntry>())?;
+    let offset_ptr = entry as *mut u32;
+    let name_ptr = (entry as *mut u8).wrapping_add(size_of::<u32>());
+    let offset = user::read_user(offset_ptr)?;
+    let size = DIRENT_NAME_LEN as u32;
+    let name = file.lock().get_entry((offset / size) as usize).ok_or(SysError::ENOENT)?;
+    let len = name.len().min(DIRENT_NAME_LEN - 1);
+    let mut buf = Vec::from(&name.as_bytes()[..len]);
+    buf.push(0);
+    user::copy_to_user(name_ptr, &buf)?;
+    user::write_user(offset_ptr, offset + size)?;
+    Ok(0)
+}
+
+/// Make `fd2` refer to the same file as `fd1`. Return `fd2`.
+fn sys_dup(fd1: usize, fd2: usize) -> SysResult {
+    info!("dup: {} -> {}", fd1, fd2);
+    let file = get_file(fd1)?;
+    // Drop the old file after the processor is unlocked
+    let old = processor().current_context_mut().set_file(fd2, file);
+    drop(old);
+    Ok(fd2 as isize)
+}
+
+/// Make a pipe, store the fds of its read end and write end to `fds[0]` and `fds[1]`.
+fn sys_pipe(fds: *mut i32) -> SysResult {
+    info!("pipe: fds: {:?}", fds);
+    user::check_write(fds as *mut u8, 2 * size_of::<i32>())?;
+    let (reader, writer) = fs::pipe();
+    let (fd0, fd1) = {
+        let mut processor = processor();
+        let context = processor.current_context_mut();
+        let fd0 = context.add_file(FileLike::PipeReader(Arc::new(reader)));
+        let fd1 = context.add_file(FileLike::PipeWriter(Arc::new(writer)));
+        (fd0, fd1)
+    };
+    user::write_user(fds, fd0 as i32)?;
+    user::write_user(fds.wrapping_add(1), fd1 as i32)?;
+    Ok(0)
+}
+
+fn get_file(fd: usize) -> Result<FileLike, SysError> {
+    processor().current_context().get_file(fd).ok_or(SysError::EBADF)
 }
 
 /// Fork the current process. Return the child's PID.
-fn sys_fork(tf: &TrapFrame) -> i32 {
+fn sys_fork(tf: &TrapFrame) -> SysResult {
     let mut processor = processor();
-    let context = processor.current_context().fork(tf);
+    let context = processor.current_context_mut().fork(tf);
     let pid = processor.add(context);
     info!("fork: {} -> {}", processor.current_pid(), pid);
-    pid as i32
+    Ok(pid as isize)
+}
+
+// `flags` of `sys_clone`, the same as ucore
+const CLONE_VM: usize = 0x100;
+const CLONE_THREAD: usize = 0x200;
+
+/// Make a thread of the current process, sharing the memory, files and signal actions.
+/// It returns 0 with stack `stack` and thread pointer `tls`. Return its id.
+///
+/// Only threads are supported, `flags` must have `CLONE_VM | CLONE_THREAD`.
+/// On x86_64 the thread pointer is the base of `fs`, `tp` on RISC-V.
+/// `stack` must be 16-byte aligned and in user space, and so must `tls`,
+/// or loading it into `fs` faults in the kernel.
+fn sys_clone(flags: usize, stack: usize, tls: usize, tf: &TrapFrame) -> SysResult {
+    use consts::USER_TOP;
+    info!("clone: flags: {:#x}, stack: {:#x}, tls: {:#x}", flags, stack, tls);
+    if flags & (CLONE_VM | CLONE_THREAD) != CLONE_VM | CLONE_THREAD {
+        return Err(SysError::EINVAL);
+    }
+    if stack == 0 || stack > USER_TOP || stack % 16 != 0 || tls >= USER_TOP {
+        return Err(SysError::EINVAL);
+    }
+    let mut processor = processor();
+    let context = processor.current_context().clone_thread(tf, stack, tls);
+    let tid = processor.add_thread(context);
+    info!("clone: {} -> {}", processor.current_pid(), tid);
+    Ok(tid as isize)
 }
 
-/// Wait the process exit.
-/// Return the PID. Store exit code to `code` if it's not null.
-fn sys_wait(pid: usize, code: *mut i32) -> i32 {
+/// Max number of the arguments of `sys_exec`
+const MAX_ARGC: usize = 64;
+/// Max total length of the arguments of `sys_exec`, they must fit in the user stack
+const MAX_ARGS_LEN: usize = 0x2000;
+
+/// Replace the current process with the program at `path`, with `argc` arguments at `argv`.
+/// The other threads are terminated, and only the first thread can do it.
+///
+/// On success the new program starts from its entry, and the return value goes to `a0`,
+/// so return `argc` to pass it to the program on RISC-V.
+fn sys_exec(path: *const u8, argc: usize, argv: *const usize, tf: &mut TrapFrame) -> SysResult {
+    {
+        let processor = processor();
+        let pid = processor.current_pid();
+        if processor.group(pid) != Some(pid) {
+            return Err(SysError::EPERM);
+        }
+    }
+    if argc > MAX_ARGC {
+        return Err(SysError::E2BIG);
+    }
+    let path = user::copy_str_from_user(path)?;
+    // Copy arguments to the kernel, the user memory is going to be dropped
+    let mut args = Vec::new();
+    let mut args_len = 0;
+    for i in 0..argc {
+        let ptr = if tf.is_32bit() {
+            user::read_user((argv as *const u32).wrapping_add(i))? as usize
+        } else {
+            user::read_user(argv.wrapping_add(i))?
+        };
+        let arg = user::copy_str_from_user(ptr as *const u8)?;
+        args_len += arg.len() + 1;
+        if args_len > MAX_ARGS_LEN {
+            return Err(SysError::E2BIG);
+        }
+        args.push(arg);
+    }
+    info!("exec: path: {:?}, args: {:?}", path, args);
+    let data = File::open(&path, O_RDONLY).and_then(|mut file| file.read_all())
+        .ok_or(SysError::ENOENT)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    // Load it before killing the other threads, nothing changes if it fails
+    let image = UserImage::from_elf(&data, &args, &[]).map_err(|e| {
+        warn!("exec: {}", e);
+        SysError::ENOEXEC
+    })?;
     let mut processor = processor();
-    match processor.current_wait_for(pid) {
-        WaitResult::Ok(pid, error_code) => {
-            if !code.is_null() {
-                unsafe { *code = error_code as i32 };
-            }
-            0
+    let pid = processor.current_pid();
+    processor.exit_threads(pid, signal::KILLED | signal::SIGKILL);
+    processor.current_context_mut().exec(image, tf);
+    Ok(argc as isize)
+}
+
+// `prot` of `sys_mmap`
+const PROT_READ: usize = 1;
+const PROT_WRITE: usize = 2;
+const PROT_EXEC: usize = 4;
+
+// `flags` of `sys_mmap`, the same as Linux
+const MAP_SHARED: usize = 0x01;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+/// Map `len` bytes of the file `fd` from `offset`, or zeros if `MAP_ANONYMOUS`.
+/// Pages are filled on the first access. Return the address.
+///
+/// Only private mappings are supported, writes are not written back to the file.
+fn sys_mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> SysResult {
+    use ucore_memory::PAGE_SIZE;
+    info!("mmap: addr: {:#x}, len: {:#x}, prot: {:#x}, flags: {:#x}, fd: {}, offset: {:#x}",
+          addr, len, prot, flags, fd, offset);
+    if len == 0 || flags & MAP_SHARED != 0 || offset % PAGE_SIZE != 0 {
+        return Err(SysError::EINVAL);
+    }
+    let len = page_len(len).ok_or(SysError::ENOMEM)?;
+    let fixed = flags & MAP_FIXED != 0;
+    if fixed && (addr % PAGE_SIZE != 0 || !in_user_space(addr, len)) {
+        return Err(SysError::EINVAL);
+    }
+    let file = if flags & MAP_ANONYMOUS != 0 {
+        None
+    } else {
+        match get_file(fd)? {
+            FileLike::File(file) => Some((file.lock().inode().ok_or(SysError::EBADF)?, offset)),
+            _ => return Err(SysError::EBADF),
         }
-        WaitResult::NotExist => -1,
+    };
+    let attr = prot_attr(prot);
+    let addr = processor().current_context_mut().mmap(addr, len, attr, fixed, file).ok_or(SysError::ENOMEM)?;
+    Ok(addr as isize)
+}
+
+/// `len` rounded up to whole pages, `None` if it is larger than user space
+fn page_len(len: usize) -> Option<usize> {
+    use consts::USER_TOP;
+    use ucore_memory::PAGE_SIZE;
+    let len = len.checked_add(PAGE_SIZE - 1)? / PAGE_SIZE * PAGE_SIZE;
+    if len <= USER_TOP {
+        Some(len)
+    } else {
+        None
     }
 }
 
-fn sys_yield() -> i32 {
+/// Whether `[addr, addr + len)` is in user space
+fn in_user_space(addr: usize, len: usize) -> bool {
+    use consts::USER_TOP;
+    addr.checked_add(len).map_or(false, |end| end <= USER_TOP)
+}
+
+/// The attributes of user pages by `prot`.
+/// Pages are readable if writable or executable, so only `PROT_NONE` hides them.
+fn prot_attr(prot: usize) -> MemoryAttr {
+    let mut attr = MemoryAttr::default().user();
+    if prot & (PROT_READ | PROT_WRITE | PROT_EXEC) == 0 { attr = attr.hide(); }
+    if prot & PROT_WRITE == 0 { attr = attr.readonly(); }
+    if prot & PROT_EXEC != 0 { attr = attr.execute(); }
+    attr
+}
+
+/// Unmap `[addr, addr + len)`, which may be a part of a mapping
+fn sys_munmap(addr: usize, len: usize) -> SysResult {
+    info!("munmap: addr: {:#x}, len: {:#x}", addr, len);
+    if len == 0 || !in_user_space(addr, len) {
+        return Err(SysError::EINVAL);
+    }
+    processor().current_context_mut().munmap(addr, len);
+    Ok(0)
+}
+
+/// Set the program break to `addr`, return the new one.
+/// The break is unchanged if `addr` is invalid, e.g. 0 to query it.
+fn sys_brk(addr: usize) -> SysResult {
+    info!("brk: addr: {:#x}", addr);
+    Ok(processor().current_context_mut().brk(addr) as isize)
+}
+
+/// Map `len` bytes of the shared memory object of `key` at `addr`, or free space if `addr` is 0.
+/// The object is created if it does not exist, of `shm::MAX_LEN` bytes at most. Return the address.
+fn sys_shmem(key: usize, addr: usize, len: usize, prot: usize) -> SysResult {
+    use ucore_memory::PAGE_SIZE;
+    info!("shmem: key: {:#x}, addr: {:#x}, len: {:#x}, prot: {:#x}", key, addr, len, prot);
+    if len == 0 || len > shm::MAX_LEN || addr % PAGE_SIZE != 0 {
+        return Err(SysError::EINVAL);
+    }
+    if addr != 0 && !in_user_space(addr, page_len(len).unwrap()) {
+        return Err(SysError::EINVAL);
+    }
+    // Allocate frames without the processor locked
+    let shm = shm::get_or_create(key, len).ok_or(SysError::ENOMEM)?;
+    let attr = prot_attr(prot);
+    let addr = processor().current_context_mut().shmem(addr, len, attr, shm).ok_or(SysError::ENOMEM)?;
+    Ok(addr as isize)
+}
+
+// `options` of `sys_wait`, the same as Linux
+const WNOHANG: usize = 1;
+const WUNTRACED: usize = 2;
+const WCONTINUED: usize = 8;
+
+/// Wait for a child process, or a thread of the same process.
+///
+/// `pid` -1 waits for any child and returns its pid, and so does 0 but returns 0.
+/// With `WUNTRACED` and `WCONTINUED` it also returns for a child stopped or continued,
+/// whose `code` is `0x7f | signal << 8` or `0xffff` as Linux.
+/// With `WNOHANG` it returns 0 at once if nothing to report, only for `pid` -1.
+fn sys_wait(pid: usize, code: *mut i32, options: usize) -> SysResult {
+    let any = pid as isize == -1;
+    if options & !(WNOHANG | WUNTRACED | WCONTINUED) != 0 || (options & WNOHANG != 0 && !any) {
+        return Err(SysError::EINVAL);
+    }
+    let options = WaitOptions {
+        nohang: options & WNOHANG != 0,
+        stopped: options & WUNTRACED != 0,
+        continued: options & WCONTINUED != 0,
+    };
+    // Check `code` before the child is removed
+    if !code.is_null() {
+        user::check_write(code as *mut u8, size_of::<i32>())?;
+    }
+    // Do not touch user memory with the processor locked
+    let result = processor().current_wait(if any { 0 } else { pid }, options);
+    let (pid, status) = match result {
+        WaitResult::Ok(pid, error_code) => (pid, error_code as i32),
+        WaitResult::Stopped(pid, sig) => (pid, 0x7f | (sig as i32) << 8),
+        WaitResult::Continued(pid) => (pid, 0xffff),
+        WaitResult::NotReady => return Ok(0),
+        WaitResult::NotExist => return Err(SysError::ECHILD),
+    };
+    if !code.is_null() {
+        user::write_user(code, status)?;
+    }
+    Ok(if any { pid as isize } else { 0 })
+}
+
+fn sys_yield() -> SysResult {
     thread::yield_now();
-    0
+    Ok(0)
 }
 
-/// Kill the process
-fn sys_kill(pid: usize) -> i32 {
-    processor().kill(pid);
-    0
+/// Kill the process by `SIGKILL`
+fn sys_kill(pid: usize) -> SysResult {
+    sys_sigsend(pid, signal::SIGKILL)
 }
 
-/// Get the current process id
-fn sys_getpid() -> i32 {
-    thread::current().id() as i32
+/// Send signal `sig` to the process `pid`. A negative `pid` sends to the process group `-pid`,
+/// and 0 sends to the process group of the caller.
+/// With `sig` 0, only check whether they exist.
+/// Kernel threads take no signals, and init takes only the ones it handles.
+fn sys_sigsend(pid: usize, sig: usize) -> SysResult {
+    info!("sigsend: pid: {}, sig: {}", pid as isize, sig);
+    if sig != 0 && !signal::is_valid(sig) {
+        return Err(SysError::EINVAL);
+    }
+    let targets = {
+        let processor = processor();
+        match pid as isize {
+            0 => {
+                let pgid = processor.getpgid(processor.current_pid()).unwrap();
+                processor.pgroup_members(pgid)
+            }
+            p if p < 0 => processor.pgroup_members(p.wrapping_neg() as usize),
+            _ if processor.group(pid).is_some() => vec![pid],
+            _ => Vec::new(),
+        }
+    };
+    if targets.is_empty() {
+        return Err(SysError::ESRCH);
+    }
+    let targets: Vec<_> = targets.into_iter().filter(|&pid| signal::permitted(pid, sig)).collect();
+    if targets.is_empty() {
+        return Err(SysError::EPERM);
+    }
+    if sig != 0 {
+        for pid in targets {
+            signal::send(pid, sig);
+        }
+    }
+    Ok(0)
 }
 
-/// Exit the current process
-fn sys_exit(error_code: usize) -> i32 {
-    let mut processor = processor();
+/// Set the action of signal `sig` to `*act` if it is not null,
+/// and store the old one to `oldact` if it is not null.
+/// 32-bit programs on x86_64 can not handle signals.
+fn sys_sigaction(sig: usize, act: *const SigAction, oldact: *mut SigAction, tf: &TrapFrame) -> SysResult {
+    if !signal::handlers_supported(tf) {
+        return Err(SysError::ENOSYS);
+    }
+    if !signal::is_valid(sig) {
+        return Err(SysError::EINVAL);
+    }
+    let act = if act.is_null() {
+        None
+    } else {
+        Some(user::read_user(act)?)
+    };
+    if !oldact.is_null() {
+        user::check_write(oldact as *mut u8, size_of::<SigAction>())?;
+    }
+    let old = {
+        let mut processor = processor();
+        let signals = processor.current_context_mut().signals_mut();
+        let old = signals.action(sig);
+        if let Some(act) = act {
+            if !signals.set_action(sig, act) {
+                return Err(SysError::EINVAL);
+            }
+        }
+        old
+    };
+    if !oldact.is_null() {
+        user::write_user(oldact, old)?;
+    }
+    Ok(0)
+}
+
+/// Change the blocked signals by `*set` as `how` says if it is not null,
+/// and store the old ones to `oldset` if it is not null.
+fn sys_sigprocmask(how: usize, set: *const SigSet, oldset: *mut SigSet) -> SysResult {
+    let set = if set.is_null() {
+        None
+    } else {
+        Some(user::read_user(set)?)
+    };
+    if !oldset.is_null() {
+        user::check_write(oldset as *mut u8, size_of::<SigSet>())?;
+    }
+    let old = {
+        let mut processor = processor();
+        let signals = processor.current_context_mut().signals_mut();
+        let old = signals.blocked();
+        if let Some(set) = set {
+            let blocked = match how {
+                signal::SIG_BLOCK => SigSet(old.0 | set.0),
+                signal::SIG_UNBLOCK => SigSet(old.0 & !set.0),
+                signal::SIG_SETMASK => set,
+                _ => return Err(SysError::EINVAL),
+            };
+            signals.set_blocked(blocked);
+        }
+        old
+    };
+    if !oldset.is_null() {
+        user::write_user(oldset, old)?;
+    }
+    Ok(0)
+}
+
+/// Return from a signal handler, restore the registers saved when it was called
+fn sys_sigreturn(tf: &mut TrapFrame) -> SysResult {
+    // Keep the restored return value register
+    match signal::sigreturn(tf) {
+        Ok(ret) => Ok(ret as isize),
+        Err(_) => signal::exit_by(signal::SIGSEGV),
+    }
+}
+
+/// Get the current process id, the id of its first thread
+fn sys_getpid() -> SysResult {
+    let processor = processor();
+    let pid = processor.current_pid();
+    Ok(processor.group(pid).unwrap() as isize)
+}
+
+/// Get the parent process id.
+/// It is init's if the parent has exited.
+fn sys_getppid() -> SysResult {
+    let processor = processor();
     let pid = processor.current_pid();
-    processor.exit(pid, error_code);
-    0
+    Ok(processor.parent(pid).unwrap() as isize)
+}
+
+/// Move the process `pid` to the process group `pgid`.
+/// `pid` 0 is the caller, and `pgid` 0 is the same as `pid` to make a new group.
+fn sys_setpgid(pid: usize, pgid: usize) -> SysResult {
+    let mut processor = processor();
+    let pid = match pid {
+        0 => processor.current_pid(),
+        _ => pid,
+    };
+    let pid = processor.group(pid).ok_or(SysError::ESRCH)?;
+    let pgid = match pgid {
+        0 => pid,
+        _ => pgid,
+    };
+    if processor.setpgid(pid, pgid) {
+        Ok(0)
+    } else {
+        Err(SysError::EPERM)
+    }
 }
 
-fn sys_sleep(time: usize) -> i32 {
+/// The process group of `pid`, 0 for the caller
+fn sys_getpgid(pid: usize) -> SysResult {
+    let processor = processor();
+    let pid = match pid {
+        0 => processor.current_pid(),
+        _ => pid,
+    };
+    processor.getpgid(pid).map(|pgid| pgid as isize).ok_or(SysError::ESRCH)
+}
+
+/// Make the caller lead a new session and process group, without a terminal
+fn sys_setsid() -> SysResult {
+    processor().setsid().map(|sid| sid as isize).ok_or(SysError::EPERM)
+}
+
+/// The session of `pid`, 0 for the caller
+fn sys_getsid(pid: usize) -> SysResult {
+    let processor = processor();
+    let pid = match pid {
+        0 => processor.current_pid(),
+        _ => pid,
+    };
+    processor.getsid(pid).map(|sid| sid as isize).ok_or(SysError::ESRCH)
+}
+
+/// The foreground process group of the terminal `fd`
+fn sys_tcgetpgrp(fd: usize) -> SysResult {
+    match get_file(fd)? {
+        FileLike::Stdin | FileLike::Stdout => console::foreground().map(|pgid| pgid as isize).ok_or(SysError::ENOTTY),
+        _ => Err(SysError::ENOTTY),
+    }
+}
+
+/// Set the foreground process group of the terminal `fd` to `pgid`, a group in the session of the caller
+fn sys_tcsetpgrp(fd: usize, pgid: usize) -> SysResult {
+    match get_file(fd)? {
+        FileLike::Stdin | FileLike::Stdout => {}
+        _ => return Err(SysError::ENOTTY),
+    }
+    {
+        let processor = processor();
+        let sid = processor.getsid(processor.current_pid()).unwrap();
+        let members = processor.pgroup_members(pgid);
+        if members.is_empty() || processor.getsid(members[0]) != Some(sid) {
+            return Err(SysError::EPERM);
+        }
+    }
+    console::set_foreground(Some(pgid));
+    Ok(0)
+}
+
+/// Exit the current process with all its threads
+fn sys_exit(error_code: usize) -> SysResult {
+    let pid = processor().current_pid();
+    exit_group(pid, error_code);
+    Ok(0)
+}
+
+/// Exit the current thread, to be joined by `sys_wait`.
+/// It is the same as `sys_exit` for the first thread.
+fn sys_exit_thread(error_code: usize) -> SysResult {
+    let pid = processor().current_pid();
+    if processor().group(pid) == Some(pid) {
+        return sys_exit(error_code);
+    }
+    // Close the files first, see `Context::take_files`
+    let files = processor().current_context_mut().take_files();
+    drop(files);
+    processor().exit(pid, error_code);
+    Ok(0)
+}
+
+fn sys_sleep(time: usize) -> SysResult {
     use core::time::Duration;
     thread::sleep(Duration::from_millis(time as u64 * 10));
-    0
+    Ok(0)
+}
+
+// Clocks of `sys_clock_gettime`, the same as Linux
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+
+/// Store the time of `clock` to `ts`, in nanoseconds
+fn sys_clock_gettime(clock: usize, ts: *mut TimeSpec) -> SysResult {
+    let time = match clock {
+        CLOCK_REALTIME => time::realtime(),
+        CLOCK_MONOTONIC => time::monotonic(),
+        _ => return Err(SysError::EINVAL),
+    };
+    user::write_user(ts, TimeSpec::from(time))?;
+    Ok(0)
+}
+
+/// Sleep for `*req`. It sleeps by ticks first, then yields until the time,
+/// so it may be longer but never shorter. A signal ends it with `EINTR`.
+fn sys_nanosleep(req: *const TimeSpec) -> SysResult {
+    let dur = user::read_user(req)?.to_duration().ok_or(SysError::EINVAL)?;
+    let deadline = time::monotonic().checked_add(dur).ok_or(SysError::EINVAL)?;
+    thread::sleep(dur);
+    while time::monotonic() < deadline {
+        if signal::interrupted() {
+            return Err(SysError::EINTR);
+        }
+        thread::yield_now();
+    }
+    Ok(0)
 }
 
-fn sys_get_time() -> i32 {
+fn sys_get_time() -> SysResult {
     let processor = processor();
-    processor.get_time() as i32
+    Ok(processor.get_time() as isize)
 }
 
-fn sys_lab6_set_priority(priority: usize) -> i32 {
+fn sys_lab6_set_priority(priority: usize) -> SysResult {
     let mut processor = processor();
     processor.set_priority(priority as u8);
-    0
+    Ok(0)
+}
+
+/// Switch the scheduling policy of the whole system, see `process::SCHED_*`.
+/// Only the programs run by the kernel shell may do it, see `Context::is_admin`.
+fn sys_set_scheduler(policy: usize) -> SysResult {
+    if !processor().current_context().is_admin() {
+        return Err(SysError::EPERM);
+    }
+    if set_scheduler(policy) {
+        Ok(0)
+    } else {
+        Err(SysError::EINVAL)
+    }
+}
+
+// Classes of `sys_set_sched_class`
+const SCHED_CLASS_FAIR: usize = 0;
+const SCHED_CLASS_FIFO: usize = 1;
+const SCHED_CLASS_RR: usize = 2;
+
+/// Move the caller, or its child `pid`, into the scheduling class `class`.
+/// The real-time classes take a priority in 1..=`MAX_RT_PRIORITY` (99), the fair one takes 0.
+fn sys_set_sched_class(pid: usize, class: usize, priority: usize) -> SysResult {
+    let rt_priority = priority >= 1 && priority <= MAX_RT_PRIORITY as usize;
+    let rt = match (class, priority) {
+        (SCHED_CLASS_FAIR, 0) => None,
+        (SCHED_CLASS_FIFO, _) if rt_priority => Some((RtPolicy::Fifo, priority as u8)),
+        (SCHED_CLASS_RR, _) if rt_priority => Some((RtPolicy::RoundRobin, priority as u8)),
+        _ => return Err(SysError::EINVAL),
+    };
+    let mut processor = processor();
+    let current_pid = processor.current_pid();
+    let pid = match pid {
+        0 => current_pid,
+        _ => pid,
+    };
+    let group = processor.group(current_pid).unwrap();
+    match processor.parent(pid) {
+        None => return Err(SysError::ESRCH),
+        Some(parent) if pid != current_pid && parent != group => return Err(SysError::EPERM),
+        _ => {}
+    }
+    processor.set_rt(pid, rt);
+    Ok(0)
 }
 
-fn sys_putc(c: char) -> i32 {
+fn sys_putc(c: char) -> SysResult {
     print!("{}", c);
-    0
+    Ok(0)
 }
 
 const SYS_EXIT: usize = 1;
@@ -131,24 +839,42 @@ const SYS_FORK: usize = 2;
 const SYS_WAIT: usize = 3;
 const SYS_EXEC: usize = 4;
 const SYS_CLONE: usize = 5;
+const SYS_EXIT_THREAD: usize = 6;
 const SYS_YIELD: usize = 10;
 const SYS_SLEEP: usize = 11;
 const SYS_KILL: usize = 12;
+const SYS_SIGSEND: usize = 13;
+const SYS_SIGACTION: usize = 14;
+const SYS_SIGPROCMASK: usize = 15;
+const SYS_SIGRETURN: usize = 16;
 const SYS_GETTIME: usize = 17;
 const SYS_GETPID: usize = 18;
+const SYS_CLOCK_GETTIME: usize = 19;
 const SYS_MMAP: usize = 20;
 const SYS_MUNMAP: usize = 21;
 const SYS_SHMEM: usize = 22;
+const SYS_BRK: usize = 23;
+const SYS_GETPPID: usize = 24;
+const SYS_SETPGID: usize = 25;
+const SYS_GETPGID: usize = 26;
+const SYS_SETSID: usize = 27;
+const SYS_GETSID: usize = 28;
+const SYS_NANOSLEEP: usize = 29;
 const SYS_PUTC: usize = 30;
 const SYS_PGDIR: usize = 31;
+const SYS_SET_SCHED_CLASS: usize = 32;
 const SYS_OPEN: usize = 100;
 const SYS_CLOSE: usize = 101;
 const SYS_READ: usize = 102;
 const SYS_WRITE: usize = 103;
 const SYS_SEEK: usize = 104;
+const SYS_TCGETPGRP: usize = 105;
+const SYS_TCSETPGRP: usize = 106;
 const SYS_FSTAT: usize = 110;
 const SYS_FSYNC: usize = 111;
 const SYS_GETCWD: usize = 121;
 const SYS_GETDIRENTRY: usize = 128;
 const SYS_DUP: usize = 130;
+const SYS_PIPE: usize = 140;
+const SYS_SET_SCHEDULER: usize = 254;
 const SYS_LAB6_SET_PRIORITY: usize = 255;
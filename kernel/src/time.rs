@@ -0,0 +1,74 @@
+//! Clocks with nanosecond resolution
+//!
+//! The monotonic clock counts from boot by the clock source of the arch:
+//! TSC calibrated against the PIT on x86_64, the `time` CSR on RISC-V.
+//! The wall clock is the monotonic one plus the RTC time at boot.
+//! RISC-V has no RTC, so its wall clock starts from the epoch.
+
+use arch::timer;
+use core::time::Duration;
+use spin::Once;
+
+/// A free-running counter
+pub struct ClockSource {
+    pub name: &'static str,
+    pub read: fn() -> u64,
+    /// Counts per second
+    pub freq: u64,
+}
+
+struct Clocks {
+    source: ClockSource,
+    /// Count of the source at boot
+    boot_count: u64,
+    /// Wall clock at boot
+    boot_time: Duration,
+}
+
+static CLOCKS: Once<Clocks> = Once::new();
+
+pub fn init() {
+    let source = timer::clock_source();
+    let boot_time = Duration::from_secs(timer::read_epoch());
+    let boot_count = (source.read)();
+    info!("time: clock source {} at {} Hz, boot at {:?} since the epoch", source.name, source.freq, boot_time);
+    CLOCKS.call_once(|| Clocks { source, boot_count, boot_time });
+}
+
+/// Time since boot
+pub fn monotonic() -> Duration {
+    let clocks = CLOCKS.try().expect("time is not initialized");
+    let count = (clocks.source.read)() - clocks.boot_count;
+    let freq = clocks.source.freq;
+    Duration::new(count / freq, ((count % freq) * 1_000_000_000 / freq) as u32)
+}
+
+/// Time since the Unix epoch
+pub fn realtime() -> Duration {
+    CLOCKS.try().expect("time is not initialized").boot_time + monotonic()
+}
+
+/// `struct timespec` of the user
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TimeSpec {
+    pub sec: usize,
+    pub nsec: usize,
+}
+
+impl From<Duration> for TimeSpec {
+    fn from(dur: Duration) -> Self {
+        TimeSpec { sec: dur.as_secs() as usize, nsec: dur.subsec_nanos() as usize }
+    }
+}
+
+impl TimeSpec {
+    /// `None` if `nsec` is out of range, or `sec` is negative
+    pub fn to_duration(&self) -> Option<Duration> {
+        if self.nsec < 1_000_000_000 && self.sec as isize >= 0 {
+            Some(Duration::new(self.sec as u64, self.nsec as u32))
+        } else {
+            None
+        }
+    }
+}
@@ -2,25 +2,34 @@ use process::*;
 use arch::interrupt::TrapFrame;
 
 pub fn timer() {
-    let mut processor = processor();
-    processor.tick();
+    // The ticks missed by sleep are counted by waking up
+    if !idle::wake() {
+        processor().tick();
+    }
 }
 
-pub fn before_return() {
+/// Restart the tick if an idle CPU is woken by another interrupt,
+/// schedule, then deliver signals if returning to the user by `tf`
+pub fn before_return(tf: &mut TrapFrame) {
     if let Some(processor) = PROCESSOR.try() {
+        idle::wake();
         processor.lock().schedule();
+        if tf.is_user() {
+            signal::handle(tf);
+        }
     }
 }
 
-pub fn error(tf: &TrapFrame) -> ! {
-    if let Some(processor) = PROCESSOR.try() {
-        let mut processor = processor.lock();
-        let pid = processor.current_pid();
-        error!("Process {} error:\n{:#x?}", pid, tf);
-        processor.exit(pid, 0x100); // TODO: Exit code for error
-        processor.schedule();
-        unreachable!();
-    } else {
+/// Handle a fault of the current process by sending `sig` to it.
+/// Faults in the kernel can not be handled, so the process exits at once.
+pub fn error(tf: &TrapFrame, sig: usize) {
+    if PROCESSOR.try().is_none() {
         panic!("Exception when processor not inited\n{:#x?}", tf);
     }
+    let pid = processor().current_pid();
+    error!("Process {} error, signal {}:\n{:#x?}", pid, sig, tf);
+    match tf.is_user() {
+        true => signal::force(sig),
+        false => signal::exit_by(sig),
+    }
 }
\ No newline at end of file
@@ -1,5 +1,6 @@
 #![no_std]
 #![feature(asm)]
+#![feature(global_asm)]
 #![feature(lang_items)]
 #![feature(panic_implementation)]
 #![feature(panic_info_message)]
@@ -8,4 +9,5 @@
 
 #[macro_use]
 pub mod syscall;
-pub mod lang_items;
\ No newline at end of file
+pub mod lang_items;
+pub mod thread;
\ No newline at end of file
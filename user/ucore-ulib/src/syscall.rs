@@ -21,16 +21,70 @@ struct StdOut;
 
 impl fmt::Write for StdOut {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        match sys_write(0, s.as_ptr(), s.len()) {
-            0 => Ok(()),
+        match sys_write(1, s.as_ptr(), s.len()) {
+            Ok(len) if len == s.len() => Ok(()),
             _ => Err(fmt::Error::default()),
         }
     }
 }
 
+/// Errors of syscalls, the same as Linux
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SysError {
+    EPERM,
+    ENOENT,
+    ESRCH,
+    EINTR,
+    EIO,
+    E2BIG,
+    ENOEXEC,
+    EBADF,
+    ECHILD,
+    ENOMEM,
+    EFAULT,
+    EEXIST,
+    EINVAL,
+    ENOTTY,
+    ESPIPE,
+    EPIPE,
+    ENOSYS,
+    /// Unknown to this library
+    Other(usize),
+}
+
+impl SysError {
+    fn from_errno(errno: usize) -> Self {
+        match errno {
+            1 => SysError::EPERM,
+            2 => SysError::ENOENT,
+            3 => SysError::ESRCH,
+            4 => SysError::EINTR,
+            5 => SysError::EIO,
+            7 => SysError::E2BIG,
+            8 => SysError::ENOEXEC,
+            9 => SysError::EBADF,
+            10 => SysError::ECHILD,
+            12 => SysError::ENOMEM,
+            14 => SysError::EFAULT,
+            17 => SysError::EEXIST,
+            22 => SysError::EINVAL,
+            25 => SysError::ENOTTY,
+            29 => SysError::ESPIPE,
+            32 => SysError::EPIPE,
+            38 => SysError::ENOSYS,
+            _ => SysError::Other(errno),
+        }
+    }
+}
+
+pub type SysResult = Result<usize, SysError>;
+
+/// Max error number, return values in `[-MAX_ERRNO, -1]` are errors
+const MAX_ERRNO: usize = 4095;
+
 #[inline(always)]
-fn sys_call(id: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize, arg5: usize) -> i32 {
-    let ret: i32;
+fn sys_call(id: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize, arg5: usize) -> SysResult {
+    let ret: usize;
     unsafe {
         #[cfg(target_arch = "riscv32")]
             asm!("ecall"
@@ -45,96 +99,468 @@ fn sys_call(id: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4:
             : "memory"
             : "intel" "volatile");
     }
-    ret
+    sys_result(ret)
+}
+
+/// Decode the return value of a syscall
+fn sys_result(ret: usize) -> SysResult {
+    match ret.wrapping_neg() {
+        errno @ 1...MAX_ERRNO => Err(SysError::from_errno(errno)),
+        _ => Ok(ret),
+    }
 }
 
 pub fn sys_exit(code: usize) -> ! {
-    sys_call(SYS_EXIT, code, 0, 0, 0, 0, 0);
+    let _ = sys_call(SYS_EXIT, code, 0, 0, 0, 0, 0);
+    unreachable!()
+}
+
+/// Exit the current thread, to be joined by `sys_wait`.
+/// It is the same as `sys_exit` for the first thread.
+pub fn sys_exit_thread(code: usize) -> ! {
+    let _ = sys_call(SYS_EXIT_THREAD, code, 0, 0, 0, 0, 0);
     unreachable!()
 }
 
-pub fn sys_write(fd: usize, base: *const u8, len: usize) -> i32 {
+pub fn sys_read(fd: usize, base: *mut u8, len: usize) -> SysResult {
+    sys_call(SYS_READ, fd, base as usize, len, 0, 0, 0)
+}
+
+pub fn sys_write(fd: usize, base: *const u8, len: usize) -> SysResult {
     sys_call(SYS_WRITE, fd, base as usize, len, 0, 0, 0)
 }
 
-pub fn sys_open(path: &str, flags: usize) -> i32 {
-    // UNSAFE: append '\0' to the string
-    use core::mem::replace;
-    let end = unsafe { &mut *(path.as_ptr().offset(path.len() as isize) as *mut u8) };
-    let backup = replace(end, 0);
-    let ret = sys_call(SYS_OPEN, path.as_ptr() as usize, flags, 0, 0, 0, 0);
-    *end = backup;
-    ret
+/// The longest path passed to the kernel, with the terminating '\0'
+const PATH_MAX: usize = 256;
+
+/// Call `f` with `path` as a C string.
+/// It is copied to the stack, since `path` may be read-only or end at a page.
+fn with_c_str(path: &str, f: impl FnOnce(*const u8) -> SysResult) -> SysResult {
+    if path.len() >= PATH_MAX {
+        return Err(SysError::EINVAL);
+    }
+    let mut buf = [0u8; PATH_MAX];
+    buf[..path.len()].copy_from_slice(path.as_bytes());
+    f(buf.as_ptr())
 }
 
-pub fn sys_close(fd: usize) -> i32 {
+pub fn sys_open(path: &str, flags: usize) -> SysResult {
+    with_c_str(path, |path| sys_call(SYS_OPEN, path as usize, flags, 0, 0, 0, 0))
+}
+
+pub fn sys_close(fd: usize) -> SysResult {
     sys_call(SYS_CLOSE, fd, 0 , 0, 0, 0, 0)
 }
 
 /// Fork the current process. Return the child's PID.
-pub fn sys_fork() -> i32 {
+pub fn sys_fork() -> SysResult {
     sys_call(SYS_FORK, 0, 0, 0, 0, 0, 0)
 }
 
-/// Wait the process exit.
-/// Return the PID. Store exit code to `code` if it's not null.
-pub fn sys_wait(pid: usize, code: *mut i32) -> i32 {
-    sys_call(SYS_WAIT, pid, code as usize, 0, 0, 0, 0)
+/// Make a thread running `entry(arg)` on the stack below `stack_top`, with thread pointer `tls`.
+/// Return its id. `entry` must end by `sys_exit_thread`.
+///
+/// The thread shares the memory, files and signal actions of the process.
+pub unsafe fn sys_clone(entry: extern fn(usize) -> !, arg: usize, stack_top: usize, tls: usize) -> SysResult {
+    use core::mem::size_of;
+    // Popped by `__clone` in the new thread
+    let sp = (stack_top & !0xf) - 16;
+    *(sp as *mut usize) = arg;
+    *((sp + size_of::<usize>()) as *mut usize) = entry as usize;
+    sys_result(__clone(CLONE_VM | CLONE_THREAD, sp, tls))
+}
+
+// `sys_clone` (5) returns twice, the new thread must not return to Rust code on its new stack
+extern {
+    fn __clone(flags: usize, stack: usize, tls: usize) -> usize;
+}
+
+#[cfg(target_arch = "x86_64")]
+global_asm!("
+    .text
+    .global __clone
+__clone:
+    movq $5, %rax
+    int $0x40
+    testq %rax, %rax
+    jnz 1f
+    popq %rdi
+    popq %rax
+    callq *%rax
+    ud2
+1:
+    ret
+");
+
+#[cfg(target_arch = "riscv32")]
+global_asm!("
+    .text
+    .global __clone
+__clone:
+    mv a3, a2
+    mv a2, a1
+    mv a1, a0
+    li a0, 5
+    ecall
+    bnez a0, 1f
+    lw a0, 0(sp)
+    lw t0, 4(sp)
+    addi sp, sp, 16
+    jalr t0
+1:
+    ret
+");
+
+/// Replace the current process with the program at `path`.
+/// `argv` is an array of `argc` pointers to C strings.
+/// Only return on error.
+pub fn sys_exec(path: &str, argc: usize, argv: *const *const u8) -> SysResult {
+    with_c_str(path, |path| sys_call(SYS_EXEC, path as usize, argc, argv as usize, 0, 0, 0))
+}
+
+/// Wait the process exit, or join a thread of the current process.
+/// Store exit code to `code` if it's not null.
+///
+/// `WAIT_ANY` waits for any child and returns its pid, and so does 0 but returns 0.
+pub fn sys_wait(pid: usize, code: *mut i32) -> SysResult {
+    sys_waitpid(pid, code, 0)
+}
+
+/// `sys_wait` with `options`, `WUNTRACED` and `WCONTINUED` report the children stopped or continued.
+/// `WNOHANG` returns 0 at once if nothing to report, only for `WAIT_ANY`.
+pub fn sys_waitpid(pid: usize, code: *mut i32, options: usize) -> SysResult {
+    sys_call(SYS_WAIT, pid, code as usize, options, 0, 0, 0)
+}
+
+/// Map `len` bytes of file `fd` from `offset` (or zeros if `MAP_ANONYMOUS`) to memory.
+/// Return the address.
+pub fn sys_mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> SysResult {
+    sys_call(SYS_MMAP, addr, len, prot, flags, fd, offset)
+}
+
+pub fn sys_munmap(addr: usize, len: usize) -> SysResult {
+    sys_call(SYS_MUNMAP, addr, len, 0, 0, 0, 0)
+}
+
+/// Set the program break to `addr`, return the new one.
+/// Query it if `addr` is 0.
+pub fn sys_brk(addr: usize) -> SysResult {
+    sys_call(SYS_BRK, addr, 0, 0, 0, 0, 0)
+}
+
+/// Move the program break by `increment`, return the old one.
+pub fn sbrk(increment: isize) -> SysResult {
+    let old = sys_brk(0)?;
+    let new = (old as isize + increment) as usize;
+    match sys_brk(new)? == new {
+        true => Ok(old),
+        false => Err(SysError::ENOMEM),
+    }
 }
 
-pub fn sys_yield() -> i32 {
+/// Map `len` bytes of the shared memory object of `key` at `addr` (or anywhere if 0).
+/// The object is created if not exists. Return the address.
+pub fn sys_shmem(key: usize, addr: usize, len: usize, prot: usize) -> SysResult {
+    sys_call(SYS_SHMEM, key, addr, len, prot, 0, 0)
+}
+
+/// Make a pipe, `fds[0]` is the read end and `fds[1]` is the write end.
+pub fn sys_pipe(fds: &mut [i32; 2]) -> SysResult {
+    sys_call(SYS_PIPE, fds.as_mut_ptr() as usize, 0, 0, 0, 0, 0)
+}
+
+pub fn sys_yield() -> SysResult {
     sys_call(SYS_YIELD, 0, 0, 0, 0, 0, 0)
 }
 
-/// Kill the process
-pub fn sys_kill(pid: usize) -> i32 {
+/// Kill the process by `SIGKILL`
+pub fn sys_kill(pid: usize) -> SysResult {
     sys_call(SYS_KILL, pid, 0, 0, 0, 0, 0)
 }
 
+/// Send signal `sig` to the process `pid`, or the process group `-pid` if negative, or the current group if 0
+pub fn sys_sigsend(pid: usize, sig: usize) -> SysResult {
+    sys_call(SYS_SIGSEND, pid, sig, 0, 0, 0, 0)
+}
+
+/// Set the action of signal `sig` if `act` is given, return the old one.
+/// The restorer is filled in.
+pub fn sys_sigaction(sig: usize, act: Option<&SigAction>) -> Result<SigAction, SysError> {
+    let act = act.map(|act| SigAction { restorer: __sigrestorer as usize, ..*act });
+    let act_ptr = match act {
+        Some(ref act) => act as *const SigAction as usize,
+        None => 0,
+    };
+    let mut old = SigAction::default();
+    sys_call(SYS_SIGACTION, sig, act_ptr, &mut old as *mut SigAction as usize, 0, 0, 0)?;
+    Ok(old)
+}
+
+/// Handle signal `sig` by `handler`
+pub fn signal(sig: usize, handler: extern fn(usize)) -> SysResult {
+    let act = SigAction { handler: handler as usize, ..SigAction::default() };
+    sys_sigaction(sig, Some(&act)).map(|_| 0)
+}
+
+/// Change the blocked signals by `set` as `how` says if it is given, return the old ones.
+pub fn sys_sigprocmask(how: usize, set: Option<SigSet>) -> Result<SigSet, SysError> {
+    let set_ptr = match set {
+        Some(ref set) => set as *const SigSet as usize,
+        None => 0,
+    };
+    let mut old = 0;
+    sys_call(SYS_SIGPROCMASK, how, set_ptr, &mut old as *mut SigSet as usize, 0, 0, 0)?;
+    Ok(old)
+}
+
+/// The signal killing a process, got from its exit code by `sys_wait`
+pub fn killed_by(code: i32) -> Option<usize> {
+    match code as usize {
+        code if code & !(NSIG - 1) == KILLED => Some(code & (NSIG - 1)),
+        _ => None,
+    }
+}
+
+/// The signal stopping a process, got from its code by `sys_waitpid` with `WUNTRACED`
+pub fn stopped_by(code: i32) -> Option<usize> {
+    match code & 0xff {
+        0x7f => Some((code >> 8) as usize & 0xff),
+        _ => None,
+    }
+}
+
+/// Is a process continued, by its code from `sys_waitpid` with `WCONTINUED` ?
+pub fn is_continued(code: i32) -> bool {
+    code == 0xffff
+}
+
+// Signal handlers return here, then `sys_sigreturn` (16) restores the registers before the signal
+extern {
+    fn __sigrestorer();
+}
+
+#[cfg(target_arch = "x86_64")]
+global_asm!("
+    .text
+    .global __sigrestorer
+__sigrestorer:
+    movq $16, %rax
+    int $0x40
+");
+
+#[cfg(target_arch = "riscv32")]
+global_asm!("
+    .text
+    .global __sigrestorer
+__sigrestorer:
+    li a0, 16
+    ecall
+");
+
 /// Get the current process id
-pub fn sys_getpid() -> i32 {
+pub fn sys_getpid() -> SysResult {
     sys_call(SYS_GETPID, 0, 0, 0, 0, 0, 0)
 }
 
-pub fn sys_sleep(time: usize) -> i32 {
+/// Get the parent process id, init's if the parent has exited
+pub fn sys_getppid() -> SysResult {
+    sys_call(SYS_GETPPID, 0, 0, 0, 0, 0, 0)
+}
+
+/// Move process `pid` (0 for the current) to process group `pgid` (0 for a new one of `pid`)
+pub fn sys_setpgid(pid: usize, pgid: usize) -> SysResult {
+    sys_call(SYS_SETPGID, pid, pgid, 0, 0, 0, 0)
+}
+
+/// Get the process group of `pid`, 0 for the current
+pub fn sys_getpgid(pid: usize) -> SysResult {
+    sys_call(SYS_GETPGID, pid, 0, 0, 0, 0, 0)
+}
+
+/// Make a new session and process group led by the current process
+pub fn sys_setsid() -> SysResult {
+    sys_call(SYS_SETSID, 0, 0, 0, 0, 0, 0)
+}
+
+/// Get the session of `pid`, 0 for the current
+pub fn sys_getsid(pid: usize) -> SysResult {
+    sys_call(SYS_GETSID, pid, 0, 0, 0, 0, 0)
+}
+
+/// Get the foreground process group of the terminal `fd`
+pub fn sys_tcgetpgrp(fd: usize) -> SysResult {
+    sys_call(SYS_TCGETPGRP, fd, 0, 0, 0, 0, 0)
+}
+
+/// Let the process group `pgid` read the terminal `fd` and get the signals from its keyboard
+pub fn sys_tcsetpgrp(fd: usize, pgid: usize) -> SysResult {
+    sys_call(SYS_TCSETPGRP, fd, pgid, 0, 0, 0, 0)
+}
+
+pub fn sys_sleep(time: usize) -> SysResult {
     sys_call(SYS_SLEEP, time, 0, 0, 0, 0, 0)
 }
 
-pub fn sys_get_time() -> i32 {
+pub fn sys_get_time() -> SysResult {
     sys_call(SYS_GETTIME, 0, 0, 0, 0, 0, 0)
 }
 
-pub fn sys_lab6_set_priority(priority: usize) -> i32 {
+/// Get the time of `CLOCK_REALTIME` or `CLOCK_MONOTONIC`, in nanoseconds
+pub fn sys_clock_gettime(clock: usize) -> Result<TimeSpec, SysError> {
+    let mut ts = TimeSpec::default();
+    sys_call(SYS_CLOCK_GETTIME, clock, &mut ts as *mut TimeSpec as usize, 0, 0, 0, 0)?;
+    Ok(ts)
+}
+
+/// Sleep for at least `req`, `EINTR` if a signal comes
+pub fn sys_nanosleep(req: &TimeSpec) -> SysResult {
+    sys_call(SYS_NANOSLEEP, req as *const TimeSpec as usize, 0, 0, 0, 0, 0)
+}
+
+pub fn sys_lab6_set_priority(priority: usize) -> SysResult {
     sys_call(SYS_LAB6_SET_PRIORITY, priority, 0, 0, 0, 0, 0)
 }
 
-pub fn sys_putc(c: char) -> i32 {
+/// Switch the scheduling policy of the whole system. Only for programs run by the kernel shell, not their forks.
+pub fn sys_set_scheduler(policy: usize) -> SysResult {
+    sys_call(SYS_SET_SCHEDULER, policy, 0, 0, 0, 0, 0)
+}
+
+/// Move the caller (`pid` 0) or its child into a scheduling class `SCHED_CLASS_*`,
+/// with a priority in 1..=99 for the real-time ones, 0 for the fair one
+pub fn sys_set_sched_class(pid: usize, class: usize, priority: usize) -> SysResult {
+    sys_call(SYS_SET_SCHED_CLASS, pid, class, priority, 0, 0, 0)
+}
+
+pub fn sys_putc(c: char) -> SysResult {
     sys_call(SYS_PUTC, c as usize, 0, 0, 0, 0, 0)
 }
 
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct TimeSpec {
+    pub sec: usize,
+    pub nsec: usize,
+}
+
+/// Bit `n` for signal `n`
+pub type SigSet = u32;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SigAction {
+    /// Address of `extern fn(signal: usize)`, or `SIG_DFL`, `SIG_IGN`
+    pub handler: usize,
+    /// Blocked during the handler, besides the signal itself
+    pub mask: SigSet,
+    pub flags: u32,
+    pub restorer: usize,
+}
+
+pub const NSIG: usize = 32;
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+pub const SA_NODEFER: u32 = 0x4000_0000;
+pub const SA_RESETHAND: u32 = 0x8000_0000;
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// Exit code of a process killed by a signal is `KILLED | signal`
+pub const KILLED: usize = 0x1000;
+
+/// `pid` of `sys_wait` for any child
+pub const WAIT_ANY: usize = !0;
+
+pub const WNOHANG: usize = 1;
+pub const WUNTRACED: usize = 2;
+pub const WCONTINUED: usize = 8;
+
+pub const CLONE_VM: usize = 0x100;
+pub const CLONE_THREAD: usize = 0x200;
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+
+pub const SCHED_STRIDE: usize = 0;
+pub const SCHED_RR: usize = 1;
+pub const SCHED_MLFQ: usize = 2;
+
+pub const SCHED_CLASS_FAIR: usize = 0;
+pub const SCHED_CLASS_FIFO: usize = 1;
+pub const SCHED_CLASS_RR: usize = 2;
+
+pub const PROT_READ: usize = 1;
+pub const PROT_WRITE: usize = 2;
+pub const PROT_EXEC: usize = 4;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
 const SYS_EXIT: usize = 1;
 const SYS_FORK: usize = 2;
 const SYS_WAIT: usize = 3;
 const SYS_EXEC: usize = 4;
 const SYS_CLONE: usize = 5;
+const SYS_EXIT_THREAD: usize = 6;
 const SYS_YIELD: usize = 10;
 const SYS_SLEEP: usize = 11;
 const SYS_KILL: usize = 12;
+const SYS_SIGSEND: usize = 13;
+const SYS_SIGACTION: usize = 14;
+const SYS_SIGPROCMASK: usize = 15;
 const SYS_GETTIME: usize = 17;
 const SYS_GETPID: usize = 18;
+const SYS_CLOCK_GETTIME: usize = 19;
 const SYS_MMAP: usize = 20;
 const SYS_MUNMAP: usize = 21;
 const SYS_SHMEM: usize = 22;
+const SYS_BRK: usize = 23;
+const SYS_GETPPID: usize = 24;
+const SYS_SETPGID: usize = 25;
+const SYS_GETPGID: usize = 26;
+const SYS_SETSID: usize = 27;
+const SYS_GETSID: usize = 28;
+const SYS_NANOSLEEP: usize = 29;
 const SYS_PUTC: usize = 30;
 const SYS_PGDIR: usize = 31;
+const SYS_SET_SCHED_CLASS: usize = 32;
 const SYS_OPEN: usize = 100;
 const SYS_CLOSE: usize = 101;
 const SYS_READ: usize = 102;
 const SYS_WRITE: usize = 103;
 const SYS_SEEK: usize = 104;
+const SYS_TCGETPGRP: usize = 105;
+const SYS_TCSETPGRP: usize = 106;
 const SYS_FSTAT: usize = 110;
 const SYS_FSYNC: usize = 111;
 const SYS_GETCWD: usize = 121;
 const SYS_GETDIRENTRY: usize = 128;
 const SYS_DUP: usize = 130;
+const SYS_PIPE: usize = 140;
+const SYS_SET_SCHEDULER: usize = 254;
 const SYS_LAB6_SET_PRIORITY: usize = 255;
\ No newline at end of file
@@ -0,0 +1,64 @@
+//! Threads sharing the memory of the process, on top of `sys_clone`
+
+use core::mem::{align_of, size_of};
+use core::ptr;
+use syscall::*;
+
+/// Size of the memory of a thread, for its stack and `Packet`.
+/// Mapped lazily, so only the used pages take frames.
+const STACK_SIZE: usize = 0x10000;
+
+/// At the top of the memory of a thread
+struct Packet<F, T> {
+    f: Option<F>,
+    result: Option<T>,
+}
+
+/// An owned permission to join a thread.
+///
+/// Dropping it detaches the thread, whose stack is kept until the process exits.
+pub struct JoinHandle<T> {
+    tid: usize,
+    /// The memory of the thread, unmapped when joined
+    base: usize,
+    result: *mut Option<T>,
+}
+
+/// Run `f` in a new thread, return a handle to get its result
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+{
+    let base = sys_mmap(0, STACK_SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0)
+        .expect("failed to map the thread stack");
+    let packet_addr = (base + STACK_SIZE - size_of::<Packet<F, T>>()) & !(align_of::<Packet<F, T>>() - 1);
+    let packet = packet_addr as *mut Packet<F, T>;
+    unsafe { ptr::write(packet, Packet { f: Some(f), result: None }); }
+    // The stack is below the packet
+    let tid = unsafe { sys_clone(start::<F, T>, packet_addr, packet_addr, 0) }
+        .expect("failed to clone");
+    JoinHandle { tid, base, result: unsafe { &mut (*packet).result } }
+}
+
+extern fn start<F, T>(packet: usize) -> ! where F: FnOnce() -> T {
+    let packet = unsafe { &mut *(packet as *mut Packet<F, T>) };
+    let f = packet.f.take().unwrap();
+    packet.result = Some(f());
+    sys_exit_thread(0)
+}
+
+impl<T> JoinHandle<T> {
+    /// The thread id, which is the pid for `sys_wait`
+    pub fn id(&self) -> usize {
+        self.tid
+    }
+
+    /// Wait for the thread to finish, return its result.
+    ///
+    /// A panic in the thread exits the whole process, so the result is always there.
+    pub fn join(self) -> Result<T, SysError> {
+        sys_wait(self.tid, ptr::null_mut())?;
+        let result = unsafe { (*self.result).take() }.unwrap();
+        sys_munmap(self.base, STACK_SIZE)?;
+        Ok(result)
+    }
+}